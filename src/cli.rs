@@ -15,7 +15,14 @@ pub mod manage;
 /// Handle multiple shell sessions.
 pub mod multishell;
 
-use clap::{Parser, Subcommand};
+/// Status line output.
+pub mod reporter;
+
+use std::time::Duration;
+
+use clap::{Args, Parser, Subcommand};
+
+use crate::utils::RetryPolicy;
 
 #[derive(Parser)]
 #[command(name = "biusrv")]
@@ -31,10 +38,84 @@ pub struct Cli {
     #[arg(long, default_value = "warn")]
     pub log_level: String,
 
+    #[command(flatten)]
+    pub global: GlobalArgs,
+
     #[command(subcommand)]
     pub command: Commands,
 }
 
+/// Options shared by all commands
+#[derive(Args, Clone, Debug)]
+pub struct GlobalArgs {
+    /// Print the commands that would run on servers without executing them
+    #[arg(long, global = true)]
+    pub dry_run: bool,
+
+    /// Flush and timestamp every status line, enabled when stdout is not a terminal
+    #[arg(long, global = true)]
+    pub stream: bool,
+
+    /// Maximum number of simultaneous SSH connections, independent of threads
+    #[arg(long, global = true, value_parser = clap::value_parser!(u32).range(1..))]
+    pub max_connections: Option<u32>,
+
+    /// Retry connecting to a server this many times before the task fails
+    #[arg(long, global = true, value_name = "NUM", default_value = "0")]
+    pub connect_retries: u32,
+
+    /// Send an SSH keepalive after this many idle seconds, off by default
+    #[arg(long, global = true, value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+    pub keepalive: Option<u64>,
+
+    /// Skip the confirmation prompt before disruptive operations
+    #[arg(short = 'y', long, global = true)]
+    pub yes: bool,
+
+    /// Seconds before the first retry of a failed operation
+    #[arg(long, global = true, value_name = "SECS", default_value = "1", value_parser = parse_non_negative)]
+    pub retry_delay: f64,
+
+    /// Growth factor of the delay between retries
+    #[arg(long, global = true, default_value = "2", value_parser = parse_non_negative)]
+    pub retry_multiplier: f64,
+
+    /// Longest wait in seconds between two retries
+    #[arg(long, global = true, value_name = "SECS", default_value = "30", value_parser = parse_non_negative)]
+    pub retry_max_delay: f64,
+
+    /// Wait exactly the computed retry delay instead of a random 50-100% of it
+    #[arg(long, global = true)]
+    pub no_retry_jitter: bool,
+
+    /// Output format, json prints one result object per server
+    #[arg(long, global = true, value_enum, default_value_t = reporter::OutputFormat::Text)]
+    pub output: reporter::OutputFormat,
+}
+
+impl GlobalArgs {
+    /// Retry backoff chosen on the command line
+    pub fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            base_delay: Duration::from_secs_f64(self.retry_delay),
+            multiplier: self.retry_multiplier,
+            max_delay: Duration::from_secs_f64(self.retry_max_delay),
+            jitter: !self.no_retry_jitter,
+        }
+    }
+}
+
+/// Parse a non-negative number
+fn parse_non_negative(value: &str) -> anyhow::Result<f64> {
+    let number = value
+        .parse::<f64>()
+        .map_err(|_| anyhow::anyhow!("Invalid number: {}", value))?;
+    if !number.is_finite() || number < 0.0 {
+        return Err(anyhow::anyhow!("Must be a non-negative number: {}", value));
+    }
+    Ok(number)
+}
+
 #[derive(Subcommand)]
 pub enum Commands {
     /// 🚀 Initialize server (users, SSH, firewall, fail2ban)
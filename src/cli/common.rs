@@ -1,7 +1,138 @@
 /// Common functions for CLI.
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    io::{IsTerminal, Write},
+    sync::Arc,
+    time::Duration,
+};
 
-use crate::config::ServerConfig;
+use anyhow::{anyhow, Context, Result};
+use tokio::sync::Semaphore;
+
+use crate::{
+    cli::{executor::Task, GlobalArgs},
+    config::ServerConfig,
+    utils,
+};
+
+/// Resolve server names and glob patterns (`web*`, `web[1-3]`) against the
+/// configured servers, in the order given and without duplicates
+pub fn select_servers(
+    selectors: &[String],
+    servers: &HashMap<String, ServerConfig>,
+    section: &str,
+) -> Result<Vec<String>> {
+    let mut selected: Vec<String> = vec![];
+
+    for selector in selectors.iter() {
+        let mut matched = if utils::is_glob(selector) {
+            servers
+                .keys()
+                .filter(|name| utils::glob_match(selector, name))
+                .cloned()
+                .collect::<Vec<_>>()
+        } else if servers.contains_key(selector) {
+            vec![selector.clone()]
+        } else {
+            return Err(anyhow!(
+                "Server '{}' not found in {} config",
+                selector,
+                section
+            ));
+        };
+
+        if matched.is_empty() {
+            return Err(anyhow!(
+                "Pattern '{}' matches no servers in {} config",
+                selector,
+                section
+            ));
+        }
+
+        matched.sort();
+        for name in matched {
+            if !selected.contains(&name) {
+                selected.push(name);
+            }
+        }
+    }
+
+    Ok(selected)
+}
+
+/// Prompt for the passwords of password-auth servers one by one,
+/// so connections never wait on stdin once tasks are running
+pub fn prompt_passwords(tasks: &mut [Task], servers: &HashMap<String, ServerConfig>) -> Result<()> {
+    for task in tasks.iter_mut() {
+        let needs_password = servers
+            .get(&task.srv_name)
+            .is_some_and(|cfg| cfg.needs_password_prompt());
+        if !needs_password {
+            continue;
+        }
+
+        let password = rpassword::prompt_password(format!(
+            "🔐 Password for {} ({}): ",
+            task.srv_name, task.ssh_client
+        ))
+        .with_context(|| format!("Failed to read password for {}", task.srv_name))?;
+        task.ssh_client.with_password(password);
+    }
+
+    Ok(())
+}
+
+/// Apply global options to the clients of all tasks
+pub fn apply_global_args(tasks: &mut [Task], global: &GlobalArgs) {
+    let connection_limit = global
+        .max_connections
+        .map(|n| Arc::new(Semaphore::new(n as usize)));
+
+    for task in tasks.iter_mut() {
+        task.ssh_client.with_dry_run(global.dry_run);
+        task.ssh_client
+            .with_dry_run_hook(|line| crate::report!("{}", line));
+        task.ssh_client.with_connect_retries(global.connect_retries);
+        if let Some(secs) = global.keepalive {
+            task.ssh_client.with_keepalive(Duration::from_secs(secs));
+        }
+        if let Some(ref limit) = connection_limit {
+            task.ssh_client.with_connection_limit(Arc::clone(limit));
+        }
+    }
+}
+
+/// Ask before running a disruptive operation, skipped with --yes or --dry-run.
+/// Non-interactive sessions must pass --yes.
+pub fn confirm(operation: &str, server_count: usize, global: &GlobalArgs) -> Result<()> {
+    if global.yes || global.dry_run {
+        return Ok(());
+    }
+
+    if !std::io::stdin().is_terminal() || !std::io::stdout().is_terminal() {
+        return Err(anyhow!(
+            "Refusing to run {} on {} servers without confirmation, pass --yes in non-interactive sessions",
+            operation,
+            server_count
+        ));
+    }
+
+    eprint!(
+        "⚠️  About to run {} on {} servers, continue? [y/N] ",
+        operation, server_count
+    );
+    std::io::stderr().flush()?;
+
+    let mut answer = String::new();
+    std::io::stdin()
+        .read_line(&mut answer)
+        .context("Failed to read confirmation")?;
+
+    match answer.trim().to_lowercase().as_str() {
+        "y" | "yes" => Ok(()),
+        _ => Err(anyhow!("Aborted by user")),
+    }
+}
 
 /// List all servers.
 pub fn list_servers(servers: &HashMap<String, ServerConfig>) {
@@ -1,19 +1,86 @@
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::{collections::HashMap, future::Future};
+use std::time::{Duration, Instant};
+use std::{collections::HashMap, fs, future::Future};
 
-use anyhow::Result;
+use anyhow::{anyhow, Context, Result};
 use tokio::sync::{mpsc, Mutex};
 
 use crate::config::ServerConfig;
-use crate::ssh::Client;
+use crate::ssh::{Client, ConnectError, Session};
+use crate::utils::PermanentError;
 
-use crate::retry_operation;
+use crate::cli::reporter::Reporter;
+use crate::{report, retry_operation};
 
 /// A task containing server name and client for execution
-#[derive(Debug)]
 pub struct Task {
     pub srv_name: String,
     pub ssh_client: Client,
+    /// session shared by all actions on this server during the run
+    session: Mutex<Option<Arc<Session>>>,
+}
+
+impl Task {
+    pub fn new(srv_name: String, ssh_client: Client) -> Self {
+        Self {
+            srv_name,
+            ssh_client,
+            session: Mutex::new(None),
+        }
+    }
+
+    /// Get the cached session, connecting on first use
+    pub async fn session(&self) -> Result<Arc<Session>> {
+        let mut session = self.session.lock().await;
+        if let Some(ref session) = *session {
+            return Ok(Arc::clone(session));
+        }
+
+        let connected = match self.ssh_client.connect().await {
+            Ok(connected) => Arc::new(connected),
+            Err(e) => {
+                log::error!(
+                    "Failed to connect to {}({})",
+                    self.srv_name,
+                    self.ssh_client
+                );
+                return Err(e);
+            }
+        };
+        *session = Some(Arc::clone(&connected));
+
+        Ok(connected)
+    }
+
+    /// Drop the cached session so the next action reconnects
+    pub async fn reset_session(&self) {
+        self.session.lock().await.take();
+    }
+
+    /// Reboot the server and cache the session opened once it is back
+    pub async fn reboot(&self, timeout: Duration) -> Result<()> {
+        let mut cached = self.session.lock().await;
+        let session = match cached.take() {
+            Some(session) => session,
+            None => Arc::new(self.ssh_client.connect().await?),
+        };
+        let session = Arc::try_unwrap(session)
+            .map_err(|_| anyhow!("Session of {} is still in use", self.srv_name))?;
+
+        *cached = Some(Arc::new(session.reboot_and_wait(timeout).await?));
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for Task {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Task")
+            .field("srv_name", &self.srv_name)
+            .field("ssh_client", &self.ssh_client)
+            .finish_non_exhaustive()
+    }
 }
 
 impl std::fmt::Display for Task {
@@ -22,22 +89,49 @@ impl std::fmt::Display for Task {
     }
 }
 
+/// Details of a successful task, reported in JSON output
+#[derive(Debug, Default)]
+pub struct TaskOutput {
+    pub exit_status: Option<u32>,
+    pub bytes: Option<u64>,
+    pub output: Option<String>,
+    /// structured result, e.g. parsed firewall rules
+    pub data: Option<serde_json::Value>,
+}
+
 /// List all server tasks
 pub fn list_tasks(tasks: &Vec<Task>) {
     if tasks.is_empty() {
-        println!("📝 No servers to process");
+        report!("📝 No servers to process");
         return;
     }
 
-    println!("\n🎯 Target Servers ({})", tasks.len());
-    println!("{}", "─".repeat(40));
+    report!("\n🎯 Target Servers ({})", tasks.len());
+    report!("{}", "─".repeat(40));
 
     for (i, task) in tasks.iter().enumerate() {
         let server_num = i + 1;
-        println!("{:2} - {}", server_num, task);
+        report!("{:2} - {}", server_num, task);
     }
 
-    println!("{}", "─".repeat(40));
+    report!("{}", "─".repeat(40));
+}
+
+/// How tasks are run across servers
+#[derive(Debug, Clone, Copy)]
+pub struct Schedule {
+    /// tasks running at the same time
+    pub threads: usize,
+    /// retry attempts for a failed task
+    pub max_retry: u32,
+    /// start no new tasks once one has failed
+    pub fail_fast: bool,
+    /// run servers in groups of this size, all at once if None
+    pub batch_size: Option<usize>,
+    /// wait between batches
+    pub batch_pause: Duration,
+    /// give up on a task, retries included, after this long
+    pub task_timeout: Option<Duration>,
 }
 
 /// Build server tasks from server configs
@@ -45,50 +139,145 @@ pub fn build_tasks(server_config: &HashMap<String, ServerConfig>) -> Result<Vec<
     let mut tasks = vec![];
 
     for (srv_name, srv_config) in server_config.iter() {
-        if srv_config.use_password.unwrap_or(false) {
-            println!(
-                "🔐 {} ({}@{}:{}) requires password authentication",
-                srv_name,
-                srv_config.username,
-                srv_config.host,
-                srv_config.port.unwrap_or(22)
-            );
-        }
-
-        tasks.push(Task {
-            srv_name: srv_name.clone(),
-            ssh_client: srv_config.build_client()?,
-        });
+        tasks.push(Task::new(srv_name.clone(), srv_config.build_client()?));
     }
 
     Ok(tasks)
 }
 
-/// Generic concurrent task executor using producer-consumer pattern
+/// Generic concurrent task executor using producer-consumer pattern,
+/// `action` labels the results in JSON output.
+/// Returns an error if any task still failed after retries or was skipped by fail-fast.
 pub async fn execute_tasks<F, Fut>(
-    thread_num: usize,
-    max_retry: u32,
+    action: &str,
+    schedule: Schedule,
     tasks: Vec<Task>,
     executor: F,
 ) -> Result<()>
 where
     F: Fn(usize, Arc<Task>) -> Fut + Send + Sync + 'static,
-    Fut: Future<Output = Result<()>> + Send + 'static,
+    Fut: Future<Output = Result<TaskOutput>> + Send + 'static,
 {
     if tasks.is_empty() {
         return Ok(());
     }
 
-    let thread_num = std::cmp::min(thread_num, tasks.len());
+    let start = Instant::now();
+    let total = tasks.len();
+    let dry_run = tasks.iter().any(|task| task.ssh_client.is_dry_run());
+    let batch_size = schedule.batch_size.unwrap_or(total).clamp(1, total);
+    let batches = total.div_ceil(batch_size);
 
-    let (sender, receiver) = mpsc::channel(tasks.len());
-    let receiver = Arc::new(Mutex::new(receiver));
+    let (result_sender, mut result_receiver) = mpsc::unbounded_channel();
     let executor = Arc::new(executor);
+    let stop = Arc::new(AtomicBool::new(false));
+
+    let mut queue = tasks.into_iter().enumerate().collect::<Vec<_>>();
+    let mut skipped = vec![];
+    for batch_num in 1..=batches {
+        // fail-fast also cancels the batches that have not started yet
+        if stop.load(Ordering::SeqCst) {
+            skipped.extend(queue.drain(..).map(|(_, task)| task.srv_name));
+            break;
+        }
+
+        let rest = queue.split_off(batch_size.min(queue.len()));
+        let batch = std::mem::replace(&mut queue, rest);
+        if batches > 1 {
+            report!(
+                "\n📦 Batch {}/{} ({} servers)",
+                batch_num,
+                batches,
+                batch.len()
+            );
+        }
+
+        skipped.extend(
+            run_batch(
+                action,
+                schedule,
+                batch,
+                Arc::clone(&executor),
+                result_sender.clone(),
+                Arc::clone(&stop),
+            )
+            .await?,
+        );
+
+        if batch_num < batches && !schedule.batch_pause.is_zero() && !stop.load(Ordering::SeqCst) {
+            report!(
+                "⏸️  Pausing {:.0}s before the next batch",
+                schedule.batch_pause.as_secs_f64()
+            );
+            tokio::time::sleep(schedule.batch_pause).await;
+        }
+    }
+    drop(result_sender);
+
+    // Collect outcomes, tasks lost to a panicked worker count as failed
+    let mut failures = vec![];
+    let mut succeeded = 0;
+    while let Some(outcome) = result_receiver.recv().await {
+        match outcome {
+            TaskOutcome::Succeeded => succeeded += 1,
+            TaskOutcome::Failed(failure) => failures.push(failure),
+        }
+    }
+    let failed = total - succeeded - skipped.len();
+
+    print_summary(total, succeeded, &skipped, &mut failures, start.elapsed());
+
+    let failed_names = failures
+        .iter()
+        .map(|failure| failure.srv_name.clone())
+        .chain(skipped.iter().cloned())
+        .collect::<Vec<_>>();
+    // a dry run has nothing to retry
+    if !dry_run {
+        if let Err(e) = save_failed(&failed_names) {
+            log::warn!("Failed to save the failed servers: {:#}", e);
+        }
+    }
+
+    if failed > 0 || !skipped.is_empty() {
+        let exit_status = failures
+            .iter()
+            .filter_map(|failure| failure.exit_status)
+            .max();
+        return Err(TasksFailed {
+            failed,
+            total,
+            exit_status,
+        }
+        .into());
+    }
+
+    Ok(())
+}
+
+/// Run one batch of tasks on a pool of workers.
+/// Returns the servers left queued because of fail-fast
+async fn run_batch<F, Fut>(
+    action: &str,
+    schedule: Schedule,
+    batch: Vec<(usize, Task)>,
+    executor: Arc<F>,
+    results: mpsc::UnboundedSender<TaskOutcome>,
+    stop: Arc<AtomicBool>,
+) -> Result<Vec<String>>
+where
+    F: Fn(usize, Arc<Task>) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<TaskOutput>> + Send + 'static,
+{
+    let thread_num = std::cmp::min(schedule.threads, batch.len());
+
+    let (sender, receiver) = mpsc::channel(batch.len());
+    let receiver = Arc::new(Mutex::new(receiver));
 
     log::info!(
         "Starting execution with {} threads for {} tasks",
         thread_num,
-        tasks.len()
+        batch.len()
     );
 
     // Spawn worker threads
@@ -96,36 +285,291 @@ where
     for _ in 0..thread_num {
         let receiver = Arc::clone(&receiver);
         let executor = Arc::clone(&executor);
+        let results = results.clone();
+        let action = action.to_string();
+        let stop = Arc::clone(&stop);
 
         handles.push(tokio::spawn(async move {
-            task_worker(max_retry, executor, receiver).await;
+            task_worker(&action, schedule, executor, receiver, results, stop).await;
         }));
     }
+    drop(results);
 
     // Send all tasks to the channel
-    for (idx, task) in tasks.into_iter().enumerate() {
-        let _ = sender.send((idx, task)).await?;
+    for task in batch {
+        sender.send(task).await?;
     }
     drop(sender);
 
     // Wait for all workers to complete
     for handle in handles {
-        let _ = handle.await;
+        if let Err(e) = handle.await {
+            log::error!("Worker panicked: {}", e);
+        }
+    }
+
+    // Tasks still queued were never started because of fail-fast
+    let mut skipped = vec![];
+    let mut receiver = receiver.lock().await;
+    while let Ok((_, task)) = receiver.try_recv() {
+        skipped.push(task.srv_name);
+    }
+
+    Ok(skipped)
+}
+
+/// State file listing servers that failed in the last run,
+/// kept in the per-user state directory
+pub fn failed_state_file() -> Result<PathBuf> {
+    let dir = dirs::state_dir()
+        .or_else(dirs::data_local_dir)
+        .ok_or_else(|| anyhow!("No state directory for the current user"))?;
+    Ok(dir.join("biusrv").join("last_failed.json"))
+}
+
+/// Record failed servers so the next run can use --retry-failed,
+/// a run without failures clears the previous record
+fn save_failed(names: &[String]) -> Result<()> {
+    let path = failed_state_file()?;
+    if names.is_empty() {
+        if path.exists() {
+            fs::remove_file(&path)
+                .with_context(|| format!("Failed to remove {}", path.display()))?;
+        }
+        return Ok(());
     }
 
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(&path, serde_json::to_string_pretty(names)?)
+        .with_context(|| format!("Failed to write {}", path.display()))?;
     Ok(())
 }
 
-/// Worker function that processes tasks from the channel
+/// Load servers that failed in the last run
+pub fn load_failed() -> Result<Vec<String>> {
+    let path = failed_state_file()?;
+    if !path.exists() {
+        return Err(anyhow!("No failed servers in the previous run"));
+    }
+    let contents =
+        fs::read_to_string(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let names: Vec<String> = serde_json::from_str(&contents)
+        .with_context(|| format!("Failed to parse {}", path.display()))?;
+
+    if names.is_empty() {
+        return Err(anyhow!("No failed servers in the previous run"));
+    }
+
+    Ok(names)
+}
+
+/// Why a task failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum FailureKind {
+    /// The server could not be connected to or authenticated
+    Connection,
+    /// Connected, but the operation itself failed
+    Execution,
+    /// The task ran past --task-timeout
+    Timeout,
+}
+
+impl FailureKind {
+    pub fn of(error: &anyhow::Error) -> Self {
+        if error.chain().any(|e| e.is::<TaskTimedOut>()) {
+            FailureKind::Timeout
+        } else if ConnectError::is_connect_error(error) {
+            FailureKind::Connection
+        } else {
+            FailureKind::Execution
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FailureKind::Connection => "connection",
+            FailureKind::Execution => "execution",
+            FailureKind::Timeout => "timeout",
+        }
+    }
+}
+
+/// A task that ran past its time budget
+#[derive(Debug)]
+pub struct TaskTimedOut(pub Duration);
+
+impl std::fmt::Display for TaskTimedOut {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Task timed out after {}s", self.0.as_secs())
+    }
+}
+
+impl std::error::Error for TaskTimedOut {}
+
+/// A remote command that exited with a non-zero status
+#[derive(Debug)]
+pub struct CommandFailed {
+    pub srv_name: String,
+    pub exit_status: u32,
+}
+
+impl CommandFailed {
+    /// Exit status of the failed command, if the error is one
+    pub fn exit_status_of(error: &anyhow::Error) -> Option<u32> {
+        error
+            .chain()
+            .find_map(|e| match e.downcast_ref::<PermanentError>() {
+                Some(permanent) => Self::exit_status_of(permanent.inner()),
+                None => e.downcast_ref::<CommandFailed>().map(|e| e.exit_status),
+            })
+    }
+
+    /// Whether the exit status means the command could not run at all:
+    /// 126 not executable, 127 not found, retrying will not help
+    pub fn is_permanent(&self) -> bool {
+        matches!(self.exit_status, 126 | 127)
+    }
+}
+
+impl std::fmt::Display for CommandFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Command failed on {} (exit code: {})",
+            self.srv_name, self.exit_status
+        )
+    }
+}
+
+impl std::error::Error for CommandFailed {}
+
+/// Some tasks of a run still failed after retries
+#[derive(Debug)]
+pub struct TasksFailed {
+    pub failed: usize,
+    pub total: usize,
+    /// worst exit status of a failed remote command
+    pub exit_status: Option<u32>,
+}
+
+impl TasksFailed {
+    /// Process exit code for the run, the worst remote exit status or 1
+    pub fn exit_code(&self) -> i32 {
+        match self.exit_status {
+            Some(status @ 1..=255) => status as i32,
+            _ => 1,
+        }
+    }
+}
+
+impl std::fmt::Display for TasksFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} of {} tasks failed", self.failed, self.total)
+    }
+}
+
+impl std::error::Error for TasksFailed {}
+
+/// A task that still failed after retries
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct Failure {
+    kind: FailureKind,
+    srv_name: String,
+    error: String,
+    // exit status when a remote command failed
+    exit_status: Option<u32>,
+}
+
+/// Final outcome of a task after retries
+enum TaskOutcome {
+    Succeeded,
+    Failed(Failure),
+}
+
+/// Print the end-of-run summary
+fn print_summary(
+    total: usize,
+    succeeded: usize,
+    skipped: &[String],
+    failures: &mut [Failure],
+    elapsed: Duration,
+) {
+    let count = |kind| {
+        failures
+            .iter()
+            .filter(|failure| failure.kind == kind)
+            .count()
+    };
+    let unreachable = count(FailureKind::Connection);
+    let timed_out = count(FailureKind::Timeout);
+
+    report!("\n📊 Summary");
+    report!("{}", "─".repeat(40));
+    report!(
+        "Total: {} | ✅ Succeeded: {} | ❌ Failed: {} | ⏱️  Elapsed: {:.1}s",
+        total,
+        succeeded,
+        total - succeeded - skipped.len(),
+        elapsed.as_secs_f64()
+    );
+
+    if !skipped.is_empty() {
+        report!(
+            "⏹️  Stopped by --fail-fast, skipped {}: {}",
+            skipped.len(),
+            skipped.join(", ")
+        );
+    }
+
+    if !failures.is_empty() {
+        report!(
+            "🔌 Unreachable: {} | 💥 Operation failed: {} | ⌛ Timed out: {}",
+            unreachable,
+            failures.len() - unreachable - timed_out,
+            timed_out
+        );
+
+        failures.sort();
+        let mut kind = None;
+        for failure in failures.iter() {
+            if kind != Some(failure.kind) {
+                kind = Some(failure.kind);
+                match failure.kind {
+                    FailureKind::Connection => report!("Unreachable servers:"),
+                    FailureKind::Execution => report!("Failed servers:"),
+                    FailureKind::Timeout => report!("Timed out servers:"),
+                }
+            }
+            match failure.exit_status {
+                Some(status) => report!("  • {} - exited {}", failure.srv_name, status),
+                None => report!("  • {} - {}", failure.srv_name, failure.error),
+            }
+        }
+    }
+    report!("{}", "─".repeat(40));
+}
+
+/// Worker function that processes tasks from the channel,
+/// outcomes are sent back through `results`
 async fn task_worker<F, Fut>(
-    max_retry: u32,
+    action: &str,
+    schedule: Schedule,
     executor: Arc<F>,
     receiver: Arc<Mutex<mpsc::Receiver<(usize, Task)>>>,
+    results: mpsc::UnboundedSender<TaskOutcome>,
+    stop: Arc<AtomicBool>,
 ) where
     F: Fn(usize, Arc<Task>) -> Fut + Send + Sync + 'static,
-    Fut: Future<Output = Result<()>> + Send + 'static,
+    Fut: Future<Output = Result<TaskOutput>> + Send + 'static,
 {
     loop {
+        // leave the remaining tasks queued, they are reported as skipped
+        if stop.load(Ordering::SeqCst) {
+            break;
+        }
+
         let (idx, task) = match receiver.lock().await.recv().await {
             Some((idx, task)) => (idx, task),
             None => break,
@@ -134,7 +578,46 @@ async fn task_worker<F, Fut>(
         let task = Arc::new(task);
         let log_prefix = format!("Server '{} ({})'", task.srv_name, task.ssh_client);
 
-        // Use macro with logging
-        let _ = retry_operation!(max_retry, executor(idx, task.clone()).await, log_prefix);
+        // Use macro with logging, a failed attempt reconnects on retry
+        let attempts = async {
+            retry_operation!(
+                schedule.max_retry,
+                {
+                    let result = executor(idx, task.clone()).await;
+                    if result.is_err() {
+                        task.reset_session().await;
+                    }
+                    result
+                },
+                log_prefix
+            )
+        };
+        let result = match schedule.task_timeout {
+            Some(limit) => match tokio::time::timeout(limit, attempts).await {
+                Ok(result) => result,
+                Err(_) => {
+                    task.reset_session().await;
+                    Err(TaskTimedOut(limit).into())
+                }
+            },
+            None => attempts.await,
+        };
+        Reporter::get().task_result(action, &task, &result);
+
+        let outcome = match result {
+            Ok(_) => TaskOutcome::Succeeded,
+            Err(e) => {
+                if schedule.fail_fast {
+                    stop.store(true, Ordering::SeqCst);
+                }
+                TaskOutcome::Failed(Failure {
+                    kind: FailureKind::of(&e),
+                    srv_name: task.srv_name.clone(),
+                    error: e.to_string(),
+                    exit_status: CommandFailed::exit_status_of(&e),
+                })
+            }
+        };
+        let _ = results.send(outcome);
     }
 }
@@ -1,4 +1,4 @@
-use std::sync::Arc;
+use std::{collections::HashSet, sync::Arc, time::Duration};
 
 use anyhow::{anyhow, Result};
 use clap::Args;
@@ -6,10 +6,14 @@ use clap::Args;
 use crate::{
     cli::{
         common,
-        executor::{self, Task},
+        executor::{self, Schedule, Task, TaskOutput},
+        GlobalArgs,
     },
     config::InitConfig,
-    init::InitServer,
+    init::{self, InitChanges, InitEvent, InitPhase, InitServer},
+    report,
+    ssh::Session,
+    sshd,
 };
 
 #[derive(Args)]
@@ -20,7 +24,7 @@ pub struct InitCommand {
     /// Initialize all servers
     #[arg(long)]
     pub all_servers: bool,
-    /// Specify server names to initialize
+    /// Specify server names or glob patterns (`web*`, `web[1-3]`) to initialize
     #[arg(short, long, value_delimiter = ',')]
     pub server: Vec<String>,
     /// Threads to use for initialization, default is cpu cores
@@ -29,10 +33,75 @@ pub struct InitCommand {
     /// Maximum retry attempts for failed operations
     #[arg(long, default_value = "0")]
     pub max_retry: u32,
+    /// Give up on a server after this many seconds, retries included
+    #[arg(long, value_name = "SECS")]
+    pub task_timeout: Option<u64>,
+    /// Stop starting new servers after the first failure
+    #[arg(long)]
+    pub fail_fast: bool,
+    /// Initialize only the servers that failed in the previous run
+    #[arg(long, conflicts_with_all = ["all_servers", "server"])]
+    pub retry_failed: bool,
+    /// Keep partial changes when initialization fails instead of rolling back
+    #[arg(long)]
+    pub no_rollback: bool,
+    /// Skip phases already recorded in the remote checkpoint
+    #[arg(long, conflicts_with = "restart")]
+    pub resume: bool,
+    /// Clear the remote checkpoint and run every phase again
+    #[arg(long)]
+    pub restart: bool,
+    /// Skip these phases (update, packages, user, sudo, sshd, fail2ban, sysctl, mounts, commands, firewall, reload)
+    #[arg(long, value_delimiter = ',', value_parser = parse_phase, conflicts_with = "only")]
+    pub skip: Vec<InitPhase>,
+    /// Run only these phases, sshd is still reloaded unless `reload` is skipped
+    #[arg(long, value_delimiter = ',', value_parser = parse_phase)]
+    pub only: Vec<InitPhase>,
+    /// Reconnect as the new user on the new port afterwards and fail if `whoami` does not answer
+    #[arg(long)]
+    pub verify: bool,
+    /// Private key for --verify, default is the generated key, the user's password or the current key
+    #[arg(long, value_name = "PATH", requires = "verify")]
+    pub verify_key: Option<String>,
+}
+
+fn parse_phase(name: &str) -> Result<InitPhase> {
+    InitPhase::from_name(name).ok_or_else(|| {
+        let names = InitPhase::ALL.map(|phase| phase.name());
+        anyhow!(
+            "Unknown init phase '{}', expected one of: {}",
+            name,
+            names.join(", ")
+        )
+    })
+}
+
+/// Per-run init behavior shared by all servers
+#[derive(Clone)]
+struct InitOptions {
+    rollback: bool,
+    resume: bool,
+    restart: bool,
+    phases: Arc<HashSet<InitPhase>>,
+    verify: bool,
+    verify_key: Option<String>,
 }
 
 impl InitCommand {
-    pub async fn execute(&self, config: &InitConfig) -> Result<()> {
+    // Phases selected by --only or --skip
+    fn selected_phases(&self) -> HashSet<InitPhase> {
+        if !self.only.is_empty() {
+            let mut phases: HashSet<_> = self.only.iter().copied().collect();
+            phases.insert(InitPhase::Reload);
+            return phases;
+        }
+        InitPhase::ALL
+            .into_iter()
+            .filter(|phase| !self.skip.contains(phase))
+            .collect()
+    }
+
+    pub async fn execute(&self, config: &InitConfig, global: &GlobalArgs) -> Result<()> {
         let srv_config = config
             .server
             .as_ref()
@@ -49,24 +118,29 @@ impl InitCommand {
         }
 
         // Handle all servers case
-        let tasks = if self.all_servers {
+        let mut tasks = if self.all_servers {
             executor::build_tasks(srv_config)?
-        } else if !self.server.is_empty() {
+        } else if self.retry_failed || !self.server.is_empty() {
+            let selectors = if self.retry_failed {
+                executor::load_failed()?
+            } else {
+                self.server.clone()
+            };
+            let servers = common::select_servers(&selectors, srv_config, "init")?;
+
             let mut tasks = vec![];
-            for server_name in self.server.iter() {
-                let cfg = srv_config
-                    .get(server_name)
-                    .ok_or_else(|| anyhow!("Server '{}' not found in init config", server_name))?;
-                tasks.push(Task {
-                    srv_name: server_name.clone(),
-                    ssh_client: cfg.build_client()?,
-                });
+            for server_name in servers.iter() {
+                let cfg = &srv_config[server_name];
+                tasks.push(Task::new(server_name.clone(), cfg.build_client()?));
             }
             tasks
         } else {
             return Err(anyhow!("No servers specified. Use --server to specify servers or --all-servers to initialize all servers."));
         };
 
+        common::prompt_passwords(&mut tasks, srv_config)?;
+        common::apply_global_args(&mut tasks, global);
+
         let init_server = Arc::new(InitServer::new(config));
 
         // Handle multiple servers or all servers
@@ -76,105 +150,193 @@ impl InitCommand {
                 .unwrap_or(4),
         );
 
-        println!("\n🚀 Server Initialization");
-        println!("{}", "═".repeat(50));
+        report!("\n🚀 Server Initialization");
+        report!("{}", "═".repeat(50));
         executor::list_tasks(&tasks);
+        if let Some(ref sshd_config) = config.sshd {
+            for warning in sshd::check_options(sshd_config) {
+                report!("⚠️  {}", warning);
+            }
+        }
+        common::confirm("init", tasks.len(), global)?;
 
-        executor::execute_tasks(thread_num, self.max_retry, tasks, move |_, task| {
+        let options = InitOptions {
+            rollback: !self.no_rollback,
+            resume: self.resume,
+            restart: self.restart,
+            phases: Arc::new(self.selected_phases()),
+            verify: self.verify,
+            verify_key: self.verify_key.clone(),
+        };
+        let schedule = Schedule {
+            threads: thread_num,
+            max_retry: self.max_retry,
+            fail_fast: self.fail_fast,
+            task_timeout: self.task_timeout.map(Duration::from_secs),
+            batch_size: None,
+            batch_pause: Duration::ZERO,
+        };
+        executor::execute_tasks("init", schedule, tasks, move |_, task| {
             let init_server = Arc::clone(&init_server);
-            handle_server(init_server, task)
+            handle_server(init_server, task, options.clone())
         })
         .await
     }
 }
 
 // Handle single server initialization
-async fn handle_server(init_server: Arc<InitServer>, task: Arc<Task>) -> Result<()> {
-    println!("🔧 Initializing: {}", task.srv_name);
+async fn handle_server(
+    init_server: Arc<InitServer>,
+    task: Arc<Task>,
+    options: InitOptions,
+) -> Result<TaskOutput> {
+    report!("🔧 Initializing: {}", task.srv_name);
 
-    if let Err(e) = run_init(&init_server, &task).await {
-        println!("❌ {} ({}) - Failed: {}", task.srv_name, task.ssh_client, e);
-    } else {
-        println!("✅ {} ({}) - Success", task.srv_name, task.ssh_client);
+    if let Err(e) = run_init(&init_server, &task, options).await {
+        report!("❌ {} ({}) - Failed: {}", task.srv_name, task.ssh_client, e);
+        return Err(e);
     }
 
-    Ok(())
+    report!("✅ {} ({}) - Success", task.srv_name, task.ssh_client);
+    Ok(TaskOutput::default())
 }
 
-pub async fn run_init(init_server: &InitServer, task: &Task) -> Result<()> {
-    let session = task.ssh_client.connect().await?;
-
-    println!(
-        "  📦 {} ({}) → Updating system packages",
-        task.srv_name, task.ssh_client
-    );
-    init_server.update_system(&session).await?;
+async fn run_init(init_server: &InitServer, task: &Task, options: InitOptions) -> Result<()> {
+    let session = task.session().await?;
 
-    println!(
-        "  📥 {} ({}) → Installing required packages",
-        task.srv_name, task.ssh_client
-    );
-    init_server.install_required(&session).await?;
+    if options.restart {
+        init::clear_checkpoint(&session).await?;
+    }
 
-    println!(
-        "  👤 {} ({}) → Creating user account",
-        task.srv_name, task.ssh_client
-    );
-    init_server.create_user(&session).await?;
+    let done = if options.resume {
+        init::read_checkpoint(&session).await?
+    } else {
+        HashSet::new()
+    };
 
-    println!(
-        "  🔐 {} ({}) → Setting up sudo permissions",
-        task.srv_name, task.ssh_client
-    );
-    init_server.setup_sudo(&session).await?;
+    let mut changes = InitChanges::default();
+    let result = init_server
+        .run_phases(&session, &options.phases, &done, &mut changes, |event| {
+            report_event(task, event)
+        })
+        .await;
 
-    let mut ssh_port = 22;
-    if let Some(ref sshd_config) = init_server.sshd_config {
-        println!(
-            "  🔑 {} ({}) → Configuring SSH daemon",
-            task.srv_name, task.ssh_client
-        );
-        init_server.configure_sshd(&session, sshd_config).await?;
-        if let Some(port) = sshd_config.new_port {
-            ssh_port = port;
+    if let Err(e) = result {
+        if options.rollback && !changes.phases.is_empty() {
+            rollback_phases(init_server, task, &session, &changes).await;
         }
+        return Err(e);
     }
 
-    if let Some(ref fail2ban_config) = init_server.fail2ban_config {
-        println!(
-            "  🛡️ {} ({}) → Setting up Fail2ban protection",
-            task.srv_name, task.ssh_client
+    // the new port and login are only live once sshd was reloaded
+    if options.verify && options.phases.contains(&InitPhase::Reload) && !session.is_dry_run() {
+        report!(
+            "  🔁 {} ({}) → Verifying login",
+            task.srv_name,
+            task.ssh_client
         );
-        init_server
-            .setup_fail2ban(&session, fail2ban_config)
+        let verified = init_server
+            .verify_login(&task.ssh_client, options.verify_key.as_deref())
             .await?;
-    }
-
-    if let Some(ref commands) = init_server.commands {
-        println!(
-            "  ⚡ {} ({}) → Executing custom commands",
-            task.srv_name, task.ssh_client
+        report!(
+            "     {} ({}) ↳ Logged in as {}",
+            task.srv_name,
+            task.ssh_client,
+            verified
         );
-        init_server
-            .execute_custom_commands(&session, commands)
-            .await?;
     }
 
-    if let Some(ref firewall_config) = init_server.firewall_config {
-        println!(
-            "  🔥 {} ({}) → Configuring firewall",
-            task.srv_name, task.ssh_client
-        );
-        init_server
-            .setup_firewall(&session, ssh_port, firewall_config)
-            .await?;
-    }
+    Ok(())
+}
 
-    println!(
-        "  🔄 {} ({}) → Reloading SSH daemon",
-        task.srv_name, task.ssh_client
+// Undo changed phases in reverse order, best effort
+async fn rollback_phases(
+    init_server: &InitServer,
+    task: &Task,
+    session: &Session,
+    changes: &InitChanges,
+) {
+    report!(
+        "  ↩️ {} ({}) → Rolling back changes",
+        task.srv_name,
+        task.ssh_client
     );
-    init_server.reload_sshd(&session).await?;
 
-    Ok(())
+    for phase in changes.phases.iter().rev() {
+        match init_server.rollback(session, *phase, changes).await {
+            Ok(true) => {
+                report!(
+                    "     {} ({}) ↳ {} rolled back",
+                    task.srv_name,
+                    task.ssh_client,
+                    phase
+                );
+                if let Err(e) = init::unmark_completed(session, *phase).await {
+                    report!(
+                        "     {} ({}) ↳ {} checkpoint not cleared: {}",
+                        task.srv_name,
+                        task.ssh_client,
+                        phase,
+                        e
+                    );
+                }
+            }
+            Ok(false) => report!(
+                "     {} ({}) ↳ {} cannot be rolled back, skipped",
+                task.srv_name,
+                task.ssh_client,
+                phase
+            ),
+            Err(e) => report!(
+                "     {} ({}) ↳ {} rollback failed: {}",
+                task.srv_name,
+                task.ssh_client,
+                phase,
+                e
+            ),
+        }
+    }
+}
+
+/// Print the progress of an init run
+fn report_event(task: &Task, event: InitEvent) {
+    match event {
+        InitEvent::Started(phase) => {
+            let (icon, action) = match phase {
+                InitPhase::Update => ("📦", "Updating system packages"),
+                InitPhase::Packages => ("📥", "Installing required packages"),
+                InitPhase::User => ("👤", "Creating user accounts"),
+                InitPhase::Sudo => ("🔐", "Setting up sudo permissions"),
+                InitPhase::Sshd => ("🔑", "Configuring SSH daemon"),
+                InitPhase::Fail2ban => ("🛡️", "Setting up Fail2ban protection"),
+                InitPhase::Sysctl => ("🧮", "Applying kernel parameters"),
+                InitPhase::Mounts => ("💾", "Mounting filesystems"),
+                InitPhase::Commands => ("⚡", "Executing custom commands"),
+                InitPhase::Firewall => ("🔥", "Configuring firewall"),
+                InitPhase::Reload => ("🔄", "Reloading SSH daemon"),
+            };
+            report!(
+                "  {} {} ({}) → {}",
+                icon,
+                task.srv_name,
+                task.ssh_client,
+                action
+            );
+        }
+        InitEvent::Finished(_, Some(status)) => {
+            report!("     {} ({}) ↳ {}", task.srv_name, task.ssh_client, status)
+        }
+        InitEvent::Finished(_, None) => {}
+        InitEvent::Skipped(phase) => report!(
+            "  ⏭️ {} ({}) → Skipping {} (already completed)",
+            task.srv_name,
+            task.ssh_client,
+            phase
+        ),
+        InitEvent::RebootRequired => report!(
+            "  ⚠️  {} ({}) → Reboot required to finish the system update",
+            task.srv_name,
+            task.ssh_client
+        ),
+    }
 }
@@ -1,19 +1,26 @@
 /// Manage server.
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
 use clap::{Args, Subcommand};
+use std::time::Duration;
 
 pub mod exec;
+pub mod facts;
 pub mod firewall;
+pub mod ping;
 /// Manage action modules
 pub mod script;
+pub mod service;
 pub mod transfer;
+pub mod tunnel;
 
 use crate::{
     cli::{
         common,
-        executor::{self, Task},
+        executor::{self, Schedule, Task},
+        GlobalArgs,
     },
     config::ManageConfig,
+    report,
 };
 
 #[derive(Args)]
@@ -24,7 +31,7 @@ pub struct ManageCommand {
     /// Manage all servers
     #[arg(long, global = true)]
     pub all_servers: bool,
-    /// Specify server names to manage
+    /// Specify server names or glob patterns (`web*`, `web[1-3]`) to manage
     #[arg(short, long, value_delimiter = ',', global = true)]
     pub server: Vec<String>,
     /// Threads to use for parallel operations, default is cpu cores
@@ -33,6 +40,39 @@ pub struct ManageCommand {
     /// Maximum retry attempts for failed operations
     #[arg(long, default_value = "0", global = true)]
     pub max_retry: u32,
+    /// Give up on a server after this many seconds, retries included
+    #[arg(long, global = true, value_name = "SECS")]
+    pub task_timeout: Option<u64>,
+    /// Stop starting new servers after the first failure
+    #[arg(long, global = true)]
+    pub fail_fast: bool,
+    /// Run servers in batches of this size, one batch after another
+    #[arg(long, global = true, value_name = "N")]
+    pub batch_size: Option<usize>,
+    /// Seconds to wait between batches
+    #[arg(
+        long,
+        global = true,
+        value_name = "SECS",
+        default_value = "0",
+        requires = "batch_size"
+    )]
+    pub batch_pause: u64,
+    /// Manage only the servers that failed in the previous run
+    #[arg(long, global = true, conflicts_with_all = ["all_servers", "server"])]
+    pub retry_failed: bool,
+    /// Connect to this SSH port instead of the configured one
+    #[arg(long, global = true)]
+    pub port: Option<u16>,
+    /// Connect as this user instead of the configured one
+    #[arg(long, global = true, alias = "as-user")]
+    pub user: Option<String>,
+    /// Authenticate with this private key instead of the configured auth
+    #[arg(long, global = true, conflicts_with = "password")]
+    pub key: Option<String>,
+    /// Prompt once for a password used on every targeted server
+    #[arg(long, global = true)]
+    pub password: bool,
     /// Manage action to perform
     #[command(subcommand)]
     pub action: Option<ManageAction>,
@@ -48,10 +88,23 @@ pub enum ManageAction {
     Firewall(firewall::FirewallAction),
     /// Transfer files (upload, download)
     Transfer(transfer::TransferAction),
+    /// Manage services (start, stop, restart, reload, enable, disable, status)
+    Service(service::ServiceAction),
+    /// Forward local ports through the server
+    Tunnel(tunnel::TunnelAction),
+    /// Collect system facts (distro, kernel, CPUs, memory, disks, uptime)
+    Facts(facts::FactsAction),
+    /// Check that servers are reachable and accept the login, changes nothing
+    Ping(ping::PingAction),
 }
 
 impl ManageCommand {
-    pub async fn execute(&self, config: &ManageConfig) -> Result<()> {
+    /// Whether the action writes file contents to stdout
+    pub fn writes_stdout(&self) -> bool {
+        matches!(&self.action, Some(ManageAction::Transfer(action)) if action.cat || action.stdout)
+    }
+
+    pub async fn execute(&self, config: &ManageConfig, global: &GlobalArgs) -> Result<()> {
         let srv_config = config
             .server
             .as_ref()
@@ -64,7 +117,7 @@ impl ManageCommand {
         }
 
         let action = self.action.as_ref().ok_or_else(|| {
-            anyhow!("Please specify an action: use subcommands (script, exec, firewall, transfer)")
+            anyhow!("Please specify an action: use subcommands (script, exec, firewall, transfer, service, tunnel, facts, ping)")
         })?;
 
         // execute actions that don't need server
@@ -73,61 +126,122 @@ impl ManageCommand {
             ManageAction::Exec(action) => action.local_execute()?,
             ManageAction::Firewall(action) => action.local_execute()?,
             ManageAction::Transfer(action) => action.local_execute()?,
+            ManageAction::Service(action) => action.local_execute()?,
+            ManageAction::Tunnel(action) => action.local_execute()?,
+            ManageAction::Facts(action) => action.local_execute()?,
+            ManageAction::Ping(action) => action.local_execute()?,
         } {
             return Ok(());
         }
 
         // build tasks
-        let tasks = if self.all_servers {
+        let mut tasks = if self.all_servers {
             executor::build_tasks(srv_config)?
-        } else if !self.server.is_empty() {
+        } else if self.retry_failed || !self.server.is_empty() {
+            let selectors = if self.retry_failed {
+                executor::load_failed()?
+            } else {
+                self.server.clone()
+            };
+            let servers = common::select_servers(&selectors, srv_config, "manage")?;
+
             let mut tasks = vec![];
-            for srv_name in self.server.iter() {
-                let cfg = srv_config
-                    .get(srv_name)
-                    .ok_or_else(|| anyhow!("Server '{}' not found in manage config", srv_name))?;
-                tasks.push(Task {
-                    srv_name: srv_name.clone(),
-                    ssh_client: cfg.build_client()?,
-                });
+            for srv_name in servers.iter() {
+                let cfg = &srv_config[srv_name];
+                tasks.push(Task::new(srv_name.clone(), cfg.build_client()?));
             }
             tasks
         } else {
             return Err(anyhow!("No servers specified. Use --server to specify servers or --all-servers to manage all servers."));
         };
 
-        println!("\n⚙️  Server Management");
-        println!("{}", "═".repeat(50));
+        if self.key.is_none() && !self.password {
+            common::prompt_passwords(&mut tasks, srv_config)?;
+        }
+        self.apply_overrides(&mut tasks)?;
+        common::apply_global_args(&mut tasks, global);
+
+        report!("\n⚙️  Server Management");
+        report!("{}", "═".repeat(50));
         executor::list_tasks(&tasks);
 
+        let disruptive = match action {
+            ManageAction::Firewall(firewall_action) => firewall_action.disruptive_operation(),
+            ManageAction::Service(service_action) => service_action.disruptive_operation(),
+            _ => None,
+        };
+        if let Some(operation) = disruptive {
+            common::confirm(&operation, tasks.len(), global)?;
+        }
+
+        if self.batch_size == Some(0) {
+            return Err(anyhow!("--batch-size must be greater than 0"));
+        }
+
         // get thread number
         let thread_num = self.threads.unwrap_or(
             std::thread::available_parallelism()
                 .map(|n| n.get())
                 .unwrap_or(4),
         );
+        let schedule = Schedule {
+            threads: thread_num,
+            max_retry: self.max_retry,
+            fail_fast: self.fail_fast,
+            task_timeout: self.task_timeout.map(Duration::from_secs),
+            batch_size: self.batch_size,
+            batch_pause: Duration::from_secs(self.batch_pause),
+        };
 
         match action {
             ManageAction::Script(script_action) => {
-                script_action
-                    .remote_execute(thread_num, self.max_retry, tasks)
-                    .await
-            }
-            ManageAction::Exec(exec_action) => {
-                exec_action
-                    .remote_execute(thread_num, self.max_retry, tasks)
-                    .await
+                script_action.remote_execute(schedule, tasks).await
             }
+            ManageAction::Exec(exec_action) => exec_action.remote_execute(schedule, tasks).await,
             ManageAction::Firewall(firewall_action) => {
-                firewall_action
-                    .remote_execute(thread_num, self.max_retry, tasks)
-                    .await
+                firewall_action.remote_execute(schedule, tasks).await
             }
             ManageAction::Transfer(transfer_action) => {
-                transfer_action
-                    .remote_execute(thread_num, self.max_retry, tasks)
-                    .await
+                transfer_action.remote_execute(schedule, tasks).await
+            }
+            ManageAction::Service(service_action) => {
+                service_action.remote_execute(schedule, tasks).await
+            }
+            ManageAction::Tunnel(tunnel_action) => {
+                tunnel_action.remote_execute(schedule, tasks).await
+            }
+            ManageAction::Facts(facts_action) => facts_action.remote_execute(schedule, tasks).await,
+            ManageAction::Ping(ping_action) => ping_action.remote_execute(schedule, tasks).await,
+        }
+    }
+
+    /// Apply the connection overrides given on the command line to every client
+    fn apply_overrides(&self, tasks: &mut [Task]) -> Result<()> {
+        let password = if self.password {
+            let password = rpassword::prompt_password("🔐 Password for all servers: ")
+                .context("Failed to read password")?;
+            Some(password)
+        } else {
+            None
+        };
+
+        for task in tasks.iter_mut() {
+            let client = &mut task.ssh_client;
+            if let Some(port) = self.port {
+                client.with_port(port);
+            }
+            if let Some(ref user) = self.user {
+                client.with_username(user.clone());
+            }
+            if let Some(ref key) = self.key {
+                client.clear_password();
+                client.with_private_key(key.clone());
+            }
+            if let Some(ref password) = password {
+                client.with_password(password.clone());
             }
         }
+
+        Ok(())
     }
 }
@@ -1,15 +1,18 @@
 use anyhow::{anyhow, Context, Result};
 use clap::Args;
 use std::sync::Arc;
+use std::time::Duration;
 
-use crate::cli::executor::{self, Task};
+use crate::cli::executor::{self, CommandFailed, Schedule, Task, TaskOutput};
 use crate::cli::multishell::MultiShell;
+use crate::report;
 use crate::ssh::Client;
+use crate::utils::PermanentError;
 
 #[derive(Args, Clone, Debug)]
 pub struct ExecAction {
     /// Command to execute on remote servers
-    #[arg(required = true, num_args = 1..)]
+    #[arg(required_unless_present_any = ["job", "reboot", "shell"], num_args = 1..)]
     pub command: Vec<String>,
 
     /// Use sudo to execute the command
@@ -20,25 +23,37 @@ pub struct ExecAction {
     #[arg(long)]
     pub hide_output: bool,
 
-    /// Start interactive shell instead of executing command
+    /// Start interactive shell instead of executing command,
+    /// runs the server's configured shell when no command is given
     #[arg(long)]
     pub shell: bool,
+
+    /// Start the command detached in the background and report its PID
+    #[arg(long = "async", conflicts_with = "shell")]
+    pub detach: bool,
+
+    /// Check a background job started with --async by its PID
+    #[arg(long, value_name = "PID", conflicts_with_all = ["shell", "detach"])]
+    pub job: Option<u32>,
+
+    /// Reboot after the command (if any) succeeds and wait for SSH to come back
+    #[arg(long, conflicts_with_all = ["shell", "detach", "job"])]
+    pub reboot: bool,
+
+    /// Seconds to wait for a rebooted server to answer SSH again
+    #[arg(long, default_value = "300", requires = "reboot")]
+    pub reboot_timeout: u64,
 }
 
 impl ExecAction {
     pub fn local_execute(&self) -> Result<bool> {
-        if self.command.is_empty() {
+        if self.command.is_empty() && self.job.is_none() && !self.reboot && !self.shell {
             return Err(anyhow!("Command cannot be empty"));
         }
         Ok(false)
     }
 
-    pub async fn remote_execute(
-        &self,
-        thread_num: usize,
-        max_retry: u32,
-        tasks: Vec<Task>,
-    ) -> Result<()> {
+    pub async fn remote_execute(&self, schedule: Schedule, tasks: Vec<Task>) -> Result<()> {
         if self.shell {
             // Shell mode - start interactive shells
             let shell_cmd = self.command.join(" ");
@@ -53,7 +68,7 @@ impl ExecAction {
         } else {
             // Command execution mode
             let action = Arc::new(self.clone());
-            executor::execute_tasks(thread_num, max_retry, tasks, move |_, task| {
+            executor::execute_tasks("exec", schedule, tasks, move |_, task| {
                 let action = Arc::clone(&action);
                 handle_exec_execute(action, task)
             })
@@ -62,59 +77,179 @@ impl ExecAction {
     }
 }
 
-pub async fn handle_exec_execute(action: Arc<ExecAction>, task: Arc<Task>) -> Result<()> {
-    let session = match task.ssh_client.connect().await {
-        Ok(session) => session,
-        Err(e) => {
-            log::error!(
-                "Failed to connect to {}({})",
-                task.srv_name,
-                task.ssh_client
-            );
-            return Err(e);
-        }
-    };
+pub async fn handle_exec_execute(action: Arc<ExecAction>, task: Arc<Task>) -> Result<TaskOutput> {
+    let mut output = TaskOutput::default();
+    if !action.command.is_empty() {
+        output = run_command(&action, &task).await?;
+    }
+
+    if action.reboot {
+        log::info!("Rebooting server '{}'", task.srv_name);
+        report!("🔄 {} ({}) - Rebooting", task.srv_name, task.ssh_client);
+        let started = std::time::Instant::now();
+        task.reboot(Duration::from_secs(action.reboot_timeout))
+            .await
+            .inspect_err(|e| {
+                report!(
+                    "❌ {} ({}) - Reboot failed: {}",
+                    task.srv_name,
+                    task.ssh_client,
+                    e
+                )
+            })?;
+        report!(
+            "✅ {} ({}) - Back after {:.0}s",
+            task.srv_name,
+            task.ssh_client,
+            started.elapsed().as_secs_f64()
+        );
+    }
+
+    Ok(output)
+}
+
+/// Run the command of an exec action on one server.
+async fn run_command(action: &ExecAction, task: &Task) -> Result<TaskOutput> {
+    let session = task.session().await?;
 
     // Join command parts with spaces
-    let full_command = action.command.join(" ");
+    let full_command = if let Some(pid) = action.job {
+        job_status_command(pid)
+    } else if action.detach {
+        async_command(&action.command.join(" "))
+    } else {
+        action.command.join(" ")
+    };
 
     log::info!("Executing '{}' on server '{}'", full_command, task.srv_name);
 
+    // Default to showing output unless explicitly hidden,
+    // regular commands print it live line by line
+    let show_output = !action.hide_output;
+    let stream_output = show_output && !action.detach;
+
+    let mut printer = LinePrinter::new(&task.srv_name);
+    let on_output = |chunk: &[u8]| {
+        if stream_output {
+            printer.push(chunk);
+        }
+    };
     let result = if action.sudo {
-        session.execute_with_sudo(&full_command).await?
+        session
+            .execute_with_sudo_streaming(&full_command, on_output)
+            .await?
     } else {
-        session.execute_command(&full_command).await?
+        session
+            .execute_command_streaming(&full_command, on_output)
+            .await?
     };
+    printer.finish();
 
-    // Default to showing output unless explicitly hidden
-    let show_output = !action.hide_output;
+    if action.detach && result.exit_status == 0 && !session.is_dry_run() {
+        let pid = result
+            .output
+            .trim()
+            .parse::<u32>()
+            .map_err(|_| anyhow!("Failed to get PID of background job: {}", result.output))?;
+        report!(
+            "🚀 {} ({}) - Started in background (PID: {}, log: {})",
+            task.srv_name,
+            task.ssh_client,
+            pid,
+            job_log_path(pid)
+        );
+        return Ok(TaskOutput {
+            exit_status: Some(result.exit_status),
+            output: Some(pid.to_string()),
+            ..Default::default()
+        });
+    }
 
     if result.exit_status == 0 {
-        println!("✅ {} ({}) - Success", task.srv_name, task.ssh_client);
-        if show_output && !result.output.is_empty() {
-            // Format output with server name prefix
-            for line in result.output.lines() {
-                if !line.trim().is_empty() {
-                    println!("   {}", line);
-                }
-            }
-        }
+        report!("✅ {} ({}) - Success", task.srv_name, task.ssh_client);
     } else {
-        println!(
+        report!(
             "❌ {} ({}) - Failed (exit code: {})",
-            task.srv_name, task.ssh_client, result.exit_status
+            task.srv_name,
+            task.ssh_client,
+            result.exit_status
         );
-        if show_output && !result.output.is_empty() {
-            for line in result.output.lines() {
-                if !line.trim().is_empty() {
-                    println!("   {}", line);
-                }
-            }
+        let failed = CommandFailed {
+            srv_name: task.srv_name.clone(),
+            exit_status: result.exit_status,
+        };
+        return Err(if failed.is_permanent() {
+            PermanentError::wrap(failed)
+        } else {
+            failed.into()
+        });
+    }
+
+    Ok(TaskOutput {
+        exit_status: Some(result.exit_status),
+        output: show_output.then_some(result.output),
+        ..Default::default()
+    })
+}
+
+/// Prints streamed output as complete lines prefixed with the server name.
+struct LinePrinter<'a> {
+    srv_name: &'a str,
+    pending: Vec<u8>,
+}
+
+impl<'a> LinePrinter<'a> {
+    fn new(srv_name: &'a str) -> Self {
+        Self {
+            srv_name,
+            pending: Vec::new(),
         }
-        return Err(anyhow!("Command failed on {}", task.srv_name));
     }
 
-    Ok(())
+    fn push(&mut self, chunk: &[u8]) {
+        self.pending.extend_from_slice(chunk);
+        while let Some(pos) = self.pending.iter().position(|&b| b == b'\n') {
+            let line = self.pending.drain(..=pos).collect::<Vec<_>>();
+            self.print(&line[..pos]);
+        }
+    }
+
+    /// Print what is left after the last newline
+    fn finish(mut self) {
+        let rest = std::mem::take(&mut self.pending);
+        self.print(&rest);
+    }
+
+    fn print(&self, line: &[u8]) {
+        let line = String::from_utf8_lossy(line);
+        let line = line.trim_end_matches('\r');
+        if !line.trim().is_empty() {
+            report!("   [{}] {}", self.srv_name, line);
+        }
+    }
+}
+
+/// Remote log file of a background job.
+fn job_log_path(pid: u32) -> String {
+    format!("/tmp/biusrv-job-{}.log", pid)
+}
+
+/// Wrap a command so it runs detached from the SSH session and prints its PID.
+/// The job shell execs the command, so the PID stays valid and names the log file.
+fn async_command(command: &str) -> String {
+    format!(
+        "setsid sh -c 'exec > /tmp/biusrv-job-$$.log 2>&1 < /dev/null; exec sh -c \"$0\"' {} & echo $!",
+        shell_words::quote(command)
+    )
+}
+
+/// Report whether a background job is still running, followed by its latest output.
+fn job_status_command(pid: u32) -> String {
+    format!(
+        "if [ -d /proc/{} ]; then echo running; else echo finished; fi; tail -n 20 {}",
+        pid,
+        job_log_path(pid)
+    )
 }
 
 /// Start an interactive shell session on a server.
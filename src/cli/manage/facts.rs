@@ -0,0 +1,80 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use clap::Args;
+use indicatif::HumanBytes;
+
+use crate::{
+    cli::executor::{self, Schedule, Task, TaskOutput},
+    report,
+    utils::{self, Facts},
+};
+
+#[derive(Args, Clone, Debug)]
+pub struct FactsAction {}
+
+impl FactsAction {
+    pub fn local_execute(&self) -> Result<bool> {
+        Ok(false)
+    }
+
+    pub async fn remote_execute(&self, schedule: Schedule, tasks: Vec<Task>) -> Result<()> {
+        executor::execute_tasks("facts", schedule, tasks, move |_, task| {
+            handle_facts_execute(task)
+        })
+        .await
+    }
+}
+
+pub async fn handle_facts_execute(task: Arc<Task>) -> Result<TaskOutput> {
+    let facts = match gather(&task).await {
+        Ok(facts) => facts,
+        Err(e) => {
+            report!("❌ {} ({}) - Failed: {}", task.srv_name, task.ssh_client, e);
+            return Err(e);
+        }
+    };
+
+    report!(
+        "📋 {} ({}) - {}",
+        task.srv_name,
+        task.ssh_client,
+        summary(&facts)
+    );
+    Ok(TaskOutput {
+        data: Some(serde_json::to_value(&facts)?),
+        ..Default::default()
+    })
+}
+
+/// Collect the facts of a server.
+pub async fn gather(task: &Task) -> Result<Facts> {
+    let session = task.session().await?;
+
+    log::info!("Gathering facts for server '{}'", task.srv_name);
+    utils::gather_facts(&session).await
+}
+
+/// One line overview, e.g. `ubuntu 22.04 | 6.8.0 | 4 CPUs | 7.75 GiB RAM | / 41% of 48.4 GiB | up 3d 4h`
+fn summary(facts: &Facts) -> String {
+    let mut parts = vec![
+        facts.distro.clone(),
+        facts.kernel.clone(),
+        format!("{} CPUs", facts.cpus),
+        format!("{} RAM", HumanBytes(facts.memory_bytes)),
+    ];
+    for disk in facts.disks.iter() {
+        let percent = (disk.used_bytes * 100)
+            .checked_div(disk.size_bytes)
+            .unwrap_or(0);
+        parts.push(format!(
+            "{} {}% of {}",
+            disk.mountpoint,
+            percent,
+            HumanBytes(disk.size_bytes)
+        ));
+    }
+    let hours = facts.uptime_secs / 3600;
+    parts.push(format!("up {}d {}h", hours / 24, hours % 24));
+    parts.join(" | ")
+}
@@ -4,9 +4,11 @@ use anyhow::Result;
 use clap::Args;
 
 use crate::{
-    cli::executor::{self, Task},
-    firewall,
-    ssh::Client,
+    cli::{
+        executor::{self, Schedule, Task, TaskOutput},
+        reporter::{OutputFormat, Reporter},
+    },
+    firewall, report,
 };
 
 #[derive(Args, Clone, Debug)]
@@ -32,6 +34,27 @@ pub struct FirewallAction {
 }
 
 impl FirewallAction {
+    /// Describe the change for the confirmation prompt, None for read-only actions
+    pub fn disruptive_operation(&self) -> Option<String> {
+        let mut changes = vec![];
+        for (label, ports) in [
+            ("allow", &self.allow_port),
+            ("deny", &self.deny_port),
+            ("delete allow", &self.delete_allow_port),
+            ("delete deny", &self.delete_deny_port),
+        ] {
+            if !ports.is_empty() {
+                changes.push(format!("{} {}", label, ports.join(",")));
+            }
+        }
+
+        if changes.is_empty() {
+            None
+        } else {
+            Some(format!("firewall {}", changes.join(", ")))
+        }
+    }
+
     pub fn local_execute(&self) -> Result<bool> {
         if !self.status
             && self.allow_port.is_empty()
@@ -42,17 +65,21 @@ impl FirewallAction {
             return Err(anyhow::anyhow!("No firewall action specified. Use --status, --allow-port, --deny-port, --delete-allow-port, or --delete-deny-port"));
         }
 
+        for ports in [
+            &self.allow_port,
+            &self.deny_port,
+            &self.delete_allow_port,
+            &self.delete_deny_port,
+        ] {
+            firewall::validate_port_specs(ports)?;
+        }
+
         Ok(false)
     }
 
-    pub async fn remote_execute(
-        &self,
-        thread_num: usize,
-        max_retry: u32,
-        tasks: Vec<Task>,
-    ) -> Result<()> {
+    pub async fn remote_execute(&self, schedule: Schedule, tasks: Vec<Task>) -> Result<()> {
         let action = Arc::new(self.clone());
-        executor::execute_tasks(thread_num, max_retry, tasks, move |_, task| {
+        executor::execute_tasks("firewall", schedule, tasks, move |_, task| {
             let action = Arc::clone(&action);
             handle_firewall_execute(action, task)
         })
@@ -60,74 +87,60 @@ impl FirewallAction {
     }
 }
 
-pub async fn handle_firewall_execute(action: Arc<FirewallAction>, task: Arc<Task>) -> Result<()> {
-    let result = if action.status {
-        show_status(&task.srv_name, &task.ssh_client).await
+pub async fn handle_firewall_execute(
+    action: Arc<FirewallAction>,
+    task: Arc<Task>,
+) -> Result<TaskOutput> {
+    let mut output = None;
+    let mut data = None;
+    let result = if action.status && Reporter::get().format == OutputFormat::Json {
+        list_rules(&task).await.and_then(|rules| {
+            data = Some(serde_json::to_value(rules)?);
+            Ok(())
+        })
+    } else if action.status {
+        show_status(&task).await.map(|status| output = Some(status))
     } else if !action.allow_port.is_empty() {
-        allow_ports(
-            &task.srv_name,
-            &task.ssh_client,
-            &action.allow_port,
-            action.save,
-        )
-        .await
+        allow_ports(&task, &action.allow_port, action.save).await
     } else if !action.deny_port.is_empty() {
-        deny_ports(
-            &task.srv_name,
-            &task.ssh_client,
-            &action.deny_port,
-            action.save,
-        )
-        .await
+        deny_ports(&task, &action.deny_port, action.save).await
     } else if !action.delete_allow_port.is_empty() {
-        delete_allow_ports(
-            &task.srv_name,
-            &task.ssh_client,
-            &action.delete_allow_port,
-            action.save,
-        )
-        .await
+        delete_allow_ports(&task, &action.delete_allow_port, action.save).await
     } else if !action.delete_deny_port.is_empty() {
-        delete_deny_ports(
-            &task.srv_name,
-            &task.ssh_client,
-            &action.delete_deny_port,
-            action.save,
-        )
-        .await
+        delete_deny_ports(&task, &action.delete_deny_port, action.save).await
     } else {
         unreachable!()
     };
 
     if let Err(e) = result {
-        println!("❌ {} ({}) - Failed: {}", task.srv_name, task.ssh_client, e);
+        report!("❌ {} ({}) - Failed: {}", task.srv_name, task.ssh_client, e);
         return Err(e);
     }
 
-    println!("✅ {} ({}) - Success", task.srv_name, task.ssh_client);
-    Ok(())
+    report!("✅ {} ({}) - Success", task.srv_name, task.ssh_client);
+    Ok(TaskOutput {
+        output,
+        data,
+        ..Default::default()
+    })
 }
 
 /// Allow ports on a server.
 pub async fn allow_ports<S: AsRef<str> + std::fmt::Debug>(
-    srv_name: &str,
-    ssh_client: &Client,
+    task: &Task,
     ports: &[S],
     save: bool,
 ) -> Result<()> {
-    let session = match ssh_client.connect().await {
-        Ok(session) => session,
-        Err(e) => {
-            log::error!("Failed to connect to {}({})", srv_name, ssh_client);
-            return Err(e);
-        }
-    };
+    let session = task.session().await?;
 
-    log::info!("Allowing ports {:?} on server '{}'", ports, srv_name);
+    log::info!("Allowing ports {:?} on server '{}'", ports, task.srv_name);
     firewall::allow_ports(&session, ports).await?;
 
     if save {
-        log::info!("Saving firewall rules permanently on server '{}'", srv_name);
+        log::info!(
+            "Saving firewall rules permanently on server '{}'",
+            task.srv_name
+        );
         firewall::save_rules(&session).await?;
     }
 
@@ -136,24 +149,20 @@ pub async fn allow_ports<S: AsRef<str> + std::fmt::Debug>(
 
 /// Deny ports on a server.
 pub async fn deny_ports<S: AsRef<str> + std::fmt::Debug>(
-    srv_name: &str,
-    ssh_client: &Client,
+    task: &Task,
     ports: &[S],
     save: bool,
 ) -> Result<()> {
-    let session = match ssh_client.connect().await {
-        Ok(session) => session,
-        Err(e) => {
-            log::error!("Failed to connect to {}({})", srv_name, ssh_client);
-            return Err(e);
-        }
-    };
+    let session = task.session().await?;
 
-    log::info!("Denying ports {:?} on server '{}'", ports, srv_name);
+    log::info!("Denying ports {:?} on server '{}'", ports, task.srv_name);
     firewall::deny_ports(&session, ports).await?;
 
     if save {
-        log::info!("Saving firewall rules permanently on server '{}'", srv_name);
+        log::info!(
+            "Saving firewall rules permanently on server '{}'",
+            task.srv_name
+        );
         firewall::save_rules(&session).await?;
     }
 
@@ -161,50 +170,52 @@ pub async fn deny_ports<S: AsRef<str> + std::fmt::Debug>(
 }
 
 /// Show firewall status for a server.
-pub async fn show_status(srv_name: &str, ssh_client: &Client) -> Result<()> {
-    let session = match ssh_client.connect().await {
-        Ok(session) => session,
-        Err(e) => {
-            log::error!("Failed to connect to {}({})", srv_name, ssh_client);
-            return Err(e);
-        }
-    };
+pub async fn show_status(task: &Task) -> Result<String> {
+    let session = task.session().await?;
 
     let status = firewall::status(&session).await?;
     log::info!(
         "Checking firewall status for server '{} ({})'",
-        srv_name,
-        ssh_client
+        task.srv_name,
+        task.ssh_client
     );
-    println!("{}", status);
+    report!("{}", status);
 
-    Ok(())
+    Ok(status)
+}
+
+/// List parsed firewall rules for a server.
+pub async fn list_rules(task: &Task) -> Result<Vec<firewall::FirewallRule>> {
+    let session = task.session().await?;
+
+    log::info!(
+        "Listing firewall rules for server '{} ({})'",
+        task.srv_name,
+        task.ssh_client
+    );
+    firewall::rules(&session).await
 }
 
 /// Delete allowed ports on a server.
 pub async fn delete_allow_ports<S: AsRef<str> + std::fmt::Debug>(
-    srv_name: &str,
-    ssh_client: &Client,
+    task: &Task,
     ports: &[S],
     save: bool,
 ) -> Result<()> {
-    let session = match ssh_client.connect().await {
-        Ok(session) => session,
-        Err(e) => {
-            log::error!("Failed to connect to {}({})", srv_name, ssh_client);
-            return Err(e);
-        }
-    };
+    let session = task.session().await?;
 
     log::info!(
         "Deleting allowed ports {:?} on server '{}'",
         ports,
-        srv_name
+        task.srv_name
     );
     firewall::delete_ports(&session, true, ports).await?;
 
     if save {
-        log::info!("Saving firewall rules permanently on server '{}'", srv_name);
+        log::info!(
+            "Saving firewall rules permanently on server '{}'",
+            task.srv_name
+        );
         firewall::save_rules(&session).await?;
     }
 
@@ -213,24 +224,24 @@ pub async fn delete_allow_ports<S: AsRef<str> + std::fmt::Debug>(
 
 /// Delete denied ports on a server.
 pub async fn delete_deny_ports<S: AsRef<str> + std::fmt::Debug>(
-    srv_name: &str,
-    ssh_client: &Client,
+    task: &Task,
     ports: &[S],
     save: bool,
 ) -> Result<()> {
-    let session = match ssh_client.connect().await {
-        Ok(session) => session,
-        Err(e) => {
-            log::error!("Failed to connect to {}({})", srv_name, ssh_client);
-            return Err(e);
-        }
-    };
+    let session = task.session().await?;
 
-    log::info!("Deleting denied ports {:?} on server '{}'", ports, srv_name);
+    log::info!(
+        "Deleting denied ports {:?} on server '{}'",
+        ports,
+        task.srv_name
+    );
     firewall::delete_ports(&session, false, ports).await?;
 
     if save {
-        log::info!("Saving firewall rules permanently on server '{}'", srv_name);
+        log::info!(
+            "Saving firewall rules permanently on server '{}'",
+            task.srv_name
+        );
         firewall::save_rules(&session).await?;
     }
 
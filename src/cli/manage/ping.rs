@@ -0,0 +1,123 @@
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+use std::time::Instant;
+
+use anyhow::{anyhow, Result};
+use clap::Args;
+
+use crate::{
+    cli::executor::{self, Schedule, Task, TaskOutput},
+    report,
+    ssh::AuthFailed,
+};
+
+#[derive(Args, Clone, Debug)]
+pub struct PingAction {}
+
+/// Per-run tally of the ping results
+#[derive(Default)]
+struct PingCounts {
+    reachable: AtomicUsize,
+    auth_failed: AtomicUsize,
+    unreachable: AtomicUsize,
+    // connected, but `echo ok` did not answer
+    failed: AtomicUsize,
+}
+
+impl PingAction {
+    pub fn local_execute(&self) -> Result<bool> {
+        Ok(false)
+    }
+
+    pub async fn remote_execute(&self, schedule: Schedule, tasks: Vec<Task>) -> Result<()> {
+        let total = tasks.len();
+        let counts = Arc::new(PingCounts::default());
+        let tally = Arc::clone(&counts);
+        let result = executor::execute_tasks("ping", schedule, tasks, move |_, task| {
+            let tally = Arc::clone(&tally);
+            handle_ping(tally, task)
+        })
+        .await;
+
+        let reachable = counts.reachable.load(Ordering::SeqCst);
+        let auth_failed = counts.auth_failed.load(Ordering::SeqCst);
+        let unreachable = counts.unreachable.load(Ordering::SeqCst);
+        let failed = counts.failed.load(Ordering::SeqCst);
+        let mut summary = format!(
+            "🏓 Reachable: {} | 🔑 Auth failed: {} | 🔌 Unreachable: {}",
+            reachable, auth_failed, unreachable
+        );
+        if failed > 0 {
+            summary.push_str(&format!(" | ❌ Failed: {}", failed));
+        }
+        // never finished, e.g. timed out or skipped after an abort
+        let skipped = total.saturating_sub(reachable + auth_failed + unreachable + failed);
+        if skipped > 0 {
+            summary.push_str(&format!(" | ⏭️ Skipped: {}", skipped));
+        }
+        report!("{}", summary);
+        result
+    }
+}
+
+/// Connect and run `echo ok`, nothing is changed on the server
+async fn handle_ping(counts: Arc<PingCounts>, task: Arc<Task>) -> Result<TaskOutput> {
+    let started = Instant::now();
+    let session = match task.session().await {
+        Ok(session) => session,
+        Err(e) => {
+            if AuthFailed::is_auth_failed(&e) {
+                counts.auth_failed.fetch_add(1, Ordering::SeqCst);
+                report!(
+                    "🔑 {} ({}) - Auth failed: {}",
+                    task.srv_name,
+                    task.ssh_client,
+                    e
+                );
+            } else {
+                counts.unreachable.fetch_add(1, Ordering::SeqCst);
+                report!(
+                    "🔌 {} ({}) - Unreachable: {}",
+                    task.srv_name,
+                    task.ssh_client,
+                    e
+                );
+            }
+            return Err(e);
+        }
+    };
+
+    let reply = session.query_command("echo ok").await.and_then(|result| {
+        if result.exit_status != 0 || result.output.trim() != "ok" {
+            return Err(anyhow!(
+                "unexpected reply to 'echo ok' (exit code: {}): {}",
+                result.exit_status,
+                result.output.trim()
+            ));
+        }
+        Ok(())
+    });
+    if let Err(e) = reply {
+        counts.failed.fetch_add(1, Ordering::SeqCst);
+        report!("❌ {} ({}) - Failed: {}", task.srv_name, task.ssh_client, e);
+        return Err(e);
+    }
+
+    let elapsed = started.elapsed();
+    counts.reachable.fetch_add(1, Ordering::SeqCst);
+    report!(
+        "✅ {} ({}) - Reachable ({} ms)",
+        task.srv_name,
+        task.ssh_client,
+        elapsed.as_millis()
+    );
+    Ok(TaskOutput {
+        data: Some(serde_json::json!({
+            "reachable": true,
+            "latency_ms": elapsed.as_millis() as u64,
+        })),
+        ..Default::default()
+    })
+}
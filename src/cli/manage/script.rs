@@ -4,7 +4,8 @@ use anyhow::{anyhow, Result};
 use clap::{Args, Subcommand};
 
 use crate::{
-    cli::executor::{self, Task},
+    cli::executor::{self, Schedule, Task, TaskOutput},
+    report,
     script::ScriptConfig,
 };
 
@@ -71,16 +72,11 @@ impl ScriptAction {
     }
 
     /// Execute remote operations
-    pub async fn remote_execute(
-        &self,
-        thread_num: usize,
-        max_retry: u32,
-        tasks: Vec<Task>,
-    ) -> Result<()> {
+    pub async fn remote_execute(&self, schedule: Schedule, tasks: Vec<Task>) -> Result<()> {
         let config = SCRIPT_CONFIG.get().unwrap();
         let action = Arc::new(self.clone());
         // Execute tasks using the standard executor pattern
-        executor::execute_tasks(thread_num, max_retry, tasks, move |_, task| {
+        executor::execute_tasks("script", schedule, tasks, move |_, task| {
             let action = Arc::clone(&action);
             handle_script_execute(action, task, config)
         })
@@ -111,24 +107,24 @@ pub async fn handle_script_execute(
     action: Arc<ScriptAction>,
     task: Arc<Task>,
     config: &ScriptConfig,
-) -> Result<()> {
+) -> Result<TaskOutput> {
     let result = match &action.action {
         ScriptSubAction::Run(run_action) => {
             handle_run_action(&task, config, &run_action.action).await
         }
         ScriptSubAction::List(list_action) => {
             list_actions(&list_action.path)?;
-            return Ok(());
+            return Ok(TaskOutput::default());
         }
     };
 
     if let Err(e) = result {
-        println!("❌ {} ({}) - Failed: {}", task.srv_name, task.ssh_client, e);
+        report!("❌ {} ({}) - Failed: {}", task.srv_name, task.ssh_client, e);
         return Err(e);
     }
 
-    println!("✅ {} ({}) - Success", task.srv_name, task.ssh_client);
-    Ok(())
+    report!("✅ {} ({}) - Success", task.srv_name, task.ssh_client);
+    Ok(TaskOutput::default())
 }
 
 /// Run script actions
@@ -137,21 +133,11 @@ pub async fn handle_run_action(
     config: &ScriptConfig,
     actions: &Vec<String>,
 ) -> Result<()> {
-    let session = match task.ssh_client.connect().await {
-        Ok(session) => session,
-        Err(e) => {
-            log::error!(
-                "Failed to connect to {} - {}",
-                task.srv_name,
-                task.ssh_client
-            );
-            return Err(e);
-        }
-    };
+    let session = task.session().await?;
 
     for action_name in actions.iter() {
         let action = config.script.get(action_name).unwrap();
-        println!(
+        report!(
             "🔍 [{} - {}] Executing action: {} - {}",
             task.srv_name,
             task.ssh_client,
@@ -159,7 +145,7 @@ pub async fn handle_run_action(
             action.desc.as_deref().unwrap_or("No description"),
         );
         for (index, step) in action.step.iter().enumerate() {
-            println!(
+            report!(
                 "🔍 [{} - {}] Executing step {} - {}",
                 task.srv_name,
                 task.ssh_client,
@@ -0,0 +1,162 @@
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use clap::{ArgGroup, Args};
+
+use crate::{
+    cli::{
+        executor::{self, Schedule, Task, TaskOutput},
+        reporter::{OutputFormat, Reporter},
+    },
+    report,
+    ssh::CommandResult,
+    utils::{self, truncate_error_message, ServiceState},
+};
+
+#[derive(Args, Clone, Debug)]
+#[command(group(
+    ArgGroup::new("operation")
+        .required(true)
+        .args(["start", "stop", "restart", "reload", "enable", "disable", "status"])
+))]
+pub struct ServiceAction {
+    /// Start a service
+    #[arg(long, value_name = "NAME")]
+    pub start: Option<String>,
+    /// Stop a service
+    #[arg(long, value_name = "NAME")]
+    pub stop: Option<String>,
+    /// Restart a service
+    #[arg(long, value_name = "NAME")]
+    pub restart: Option<String>,
+    /// Reload a service's configuration
+    #[arg(long, value_name = "NAME")]
+    pub reload: Option<String>,
+    /// Start a service at boot
+    #[arg(long, value_name = "NAME")]
+    pub enable: Option<String>,
+    /// Stop starting a service at boot
+    #[arg(long, value_name = "NAME")]
+    pub disable: Option<String>,
+    /// Show service status
+    #[arg(long, value_name = "NAME")]
+    pub status: Option<String>,
+}
+
+impl ServiceAction {
+    /// The selected operation and service name
+    fn operation(&self) -> (&'static str, &str) {
+        [
+            ("start", &self.start),
+            ("stop", &self.stop),
+            ("restart", &self.restart),
+            ("reload", &self.reload),
+            ("enable", &self.enable),
+            ("disable", &self.disable),
+            ("status", &self.status),
+        ]
+        .into_iter()
+        .find_map(|(operation, name)| name.as_deref().map(|name| (operation, name)))
+        .unwrap()
+    }
+
+    /// Describe the change for the confirmation prompt, None for read-only actions
+    pub fn disruptive_operation(&self) -> Option<String> {
+        match self.operation() {
+            ("status", _) => None,
+            (operation, name) => Some(format!("service {} {}", operation, name)),
+        }
+    }
+
+    pub fn local_execute(&self) -> Result<bool> {
+        let (_, name) = self.operation();
+        if name.is_empty() || name.contains(|c: char| c.is_whitespace() || ";&|$`'\"".contains(c)) {
+            return Err(anyhow!("Invalid service name: '{}'", name));
+        }
+        Ok(false)
+    }
+
+    pub async fn remote_execute(&self, schedule: Schedule, tasks: Vec<Task>) -> Result<()> {
+        let action = Arc::new(self.clone());
+        executor::execute_tasks("service", schedule, tasks, move |_, task| {
+            let action = Arc::clone(&action);
+            handle_service_execute(action, task)
+        })
+        .await
+    }
+}
+
+pub async fn handle_service_execute(
+    action: Arc<ServiceAction>,
+    task: Arc<Task>,
+) -> Result<TaskOutput> {
+    let (operation, name) = action.operation();
+
+    let result = run_operation(&task, operation, name).await;
+    let result = match result {
+        // an inactive service still has a status to show
+        Ok(result) if result.exit_status == 0 || operation == "status" => result,
+        Ok(result) => {
+            let e = anyhow!(
+                "{} {} (exit code: {}) - {}",
+                operation,
+                name,
+                result.exit_status,
+                truncate_error_message(result.output.trim(), 3)
+            );
+            report!("❌ {} ({}) - Failed: {}", task.srv_name, task.ssh_client, e);
+            return Err(e);
+        }
+        Err(e) => {
+            report!("❌ {} ({}) - Failed: {}", task.srv_name, task.ssh_client, e);
+            return Err(e);
+        }
+    };
+
+    let mut data = None;
+    if operation == "status" {
+        report!("{}", result.output.trim_end());
+        if Reporter::get().format == OutputFormat::Json {
+            let (state, enabled) = state_of(&task, name).await?;
+            data = Some(serde_json::json!({ "state": state, "enabled": enabled }));
+        }
+    }
+
+    report!("✅ {} ({}) - Success", task.srv_name, task.ssh_client);
+    Ok(TaskOutput {
+        exit_status: Some(result.exit_status),
+        output: (operation == "status").then_some(result.output),
+        data,
+        ..Default::default()
+    })
+}
+
+/// Normalized state of a service and whether it starts at boot.
+pub async fn state_of(task: &Task, name: &str) -> Result<(ServiceState, Option<bool>)> {
+    let session = task.session().await?;
+    let state = utils::service_state(&session, name).await?;
+    let enabled = utils::service_enabled(&session, name).await?;
+    Ok((state, enabled))
+}
+
+/// Run a service operation, falling back to `service` or the init scripts where systemd is missing.
+pub async fn run_operation(task: &Task, operation: &str, name: &str) -> Result<CommandResult> {
+    let session = task.session().await?;
+
+    log::info!(
+        "Running service {} {} on server '{}'",
+        operation,
+        name,
+        task.srv_name
+    );
+    match operation {
+        "start" => utils::start_service(&session, name).await,
+        "stop" => utils::stop_service(&session, name).await,
+        "restart" => utils::restart_service(&session, name).await,
+        "reload" => utils::reload_service(&session, name).await,
+        "enable" => utils::enable_service(&session, name).await,
+        "disable" => utils::disable_service(&session, name).await,
+        "status" => utils::service_status(&session, name).await,
+        _ => unreachable!(),
+    }
+}
@@ -1,11 +1,15 @@
 use anyhow::{anyhow, Result};
 use clap::Args;
-use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use indicatif::{HumanBytes, MultiProgress, ProgressBar, ProgressStyle};
+use std::io::{IsTerminal, Write};
 use std::sync::Arc;
 
 use crate::{
-    cli::executor::{self, Task},
-    ssh::Client,
+    cli::{
+        executor::{self, Schedule, Task, TaskOutput},
+        reporter::{OutputFormat, Reporter},
+    },
+    report,
     transfer::{TransferConfig, TransferProgress},
 };
 
@@ -17,21 +21,47 @@ pub struct TransferAction {
     /// Download remote file to local
     #[arg(long)]
     pub download: bool,
-    /// Remote file path (required for upload/download)
+    /// Print a remote file to stdout without saving it
+    #[arg(long, conflicts_with_all = ["upload", "download", "local"])]
+    pub cat: bool,
+    /// List path, size, mode and sha256 of every file under the --remote directory
+    #[arg(long, conflicts_with_all = ["upload", "download", "cat", "local"])]
+    pub manifest: bool,
+    /// Remote file path (required for upload/download/cat/manifest)
     #[arg(long)]
     pub remote: Option<String>,
-    /// Local file path (required for upload/download)
-    #[arg(long)]
-    pub local: Option<String>,
+    /// Local file path (required for upload/download), repeat or comma-separate
+    /// to upload several sources into the --remote directory
+    #[arg(long, value_delimiter = ',')]
+    pub local: Vec<String>,
+    /// Upload from stdin instead of --local
+    #[arg(long, requires = "upload", conflicts_with = "local")]
+    pub stdin: bool,
+    /// Download to stdout instead of --local
+    #[arg(long, requires = "download", conflicts_with = "local")]
+    pub stdout: bool,
     /// Force overwrite existing files
     #[arg(long)]
     pub force: bool,
     /// Enable resume for interrupted transfers
     #[arg(long)]
     pub resume: bool,
+    /// Refuse to overwrite remote files newer than the local source (even with --force)
+    #[arg(long)]
+    pub no_clobber_newer: bool,
     /// Hide progress display
     #[arg(long)]
     pub hide_progress: bool,
+    /// Print progress as plain status lines instead of a progress bar,
+    /// the default when stdout is not a terminal
+    #[arg(long, alias = "no-progress-bar")]
+    pub plain: bool,
+    /// SFTP chunk size in bytes, K and M suffixes allowed (default: 64K)
+    #[arg(long, value_name = "SIZE", value_parser = parse_chunk_size)]
+    pub chunk_size: Option<usize>,
+    /// Seconds between progress updates (default: 1)
+    #[arg(long, value_name = "SECS")]
+    pub progress_interval: Option<f64>,
 }
 
 impl TransferAction {
@@ -40,38 +70,58 @@ impl TransferAction {
             if self.remote.is_none() {
                 return Err(anyhow!("--remote is required for upload"));
             }
-            if self.local.is_none() {
-                return Err(anyhow!("--local is required for upload"));
+            if self.local.is_empty() && !self.stdin {
+                return Err(anyhow!("--local or --stdin is required for upload"));
             }
         } else if self.download {
             if self.remote.is_none() {
                 return Err(anyhow!("--remote is required for download"));
             }
-            if self.local.is_none() {
-                return Err(anyhow!("--local is required for download"));
+            if self.local.is_empty() && !self.stdout {
+                return Err(anyhow!("--local or --stdout is required for download"));
+            }
+            if self.local.len() > 1 {
+                return Err(anyhow!("--download takes a single --local path"));
+            }
+        } else if self.cat {
+            if self.remote.is_none() {
+                return Err(anyhow!("--remote is required for cat"));
+            }
+        } else if self.manifest {
+            if self.remote.is_none() {
+                return Err(anyhow!("--remote is required for manifest"));
             }
         } else {
             return Err(anyhow!(
-                "No transfer action specified. Use --upload or --download"
+                "No transfer action specified. Use --upload, --download, --cat or --manifest"
             ));
         }
 
+        if let Some(interval) = self.progress_interval {
+            if !interval.is_finite() || interval <= 0.0 {
+                return Err(anyhow!("--progress-interval must be greater than 0"));
+            }
+        }
+
         Ok(false)
     }
 
-    pub async fn remote_execute(
-        &self,
-        thread_num: usize,
-        max_retry: u32,
-        tasks: Vec<Task>,
-    ) -> Result<()> {
+    pub async fn remote_execute(&self, schedule: Schedule, tasks: Vec<Task>) -> Result<()> {
+        if (self.stdin || self.stdout) && tasks.len() > 1 {
+            return Err(anyhow!("--stdin and --stdout need a single target server"));
+        }
+
         let action = Arc::new(self.clone());
         let add_name = tasks.len() > 1;
         let progress = Arc::new(MultiProgress::new());
-        executor::execute_tasks(thread_num, max_retry, tasks, move |_, task| {
+        // progress bars turn into escape sequence noise in logs and CI
+        let plain = self.plain || !std::io::stdout().is_terminal();
+        executor::execute_tasks("transfer", schedule, tasks, move |_, task| {
             let action = Arc::clone(&action);
-            let pb = if action.hide_progress {
-                None
+            let display = if action.hide_progress || action.cat || action.manifest {
+                ProgressDisplay::Hidden
+            } else if plain {
+                ProgressDisplay::Plain
             } else {
                 let pb = Arc::new(progress.add(ProgressBar::new_spinner()));
 
@@ -82,34 +132,54 @@ impl TransferAction {
                     .progress_chars("#>-");
                 pb.set_style(style);
 
-                Some(pb)
+                ProgressDisplay::Bar(pb)
             };
-            handle_transfer_execute(pb, action, task, add_name, max_retry)
+            handle_transfer_execute(display, action, task, add_name, schedule.max_retry)
         })
         .await
     }
 }
 
 pub async fn handle_transfer_execute(
-    pb: Option<Arc<ProgressBar>>,
+    display: ProgressDisplay,
     action: Arc<TransferAction>,
     task: Arc<Task>,
     add_name: bool,
     max_retry: u32,
-) -> Result<()> {
+) -> Result<TaskOutput> {
+    let defaults = TransferConfig::default();
     let transfer_config = TransferConfig {
         max_retry,
         force: action.force,
         resume: action.resume,
-        ..Default::default()
+        no_clobber_newer: action.no_clobber_newer,
+        chunk_size: action.chunk_size.unwrap_or(defaults.chunk_size),
+        progress_interval: action
+            .progress_interval
+            .unwrap_or(defaults.progress_interval),
     };
 
-    let result = if action.upload {
+    if action.cat {
+        return cat(&task, action.remote.as_ref().unwrap(), add_name).await;
+    }
+    if action.manifest {
+        return manifest(&task, action.remote.as_ref().unwrap()).await;
+    }
+
+    let result = if action.upload && action.local.len() > 1 {
+        upload_many(
+            display,
+            &task,
+            &action.local,
+            action.remote.as_ref().unwrap(),
+            transfer_config,
+        )
+        .await
+    } else if action.upload {
         upload(
-            pb,
-            &task.srv_name,
-            &task.ssh_client,
-            action.local.as_ref().unwrap(),
+            display,
+            &task,
+            action.local.first().map(String::as_str),
             action.remote.as_ref().unwrap(),
             transfer_config,
         )
@@ -117,17 +187,19 @@ pub async fn handle_transfer_execute(
     } else if action.download {
         // For download, append server name to avoid file conflicts
         let local_path = if add_name {
-            add_server_name(action.local.as_ref().unwrap(), &task.srv_name)
+            action
+                .local
+                .first()
+                .map(|local| add_server_name(local, &task.srv_name))
         } else {
-            action.local.as_ref().unwrap().clone()
+            action.local.first().cloned()
         };
 
         download(
-            pb,
-            &task.srv_name,
-            &task.ssh_client,
+            display,
+            &task,
             action.remote.as_ref().unwrap(),
-            &local_path,
+            local_path.as_deref(),
             transfer_config,
         )
         .await
@@ -135,13 +207,40 @@ pub async fn handle_transfer_execute(
         unreachable!()
     };
 
-    if let Err(e) = result {
-        println!("❌ {} ({}) - Failed: {}", task.srv_name, task.ssh_client, e);
-        return Err(e);
+    let bytes = match result {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            report!("❌ {} ({}) - Failed: {}", task.srv_name, task.ssh_client, e);
+            return Err(e);
+        }
+    };
+
+    report!("✅ {} ({}) - Success", task.srv_name, task.ssh_client);
+    Ok(TaskOutput {
+        bytes: Some(bytes),
+        ..Default::default()
+    })
+}
+
+/// Parse a chunk size such as "65536", "256K" or "1M"
+fn parse_chunk_size(value: &str) -> Result<usize> {
+    let value = value.trim();
+    let (number, multiplier) = match value.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&value[..i], 1024),
+        Some((i, 'm' | 'M')) => (&value[..i], 1024 * 1024),
+        _ => (value, 1),
+    };
+
+    let size = number
+        .parse::<usize>()
+        .map_err(|_| anyhow!("Invalid chunk size: {}", value))?
+        .checked_mul(multiplier)
+        .ok_or_else(|| anyhow!("Chunk size too large: {}", value))?;
+    if size == 0 {
+        return Err(anyhow!("Chunk size must be greater than 0"));
     }
 
-    println!("✅ {} ({}) - Success", task.srv_name, task.ssh_client);
-    Ok(())
+    Ok(size)
 }
 
 /// Add server name to file path to avoid conflicts when downloading from multiple servers
@@ -153,100 +252,281 @@ fn add_server_name(local_path: &str, server_name: &str) -> String {
     }
 }
 
-/// Upload to server.
-pub async fn upload(
-    pb: Option<Arc<ProgressBar>>,
-    srv_name: &str,
-    ssh_client: &Client,
-    local_path: &str,
-    remote_path: &str,
-    config: TransferConfig,
-) -> Result<()> {
-    let session = match ssh_client.connect().await {
-        Ok(session) => session,
+/// Print a remote file to stdout, with a header per server when there are several.
+pub async fn cat(task: &Task, remote_path: &str, add_name: bool) -> Result<TaskOutput> {
+    let session = task.session().await?;
+
+    log::info!(
+        "Reading '{}' on server '{}({})'",
+        remote_path,
+        task.srv_name,
+        task.ssh_client
+    );
+
+    let result = match session.open_sftp_session(None).await {
+        Ok(transfer_session) => transfer_session.read_remote_bytes(remote_path).await,
+        Err(e) => Err(e),
+    };
+    let contents = match result {
+        Ok(contents) => contents,
         Err(e) => {
-            log::error!("Failed to connect to {}({})", srv_name, ssh_client);
+            report!("❌ {} ({}) - Failed: {}", task.srv_name, task.ssh_client, e);
             return Err(e);
         }
     };
 
+    if Reporter::get().format == OutputFormat::Text {
+        let mut stdout = std::io::stdout().lock();
+        if add_name {
+            writeln!(
+                stdout,
+                "==> {} ({}):{} <==",
+                task.srv_name, task.ssh_client, remote_path
+            )?;
+        }
+        stdout.write_all(&contents)?;
+        stdout.flush()?;
+    }
+
+    Ok(TaskOutput {
+        bytes: Some(contents.len() as u64),
+        output: Some(String::from_utf8_lossy(&contents).into_owned()),
+        ..Default::default()
+    })
+}
+
+/// List the files under a remote directory with their sha256.
+pub async fn manifest(task: &Task, remote_dir: &str) -> Result<TaskOutput> {
+    let session = task.session().await?;
+
+    log::info!(
+        "Building manifest of '{}' on server '{}({})'",
+        remote_dir,
+        task.srv_name,
+        task.ssh_client
+    );
+
+    let result = match session.open_sftp_session(None).await {
+        Ok(transfer_session) => transfer_session.manifest(&session, remote_dir).await,
+        Err(e) => Err(e),
+    };
+    let entries = match result {
+        Ok(entries) => entries,
+        Err(e) => {
+            report!("❌ {} ({}) - Failed: {}", task.srv_name, task.ssh_client, e);
+            return Err(e);
+        }
+    };
+
+    let lines = entries
+        .iter()
+        .map(|entry| {
+            format!(
+                "   {}  {}  {:>10}  {}",
+                entry.sha256.as_deref().unwrap_or("-"),
+                entry.mode,
+                entry.size,
+                entry.path
+            )
+        })
+        .collect::<Vec<_>>();
+    report!(
+        "📋 {} ({}) - {} files under {}\n{}",
+        task.srv_name,
+        task.ssh_client,
+        entries.len(),
+        remote_dir,
+        lines.join("\n")
+    );
+
+    Ok(TaskOutput {
+        data: Some(serde_json::to_value(entries)?),
+        ..Default::default()
+    })
+}
+
+/// Upload to server, from stdin when no local path is given.
+pub async fn upload(
+    display: ProgressDisplay,
+    task: &Task,
+    local_path: Option<&str>,
+    remote_path: &str,
+    config: TransferConfig,
+) -> Result<u64> {
+    let session = task.session().await?;
+
+    if session.is_dry_run() {
+        report!(
+            "🔍 [dry-run] {} upload {} -> {}",
+            task.ssh_client,
+            local_path.unwrap_or("stdin"),
+            remote_path
+        );
+        return Ok(0);
+    }
+
     let transfer_session = session.open_sftp_session(Some(config)).await?;
 
     log::info!(
         "Uploading '{}' to '{}' on server '{}({})'",
-        local_path,
+        local_path.unwrap_or("stdin"),
         remote_path,
-        srv_name,
-        ssh_client,
+        task.srv_name,
+        task.ssh_client,
     );
 
-    let bytes_transferred = if let Some(ref pb) = pb {
-        transfer_session
-            .upload_with_callback(local_path, remote_path, |progress| {
-                progress_callback(pb.clone(), srv_name, Operation::Upload, progress)
-            })
-            .await?
-    } else {
-        transfer_session.upload(local_path, remote_path).await?
+    let callback =
+        |progress: &TransferProgress| display.update(&task.srv_name, Operation::Upload, progress);
+    let bytes_transferred = match local_path {
+        Some(local_path) => {
+            transfer_session
+                .upload_with_callback(local_path, remote_path, callback)
+                .await?
+        }
+        None => {
+            transfer_session
+                .upload_reader_with_callback(&mut tokio::io::stdin(), remote_path, callback)
+                .await?
+        }
     };
 
-    if let Some(ref pb) = pb {
-        pb.finish_and_clear();
+    display.finish();
+    report!(
+        "📤 Uploaded Success {} Bytes on server '{}({})'",
+        bytes_transferred,
+        task.srv_name,
+        task.ssh_client
+    );
+
+    Ok(bytes_transferred)
+}
+
+/// Upload several sources into one remote directory, like `scp a b host:dir/`.
+pub async fn upload_many(
+    display: ProgressDisplay,
+    task: &Task,
+    local_paths: &[String],
+    remote_dir: &str,
+    config: TransferConfig,
+) -> Result<u64> {
+    let session = task.session().await?;
+
+    if session.is_dry_run() {
+        for local_path in local_paths {
+            report!(
+                "🔍 [dry-run] {} upload {} -> {}",
+                task.ssh_client,
+                local_path,
+                remote_dir
+            );
+        }
+        return Ok(0);
+    }
+
+    let transfer_session = session.open_sftp_session(Some(config)).await?;
+
+    let is_dir = transfer_session
+        .inner_session()
+        .metadata(remote_dir)
+        .await
+        .map(|meta| meta.is_dir())
+        .unwrap_or(false);
+    if !is_dir {
+        return Err(anyhow!(
+            "Remote path '{}' must be an existing directory for several sources",
+            remote_dir
+        ));
+    }
+
+    let callback =
+        |progress: &TransferProgress| display.update(&task.srv_name, Operation::Upload, progress);
+
+    let mut bytes_transferred = 0;
+    for local_path in local_paths {
+        let name = std::path::Path::new(local_path.trim_end_matches(['/', '\\']))
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| anyhow!("Invalid local path: {}", local_path))?;
+        let remote_path = format!("{}/{}", remote_dir.trim_end_matches('/'), name);
+
+        log::info!(
+            "Uploading '{}' to '{}' on server '{}({})'",
+            local_path,
+            remote_path,
+            task.srv_name,
+            task.ssh_client,
+        );
+
+        bytes_transferred += transfer_session
+            .upload_with_callback(local_path, &remote_path, &callback)
+            .await?;
     }
-    println!(
+
+    display.finish();
+    report!(
         "📤 Uploaded Success {} Bytes on server '{}({})'",
-        bytes_transferred, srv_name, ssh_client
+        bytes_transferred,
+        task.srv_name,
+        task.ssh_client
     );
 
-    Ok(())
+    Ok(bytes_transferred)
 }
 
-/// Download file from server.
+/// Download file from server, to stdout when no local path is given.
 pub async fn download(
-    pb: Option<Arc<ProgressBar>>,
-    srv_name: &str,
-    ssh_client: &Client,
+    display: ProgressDisplay,
+    task: &Task,
     remote_path: &str,
-    local_path: &str,
+    local_path: Option<&str>,
     config: TransferConfig,
-) -> Result<()> {
-    let session = match ssh_client.connect().await {
-        Ok(session) => session,
-        Err(e) => {
-            log::error!("Failed to connect to {}({})", srv_name, ssh_client);
-            return Err(e);
-        }
-    };
+) -> Result<u64> {
+    let session = task.session().await?;
+
+    if session.is_dry_run() {
+        report!(
+            "🔍 [dry-run] {} download {} -> {}",
+            task.ssh_client,
+            remote_path,
+            local_path.unwrap_or("stdout")
+        );
+        return Ok(0);
+    }
 
     let transfer_session = session.open_sftp_session(Some(config)).await?;
 
     log::info!(
         "Downloading '{}' from '{}' on server '{}({})'",
-        local_path,
+        local_path.unwrap_or("stdout"),
         remote_path,
-        srv_name,
-        ssh_client
+        task.srv_name,
+        task.ssh_client
     );
 
-    let bytes_transferred = if let Some(ref pb) = pb {
-        transfer_session
-            .download_with_callback(remote_path, local_path, |progress| {
-                progress_callback(pb.clone(), srv_name, Operation::Download, progress)
-            })
-            .await?
-    } else {
-        transfer_session.download(remote_path, local_path).await?
+    let callback =
+        |progress: &TransferProgress| display.update(&task.srv_name, Operation::Download, progress);
+    let bytes_transferred = match local_path {
+        Some(local_path) => {
+            transfer_session
+                .download_with_callback(remote_path, local_path, callback)
+                .await?
+        }
+        None => {
+            transfer_session
+                .download_to_writer_with_callback(remote_path, &mut tokio::io::stdout(), callback)
+                .await?
+        }
     };
 
-    if let Some(ref pb) = pb {
-        pb.finish_and_clear();
-    }
-    println!(
+    display.finish();
+    report!(
         "📥 Downloaded {} Bytes on server '{}({})'",
-        bytes_transferred, srv_name, ssh_client
+        bytes_transferred,
+        task.srv_name,
+        task.ssh_client
     );
 
-    Ok(())
+    Ok(bytes_transferred)
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -255,6 +535,34 @@ pub enum Operation {
     Download,
 }
 
+/// How transfer progress is shown
+#[derive(Clone)]
+pub enum ProgressDisplay {
+    Hidden,
+    /// Interactive progress bar
+    Bar(Arc<ProgressBar>),
+    /// Status lines at every progress interval, for logs and CI
+    Plain,
+}
+
+impl ProgressDisplay {
+    fn update(&self, srv_name: &str, operation: Operation, transfer_progress: &TransferProgress) {
+        match self {
+            ProgressDisplay::Hidden => {}
+            ProgressDisplay::Bar(pb) => {
+                progress_callback(pb.clone(), srv_name, operation, transfer_progress)
+            }
+            ProgressDisplay::Plain => plain_progress(srv_name, operation, transfer_progress),
+        }
+    }
+
+    fn finish(&self) {
+        if let ProgressDisplay::Bar(pb) = self {
+            pb.finish_and_clear();
+        }
+    }
+}
+
 /// Progress callback for transfer operations with server name
 fn progress_callback(
     pb: Arc<ProgressBar>,
@@ -278,6 +586,35 @@ fn progress_callback(
     pb.set_message(format!("📥 [{}] {}", srv_name, display_name));
 }
 
+/// Print progress as a plain line, e.g. "uploaded 40% 12.00 MiB/30.00 MiB"
+fn plain_progress(srv_name: &str, operation: Operation, transfer_progress: &TransferProgress) {
+    let (verb, path) = match operation {
+        Operation::Upload => ("uploaded", &transfer_progress.local_path),
+        Operation::Download => ("downloaded", &transfer_progress.remote_path),
+    };
+    let done = HumanBytes(transfer_progress.done_bytes);
+
+    match (transfer_progress.done_bytes * 100).checked_div(transfer_progress.total_bytes) {
+        Some(percent) => report!(
+            "   [{}] {} {} {}% {}/{} ({}/s)",
+            srv_name,
+            get_display_filename(path),
+            verb,
+            percent,
+            done,
+            HumanBytes(transfer_progress.total_bytes),
+            HumanBytes(transfer_progress.speed_bytes)
+        ),
+        None => report!(
+            "   [{}] {} {} {}",
+            srv_name,
+            get_display_filename(path),
+            verb,
+            done
+        ),
+    }
+}
+
 /// Get display filename from path, truncating if too long
 fn get_display_filename(path: &str) -> String {
     use std::path::Path;
@@ -0,0 +1,245 @@
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use clap::Args;
+use tokio::task::JoinSet;
+
+use crate::{
+    cli::executor::{self, Schedule, Task, TaskOutput},
+    report,
+    ssh::socks,
+};
+
+#[derive(Args, Clone, Debug)]
+pub struct TunnelAction {
+    /// Forward a local port to a host reachable from the server,
+    /// `[bind_address:]port:host:hostport` (repeatable, e.g. `-L 8080:localhost:80`)
+    #[arg(short = 'L', long = "local", value_name = "SPEC")]
+    pub local: Vec<String>,
+    /// Run a SOCKS5 proxy that connects through the server, `[bind_address:]port`
+    /// (repeatable, e.g. `-D 1080`)
+    #[arg(short = 'D', long = "dynamic", value_name = "SPEC")]
+    pub dynamic: Vec<String>,
+}
+
+/// A parsed `-L` specification
+#[derive(Clone, Debug)]
+pub struct LocalForward {
+    pub bind: String,
+    pub host: String,
+    pub port: u16,
+}
+
+impl LocalForward {
+    pub fn parse(spec: &str) -> Result<Self> {
+        let parts = split_spec(spec);
+        let (bind_address, bind_port, host, port) = match parts[..] {
+            [bind_port, host, port] => ("127.0.0.1", bind_port, host, port),
+            [bind_address, bind_port, host, port] => (bind_address, bind_port, host, port),
+            _ => {
+                return Err(anyhow!(
+                    "Invalid forward '{}', expected [bind_address:]port:host:hostport",
+                    spec
+                ))
+            }
+        };
+
+        let bind_port = bind_port
+            .parse::<u16>()
+            .map_err(|_| anyhow!("Invalid local port in '{}'", spec))?;
+        let port = port
+            .parse::<u16>()
+            .map_err(|_| anyhow!("Invalid remote port in '{}'", spec))?;
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            return Err(anyhow!("Missing remote host in '{}'", spec));
+        }
+
+        Ok(Self {
+            bind: format!("{}:{}", bind_address, bind_port),
+            host: host.to_string(),
+            port,
+        })
+    }
+}
+
+/// Local address of a `-D` specification
+pub fn parse_dynamic(spec: &str) -> Result<String> {
+    let (bind_address, port) = match split_spec(spec)[..] {
+        [port] => ("127.0.0.1", port),
+        [bind_address, port] => (bind_address, port),
+        _ => ("", ""),
+    };
+    let port = port.parse::<u16>().map_err(|_| {
+        anyhow!(
+            "Invalid SOCKS port in '{}', expected [bind_address:]port",
+            spec
+        )
+    })?;
+    Ok(format!("{}:{}", bind_address, port))
+}
+
+// split at the colons outside of brackets, so `[::1]:1080` is one address and a port
+fn split_spec(spec: &str) -> Vec<&str> {
+    let mut parts = vec![];
+    let mut start = 0;
+    let mut bracketed = false;
+    for (i, c) in spec.char_indices() {
+        match c {
+            '[' => bracketed = true,
+            ']' => bracketed = false,
+            ':' if !bracketed => {
+                parts.push(&spec[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(&spec[start..]);
+    parts
+}
+
+impl TunnelAction {
+    fn dynamic_forwards(&self) -> Result<Vec<String>> {
+        self.dynamic
+            .iter()
+            .map(|spec| parse_dynamic(spec))
+            .collect()
+    }
+
+    fn local_forwards(&self) -> Result<Vec<LocalForward>> {
+        self.local
+            .iter()
+            .map(|spec| LocalForward::parse(spec))
+            .collect()
+    }
+
+    pub fn local_execute(&self) -> Result<bool> {
+        if self.local.is_empty() && self.dynamic.is_empty() {
+            return Err(anyhow!("No tunnel specified. Use -L or -D"));
+        }
+        self.local_forwards()?;
+        self.dynamic_forwards()?;
+        Ok(false)
+    }
+
+    pub async fn remote_execute(&self, schedule: Schedule, tasks: Vec<Task>) -> Result<()> {
+        if tasks.len() > 1 {
+            return Err(anyhow!("tunnel needs a single target server"));
+        }
+
+        let forwards = Arc::new(self.local_forwards()?);
+        let proxies = Arc::new(self.dynamic_forwards()?);
+        executor::execute_tasks("tunnel", schedule, tasks, move |_, task| {
+            let forwards = Arc::clone(&forwards);
+            let proxies = Arc::clone(&proxies);
+            handle_tunnel(forwards, proxies, task)
+        })
+        .await
+    }
+}
+
+/// Keep the tunnels open until Ctrl-C or until one of them fails
+pub async fn handle_tunnel(
+    forwards: Arc<Vec<LocalForward>>,
+    proxies: Arc<Vec<String>>,
+    task: Arc<Task>,
+) -> Result<TaskOutput> {
+    let session = task.session().await?;
+
+    let mut tunnels = JoinSet::new();
+    for forward in forwards.iter().cloned() {
+        report!(
+            "🔀 {} ({}) - Forwarding {} → {}:{}",
+            task.srv_name,
+            task.ssh_client,
+            forward.bind,
+            forward.host,
+            forward.port
+        );
+        let session = Arc::clone(&session);
+        tunnels.spawn(async move {
+            session
+                .forward_local(&forward.bind, &forward.host, forward.port)
+                .await
+        });
+    }
+    for bind in proxies.iter() {
+        report!(
+            "🧦 {} ({}) - SOCKS5 proxy on {}",
+            task.srv_name,
+            task.ssh_client,
+            bind
+        );
+        let session = Arc::clone(&session);
+        let bind = bind.clone();
+        tunnels.spawn(async move { socks::serve(session, &bind).await });
+    }
+    report!("   Press Ctrl-C to close the tunnels");
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        Some(result) = tunnels.join_next() => {
+            let e = match result {
+                Ok(Err(e)) => e,
+                Ok(Ok(())) => anyhow!("tunnel closed"),
+                Err(e) => anyhow!("tunnel task failed: {}", e),
+            };
+            report!("❌ {} ({}) - Failed: {:#}", task.srv_name, task.ssh_client, e);
+            return Err(e);
+        }
+    }
+
+    report!(
+        "✅ {} ({}) - Tunnels closed",
+        task.srv_name,
+        task.ssh_client
+    );
+    Ok(TaskOutput::default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn local_forward_defaults_to_loopback() {
+        let forward = LocalForward::parse("8080:localhost:80").unwrap();
+        assert_eq!(forward.bind, "127.0.0.1:8080");
+        assert_eq!(forward.host, "localhost");
+        assert_eq!(forward.port, 80);
+
+        let forward = LocalForward::parse("0.0.0.0:8080:10.0.0.5:5432").unwrap();
+        assert_eq!(forward.bind, "0.0.0.0:8080");
+        assert_eq!(forward.host, "10.0.0.5");
+    }
+
+    #[test]
+    fn local_forward_accepts_bracketed_ipv6() {
+        let forward = LocalForward::parse("[::1]:8080:[fd00::5]:80").unwrap();
+        assert_eq!(forward.bind, "[::1]:8080");
+        assert_eq!(forward.host, "fd00::5");
+        assert_eq!(forward.port, 80);
+    }
+
+    #[test]
+    fn local_forward_rejects_bad_specs() {
+        for spec in [
+            "8080:localhost",
+            "x:localhost:80",
+            "8080:localhost:99999",
+            "8080::80",
+        ] {
+            assert!(LocalForward::parse(spec).is_err(), "{}", spec);
+        }
+    }
+
+    #[test]
+    fn dynamic_takes_an_optional_address() {
+        assert_eq!(parse_dynamic("1080").unwrap(), "127.0.0.1:1080");
+        assert_eq!(parse_dynamic("0.0.0.0:1080").unwrap(), "0.0.0.0:1080");
+        assert_eq!(parse_dynamic("[::1]:1080").unwrap(), "[::1]:1080");
+        assert!(parse_dynamic("::1:1080").is_err());
+        assert!(parse_dynamic("localhost").is_err());
+    }
+}
@@ -14,6 +14,8 @@ pub struct MultiShell {
     shells: HashMap<String, mpsc::Sender<Vec<u8>>>,
     /// save outputs from each shell
     outputs: Arc<Mutex<HashMap<String, Vec<String>>>>,
+    /// server receiving input set by `/only`, None broadcasts to all
+    focus: Option<String>,
 }
 
 impl MultiShell {
@@ -21,6 +23,7 @@ impl MultiShell {
         Self {
             shells: HashMap::new(),
             outputs: Arc::new(Mutex::new(HashMap::new())),
+            focus: None,
         }
     }
 
@@ -40,12 +43,50 @@ impl MultiShell {
                     if input.starts_with("/history") {
                         let srv_name = input.split_whitespace().nth(1).unwrap_or("--all");
                         self.show_outputs(srv_name).await?;
+                    } else if let Some(rest) = input.strip_prefix("/to ") {
+                        // send a single command to one server
+                        match rest.trim().split_once(char::is_whitespace) {
+                            Some((srv_name, command)) => {
+                                let command = format!("{}\n", command.trim());
+                                self.send_input(srv_name, command.as_bytes()).await;
+                            }
+                            None => println!("Usage: /to <server> <command>"),
+                        }
+                    } else if let Some(srv_name) = input.strip_prefix("/only ") {
+                        let srv_name = srv_name.trim();
+                        if self.shells.contains_key(srv_name) {
+                            println!(
+                                "🎯 Input now goes to '{}' only, use /all to broadcast",
+                                srv_name
+                            );
+                            self.focus = Some(srv_name.to_string());
+                        } else {
+                            println!("❌ Server '{}' not found", srv_name);
+                        }
+                    } else if input == "/all" {
+                        println!("📢 Input now goes to all servers");
+                        self.focus = None;
                     } else if !input.is_empty() {
                         // send command + newline
                         let command = format!("{}\n", input);
-                        self.distribute_input(command.as_bytes()).await?;
-                        if input == "exit" {
-                            break;
+                        match self.focus.clone() {
+                            Some(srv_name) => {
+                                self.send_input(&srv_name, command.as_bytes()).await;
+                                if input == "exit" {
+                                    // the focused shell is gone, return to broadcast
+                                    self.shells.remove(&srv_name);
+                                    self.focus = None;
+                                    if self.shells.is_empty() {
+                                        break;
+                                    }
+                                }
+                            }
+                            None => {
+                                self.distribute_input(command.as_bytes()).await?;
+                                if input == "exit" {
+                                    break;
+                                }
+                            }
                         }
                     }
                 }
@@ -124,6 +165,16 @@ impl MultiShell {
         Ok(())
     }
 
+    /// Send input to a single server's shell
+    pub async fn send_input(&self, srv_name: &str, input: &[u8]) {
+        match self.shells.get(srv_name) {
+            Some(tx) => {
+                let _ = tx.send(input.to_vec()).await;
+            }
+            None => println!("❌ Server '{}' not found", srv_name),
+        }
+    }
+
     pub async fn show_outputs(&self, srv_name: &str) -> Result<()> {
         let outputs = self.outputs.lock().await;
 
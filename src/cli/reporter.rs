@@ -0,0 +1,120 @@
+use std::{
+    fmt,
+    io::Write,
+    sync::OnceLock,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use clap::ValueEnum;
+
+use crate::cli::executor::{CommandFailed, FailureKind, Task, TaskOutput};
+
+static REPORTER: OnceLock<Reporter> = OnceLock::new();
+
+/// Output format for command results
+#[derive(ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Human readable status lines
+    #[default]
+    Text,
+    /// One JSON object per task on stdout, status lines are suppressed
+    Json,
+}
+
+/// Central writer for per-server status lines
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Reporter {
+    /// Flush every line immediately and prefix it with a UTC timestamp
+    pub stream: bool,
+    pub format: OutputFormat,
+    /// Write status lines to stderr, keeping stdout free for file contents
+    pub stderr: bool,
+}
+
+impl Reporter {
+    /// Install the global reporter, later calls are ignored
+    pub fn init(reporter: Reporter) {
+        let _ = REPORTER.set(reporter);
+    }
+
+    /// Get the global reporter, or the default one if not initialized
+    pub fn get() -> Reporter {
+        REPORTER.get().copied().unwrap_or_default()
+    }
+
+    /// Write a single line to stdout, suppressed in JSON mode
+    pub fn line(&self, args: fmt::Arguments) {
+        if self.format == OutputFormat::Json {
+            return;
+        }
+
+        let mut out: Box<dyn Write> = if self.stderr {
+            Box::new(std::io::stderr().lock())
+        } else {
+            Box::new(std::io::stdout().lock())
+        };
+
+        let _ = if self.stream {
+            writeln!(out, "[{}] {}", timestamp(), args)
+        } else {
+            writeln!(out, "{}", args)
+        };
+
+        if self.stream {
+            let _ = out.flush();
+        }
+    }
+
+    /// Write the final result of a task as a JSON line, only in JSON mode
+    pub fn task_result(&self, action: &str, task: &Task, result: &Result<TaskOutput>) {
+        if self.format != OutputFormat::Json {
+            return;
+        }
+
+        let record = match result {
+            Ok(output) => serde_json::json!({
+                "server": task.srv_name,
+                "host": task.ssh_client.to_string(),
+                "action": action,
+                "success": true,
+                "exit_status": output.exit_status,
+                "bytes": output.bytes,
+                "output": output.output,
+                "data": output.data,
+            }),
+            Err(e) => serde_json::json!({
+                "server": task.srv_name,
+                "host": task.ssh_client.to_string(),
+                "action": action,
+                "success": false,
+                "exit_status": CommandFailed::exit_status_of(e),
+                "error_kind": FailureKind::of(e).as_str(),
+                "error": format!("{:#}", e),
+            }),
+        };
+
+        let mut stdout = std::io::stdout().lock();
+        let _ = writeln!(stdout, "{}", record);
+        let _ = stdout.flush();
+    }
+}
+
+/// Current UTC time of day as HH:MM:SS
+fn timestamp() -> String {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+        % 86400;
+
+    format!("{:02}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+/// Print a status line through the global reporter
+#[macro_export]
+macro_rules! report {
+    ($($arg:tt)*) => {
+        $crate::cli::reporter::Reporter::get().line(format_args!($($arg)*))
+    };
+}
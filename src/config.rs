@@ -2,13 +2,14 @@
 use std::{
     collections::HashMap,
     fs::{self},
-    path::Path,
+    path::{Path, PathBuf},
 };
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
 
-use crate::ssh::Client;
+use crate::ssh::{Client, OsType};
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ServerConfig {
@@ -17,22 +18,86 @@ pub struct ServerConfig {
     pub username: String,
     pub keypath: Option<String>,
     pub password: Option<String>,
+    // local command whose stdout is the password, e.g. "pass show servers/web1"
+    pub password_command: Option<String>,
     pub use_password: Option<bool>,
+    // password for sudo on servers without passwordless sudo
+    pub sudo_password: Option<String>,
+    // skip OS detection: "debian", "redhat" or "arch"
+    pub os_type: Option<OsType>,
+    // remote shell for sudo commands and interactive sessions, default is sh
+    pub shell: Option<String>,
+    // identification string sent during handshake, e.g. "SSH-2.0-OpenSSH_9.6"
+    pub client_id: Option<String>,
+    // prefer zlib compression, helps on slow links, default is false
+    pub compress: Option<bool>,
 }
 
 impl ServerConfig {
+    /// Password auth without a configured password, prompted before the run
+    pub fn needs_password_prompt(&self) -> bool {
+        self.keypath.is_none()
+            && self.password.is_none()
+            && self.password_command.is_none()
+            && self.use_password.unwrap_or(false)
+    }
+
+    /// Run `password_command` locally and use its stdout as the password
+    fn command_password(command: &str) -> Result<String> {
+        let output = std::process::Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .stderr(std::process::Stdio::inherit())
+            .output()
+            .with_context(|| format!("Failed to run password command: {}", command))?;
+
+        if !output.status.success() {
+            return Err(anyhow!(
+                "Password command failed ({}): {}",
+                output.status,
+                command
+            ));
+        }
+
+        let password =
+            String::from_utf8(output.stdout).context("Password command printed invalid UTF-8")?;
+        let password = password.trim_end_matches(['\n', '\r']).to_string();
+        if password.is_empty() {
+            return Err(anyhow!("Password command printed nothing: {}", command));
+        }
+
+        Ok(password)
+    }
+
     pub fn build_client(&self) -> Result<Client> {
         let mut client = Client::new(self.host.clone(), self.username.clone());
 
         client.with_port(self.port.unwrap_or(22));
 
+        if let Some(ref client_id) = self.client_id {
+            client.with_client_id(client_id.clone())?;
+        }
+
+        if let Some(ref shell) = self.shell {
+            client.with_shell(shell.clone());
+        }
+
+        client.with_compression(self.compress.unwrap_or(false));
+
+        if let Some(os_type) = self.os_type {
+            client.with_os_type(os_type);
+        }
+
+        if let Some(ref sudo_password) = self.sudo_password {
+            client.with_sudo_password(sudo_password.clone());
+        }
+
         if let Some(ref keypath) = self.keypath {
             client.with_private_key(keypath.clone());
         } else if let Some(ref password) = self.password {
             client.with_password(password.clone());
-        } else if self.use_password.unwrap_or(false) {
-            let password = rpassword::read_password().context("Failed to read password")?;
-            client.with_password(password);
+        } else if let Some(ref command) = self.password_command {
+            client.with_password(Self::command_password(command)?);
         }
 
         Ok(client)
@@ -41,38 +106,168 @@ impl ServerConfig {
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Config {
+    // other config files to merge first, relative to this file
+    pub include: Option<Vec<String>>,
     pub init: Option<InitConfig>,
     pub manage: Option<ManageConfig>,
 }
 
 impl Config {
     pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
-        let path = path.as_ref();
+        let mut stack = vec![];
+        let value = Self::load_with_includes(path.as_ref(), &mut stack)?;
+        Ok(serde_json::from_value(value)?)
+    }
+
+    /// Recursively load includes, later files override earlier ones key by key
+    fn load_with_includes(path: &Path, stack: &mut Vec<PathBuf>) -> Result<Value> {
+        let canonical = fs::canonicalize(path)
+            .with_context(|| format!("Failed to resolve config path: {}", path.display()))?;
+        if stack.contains(&canonical) {
+            return Err(anyhow!(
+                "Config include cycle detected: {}",
+                canonical.display()
+            ));
+        }
+
+        let mut value = Self::parse(&canonical)?;
+
+        let includes = value.as_object_mut().and_then(|map| map.remove("include"));
+        if let Some(includes) = includes {
+            let includes: Vec<String> = serde_json::from_value(includes)
+                .context("Config include must be a list of file paths")?;
+
+            stack.push(canonical.clone());
+
+            let base_dir = canonical.parent().unwrap_or_else(|| Path::new("."));
+            let mut merged = Value::Object(Map::new());
+            for include in includes.iter() {
+                let included = Self::load_with_includes(&base_dir.join(include), stack)
+                    .with_context(|| format!("Failed to include config: {}", include))?;
+                merge_value(&mut merged, included);
+            }
+
+            stack.pop();
+
+            merge_value(&mut merged, value);
+            value = merged;
+        }
+
+        Ok(value)
+    }
+
+    /// Parse a single config file into a generic value
+    fn parse(path: &Path) -> Result<Value> {
         let contents = fs::read_to_string(path)?;
+        let mut value = Self::parse_contents(path, &contents)?;
+        expand_env_values(&mut value)
+            .with_context(|| format!("Failed to expand config: {}", path.display()))?;
+        Ok(value)
+    }
 
-        if let Some(ext) = path.extension() {
-            if ext == "toml" {
-                let config = toml::from_str(&contents)?;
-                return Ok(config);
-            } else if ext == "yaml" {
-                let config = serde_yaml::from_str(&contents)?;
-                return Ok(config);
+    fn parse_contents(path: &Path, contents: &str) -> Result<Value> {
+        if let Some(ext) = path.extension().and_then(|ext| ext.to_str()) {
+            match ext {
+                "toml" => return Ok(toml::from_str(contents)?),
+                "yaml" | "yml" => return Ok(serde_yaml::from_str(contents)?),
+                "json" => return Ok(serde_json::from_str(contents)?),
+                _ => {}
             }
         }
 
-        let config = if let Ok(config) = toml::from_str(&contents) {
+        let config = if let Ok(config) = toml::from_str(contents) {
             config
         } else {
-            serde_yaml::from_str(&contents)?
+            serde_yaml::from_str(contents)?
         };
 
         Ok(config)
     }
 }
 
+/// Deep merge maps, any other value in `other` replaces the one in `base`
+fn merge_value(base: &mut Value, other: Value) {
+    match (base, other) {
+        (Value::Object(base), Value::Object(other)) => {
+            for (key, value) in other {
+                match base.get_mut(&key) {
+                    Some(existing) => merge_value(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, other) => *base = other,
+    }
+}
+
+/// Expand environment references in every string of a parsed config,
+/// comments and keys are left alone
+fn expand_env_values(value: &mut Value) -> Result<()> {
+    match value {
+        Value::String(string) => *string = expand_env(string)?,
+        Value::Array(values) => {
+            for value in values.iter_mut() {
+                expand_env_values(value)?;
+            }
+        }
+        Value::Object(map) => {
+            for value in map.values_mut() {
+                expand_env_values(value)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Expand `${VAR}` and `${VAR:-default}` references using the process environment.
+/// `$${` is kept as a literal `${`, a missing variable without a default is an error.
+fn expand_env(contents: &str) -> Result<String> {
+    let mut expanded = String::with_capacity(contents.len());
+    let mut rest = contents;
+
+    while let Some(pos) = rest.find("${") {
+        if rest[..pos].ends_with('$') {
+            expanded.push_str(&rest[..pos - 1]);
+            expanded.push_str("${");
+            rest = &rest[pos + 2..];
+            continue;
+        }
+
+        expanded.push_str(&rest[..pos]);
+        let end = rest[pos..]
+            .find('}')
+            .ok_or_else(|| anyhow!("Unterminated variable reference: {}", &rest[pos..]))?;
+        let reference = &rest[pos + 2..pos + end];
+
+        let (name, default) = match reference.split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (reference, None),
+        };
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(anyhow!("Invalid variable name: '{}'", name));
+        }
+
+        let value = match std::env::var(name) {
+            Ok(value) if !value.is_empty() || default.is_none() => value,
+            _ => default
+                .map(str::to_string)
+                .ok_or_else(|| anyhow!("Environment variable '{}' is not set", name))?,
+        };
+        expanded.push_str(&value);
+        rest = &rest[pos + end + 1..];
+    }
+
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
 impl Default for Config {
     fn default() -> Self {
         Self {
+            include: None,
             init: None,
             manage: None,
         }
@@ -84,15 +279,31 @@ impl Default for Config {
 // new_username = "deploy"
 // new_password = "123456"
 //
+// [[init.users]]
+// username = "alice"
+// public_key = "ssh-ed25519 ..."
+// sudo = true
+// sudo_commands = ["/usr/bin/systemctl", "/usr/bin/apt"]
+//
 // [init.server.myserver]
 // host = "127.0.0.1"
 // port = 22
 // username = "root"
 // password = "123456"
 //
+// [init.update]
+// mode = "upgrade"
+// autoremove = true
+//
 // [init.sshd]
 // new_port = 2222
 // public_key = "ssh-rsa ..."
+// hardened = true
+//
+// [[init.sshd.sftp_only]]
+// user = "uploader"
+// chroot = "/srv/sftp/uploader"
+// writable = "upload"
 //
 // [init.sshd.option]
 // PubkeyAuthentication = "yes"
@@ -104,6 +315,15 @@ impl Default for Config {
 // allow_ports = ["2222/tcp", "80/tcp", "443/tcp"]
 // deny_ports = ["22/tcp"]
 //
+// [init.sysctl]
+// "net.ipv4.ip_forward" = "0"
+// "net.ipv4.tcp_syncookies" = "1"
+//
+// [[init.mounts]]
+// device = "UUID=..."
+// mountpoint = "/data"
+// fstype = "ext4"
+//
 // [init.fail2ban.jail.sshd]
 // enabled = true
 // port = "2222/tcp"
@@ -116,17 +336,47 @@ pub struct InitConfig {
     pub server: Option<HashMap<String, ServerConfig>>,
 
     // create a new user with the following username and password
-    pub new_username: String,
-    pub new_password: String,
+    pub new_username: Option<String>,
+    pub new_password: Option<String>,
+    // restrict the new user's sudo to these commands instead of ALL
+    pub sudo_commands: Option<Vec<String>>,
+    // login shell for new users, default is /bin/bash
+    pub shell: Option<String>,
+    // additional users to create
+    pub users: Option<Vec<UserConfig>>,
+    // set the password again when the user already exists
+    pub update_password: Option<bool>,
+    // send a SHA-512 crypt hash instead of the plaintext password, default is true
+    pub hash_password: Option<bool>,
 
+    // how the update phase treats installed packages
+    pub update: Option<UpdateConfig>,
     pub sshd: Option<SshdConfig>,
     pub firewall: Option<FirewallConfig>,
     pub fail2ban: Option<Fail2banConfig>,
+    // kernel parameters, written to /etc/sysctl.d/99-biusrv.conf
+    pub sysctl: Option<HashMap<String, String>>,
+    // filesystems to mount and persist in /etc/fstab
+    pub mounts: Option<Vec<MountConfig>>,
 
     pub packages: Option<Vec<String>>,
     pub commands: Option<Vec<String>>,
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct UserConfig {
+    pub username: String,
+    // users without a password can only log in with a key
+    pub password: Option<String>,
+    pub public_key: Option<String>,
+    // grant passwordless sudo, default is false
+    pub sudo: Option<bool>,
+    // restrict sudo to these absolute command paths instead of ALL
+    pub sudo_commands: Option<Vec<String>>,
+    // login shell, overrides the init level shell
+    pub shell: Option<String>,
+}
+
 // config like:
 // [manage.server.myserver1]
 // host = "127.0.0.1"
@@ -143,11 +393,71 @@ pub struct ManageConfig {
     pub server: Option<HashMap<String, ServerConfig>>,
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MountConfig {
+    // device, or UUID=... / LABEL=... as in fstab
+    pub device: String,
+    pub mountpoint: String,
+    pub fstype: String,
+    // mount options, default is defaults,nofail
+    pub options: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct UpdateConfig {
+    // refresh, upgrade or full, default is upgrade
+    pub mode: Option<UpdateMode>,
+    // remove unused dependencies and clean the package cache afterwards, default is false
+    pub autoremove: Option<bool>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum UpdateMode {
+    // refresh the package metadata only
+    Refresh,
+    // upgrade installed packages, held packages stay as they are
+    #[default]
+    Upgrade,
+    // upgrade allowing packages to be added or removed (dist-upgrade, distro-sync)
+    Full,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct SshdConfig {
     pub new_port: Option<u16>,
     pub public_key: Option<String>,
+    // generate a keypair locally and install its public half
+    pub generate_key: Option<GenerateKeyConfig>,
+    // start from hardened defaults (no root login, no passwords, ...), `options` override them
+    pub hardened: Option<bool>,
+    // sshd_config directives, unknown keys are warned about and yes/no values normalized
     pub options: Option<HashMap<String, String>>,
+    // check `sshd -T` after reload to confirm the options are in effect
+    pub verify_effective: Option<bool>,
+    // users restricted to SFTP inside a chroot directory
+    pub sftp_only: Option<Vec<SftpOnlyConfig>>,
+    // refuse to reload sshd when the current login would stop working
+    // and no other login is known to work, default is true
+    pub lockout_check: Option<bool>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SftpOnlyConfig {
+    pub user: String,
+    // absolute path, made root owned and not writable as sshd requires
+    pub chroot: String,
+    // directory inside the chroot owned by the user, e.g. "upload"
+    pub writable: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct GenerateKeyConfig {
+    // local path for the private key, reused if it already exists
+    pub path: String,
+    // key type, only ed25519 is supported
+    #[serde(rename = "type")]
+    pub key_type: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -156,17 +466,33 @@ pub struct Fail2banConfig {
     pub content: Option<String>,
     // backend, default is systemd
     pub backend: Option<String>,
+    // write /etc/fail2ban/jail.local instead of jail.d/biusrv.conf, default is false
+    pub jail_local: Option<bool>,
+    // [DEFAULT] section shared by all jails
+    pub defaults: Option<Fail2banDefaults>,
     pub jail: Option<HashMap<String, Fail2banJailConfig>>,
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Fail2banDefaults {
+    pub maxretry: Option<u16>,
+    pub findtime: Option<u32>,
+    pub bantime: Option<u32>,
+    pub ignoreip: Option<Vec<String>>,
+    pub options: Option<HashMap<String, String>>,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Fail2banJailConfig {
     pub enabled: bool,
-    pub port: String,
-    pub filter: String,
-    pub maxretry: u16,
-    pub findtime: u16,
-    pub bantime: u16,
+    // settings left out fall back to [DEFAULT] and jail.conf,
+    // a jail named recidive gets defaults suited to repeat offenders
+    pub port: Option<String>,
+    pub filter: Option<String>,
+    pub maxretry: Option<u16>,
+    // seconds
+    pub findtime: Option<u32>,
+    pub bantime: Option<u32>,
     pub logpath: Option<String>,
     pub ignoreip: Option<Vec<String>>,
     pub options: Option<HashMap<String, String>>,
@@ -187,3 +513,57 @@ pub enum FirewallPolicy {
     Whitelist,
     Blacklist,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expand_env_substitutes_set_variables() {
+        std::env::set_var("BIUSRV_TEST_SET", "secret");
+        assert_eq!(expand_env("pw=${BIUSRV_TEST_SET}!").unwrap(), "pw=secret!");
+    }
+
+    #[test]
+    fn expand_env_rejects_unset_variables() {
+        std::env::remove_var("BIUSRV_TEST_UNSET");
+        assert!(expand_env("${BIUSRV_TEST_UNSET}").is_err());
+    }
+
+    #[test]
+    fn expand_env_falls_back_to_default() {
+        std::env::remove_var("BIUSRV_TEST_DEFAULT");
+        assert_eq!(
+            expand_env("${BIUSRV_TEST_DEFAULT:-10.0.0.5}").unwrap(),
+            "10.0.0.5"
+        );
+        std::env::set_var("BIUSRV_TEST_DEFAULT_SET", "10.0.0.6");
+        assert_eq!(
+            expand_env("${BIUSRV_TEST_DEFAULT_SET:-10.0.0.5}").unwrap(),
+            "10.0.0.6"
+        );
+    }
+
+    #[test]
+    fn expand_env_keeps_escaped_references() {
+        assert_eq!(expand_env("echo $${HOME}").unwrap(), "echo ${HOME}");
+    }
+
+    #[test]
+    fn expand_env_rejects_unterminated_references() {
+        assert!(expand_env("${BIUSRV_TEST_SET").is_err());
+        assert!(expand_env("${}").is_err());
+    }
+
+    #[test]
+    fn comments_are_not_expanded() {
+        std::env::remove_var("BIUSRV_TEST_COMMENT");
+        let mut value = Config::parse_contents(
+            Path::new("config.toml"),
+            "# uses ${BIUSRV_TEST_COMMENT}\nhost = \"${BIUSRV_TEST_COMMENT:-10.0.0.5}\"\n",
+        )
+        .unwrap();
+        expand_env_values(&mut value).unwrap();
+        assert_eq!(value["host"], "10.0.0.5");
+    }
+}
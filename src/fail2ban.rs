@@ -2,15 +2,60 @@ use std::collections::HashMap;
 
 use anyhow::{anyhow, Result};
 
-use crate::config::{Fail2banConfig, Fail2banJailConfig};
+use crate::config::{Fail2banConfig, Fail2banDefaults, Fail2banJailConfig};
 use crate::ssh::{CommandResult, Session};
 use crate::utils::{self, truncate_error_message};
 
+/// Jail configuration file managed by biusrv
+pub const CONFIG_FILE: &str = "/etc/fail2ban/jail.d/biusrv.conf";
+/// Local override of jail.conf, used instead of [`CONFIG_FILE`] with `jail_local`
+pub const JAIL_LOCAL_FILE: &str = "/etc/fail2ban/jail.local";
+
+/// fail2ban's own log, read by the recidive jail
+pub const LOG_FILE: &str = "/var/log/fail2ban.log";
+/// Daemon settings managed by biusrv
+pub const DAEMON_CONFIG_FILE: &str = "/etc/fail2ban/fail2ban.d/biusrv.conf";
+
+// the recidive jail bans hosts that keep getting banned by the other jails,
+// for a week after 5 bans within a day unless configured otherwise
+const RECIDIVE_JAIL: &str = "recidive";
+const RECIDIVE_MAXRETRY: u32 = 5;
+const RECIDIVE_FINDTIME: u32 = 86400;
+const RECIDIVE_BANTIME: u32 = 604800;
+
+/// Make fail2ban log to [`LOG_FILE`], some distributions log to the journal instead.
+/// Returns true if [`DAEMON_CONFIG_FILE`] did not exist before
+async fn set_log_target(session: &Session) -> Result<bool> {
+    let existed = session
+        .query_with_sudo(&format!("test -f {}", DAEMON_CONFIG_FILE))
+        .await?
+        .exit_status
+        == 0;
+    let content = format!("[Definition]\nlogtarget = {}\n", LOG_FILE);
+    let result = utils::create_file(session, DAEMON_CONFIG_FILE, &content, Some("644")).await?;
+    if result.exit_status != 0 {
+        return Err(anyhow!(
+            "Failed to set the fail2ban log target (exit code: {}) - {}",
+            result.exit_status,
+            truncate_error_message(result.output.trim(), 3)
+        ));
+    }
+    Ok(!existed)
+}
+
+/// The file the configuration is written to
+pub fn config_file(config: &Fail2banConfig) -> &'static str {
+    if config.jail_local.unwrap_or(false) {
+        JAIL_LOCAL_FILE
+    } else {
+        CONFIG_FILE
+    }
+}
+
 /// Install and setup fail2ban
 pub async fn setup(session: &Session, backend: Option<&str>) -> Result<()> {
     // Check if fail2ban is installed
-    let check_result = session.execute_with_sudo("which fail2ban-client").await?;
-    if check_result.exit_status != 0 {
+    if !session.has_command("fail2ban-client").await? {
         utils::install(session, "fail2ban").await?;
     }
 
@@ -51,17 +96,53 @@ pub async fn set_backend(session: &Session, backend: &str) -> Result<CommandResu
     Ok(result)
 }
 
+/// What [`configure`] replaced on the server, to undo it
+#[derive(Debug, Default, Clone)]
+pub struct Fail2banChanges {
+    // copy of the config file that existed before
+    pub backup: Option<String>,
+    // DAEMON_CONFIG_FILE was written by this run
+    pub created_log_target: bool,
+}
+
 /// Configure fail2ban with the given configuration
-pub async fn configure(session: &Session, config: &Fail2banConfig) -> Result<()> {
+pub async fn configure(session: &Session, config: &Fail2banConfig) -> Result<Fail2banChanges> {
     // If content is specified, use it directly and ignore jail config
-    if let Some(ref content) = config.content {
-        configure_with_content(session, content).await?;
+    let config_file = config_file(config);
+    let backup = if let Some(ref content) = config.content {
+        configure_with_content(session, config_file, content).await?
     } else if let Some(ref jails) = config.jail {
-        configure_jails(session, jails).await?;
+        configure_jails(session, config_file, config.defaults.as_ref(), jails).await?
     } else {
         return Err(anyhow!("No content or jail config provided"));
+    };
+
+    // A config fail2ban cannot parse would stop the service on reload
+    if !session.is_dry_run() {
+        let result = session.query_with_sudo("fail2ban-client -t").await?;
+        if result.exit_status != 0 {
+            let restore = match backup {
+                Some(ref backup) => format!("mv -f {} {}", backup, config_file),
+                None => format!("rm -f {}", config_file),
+            };
+            session.execute_with_sudo(&restore).await?;
+            return Err(anyhow!(
+                "Fail2ban config test failed, previous config restored (exit code: {}) - {}",
+                result.exit_status,
+                truncate_error_message(result.output.trim(), 10)
+            ));
+        }
     }
 
+    // the recidive jail reads fail2ban's own log, written only once the jails passed the test
+    let recidive = config.content.is_none()
+        && config
+            .jail
+            .as_ref()
+            .and_then(|jails| jails.get(RECIDIVE_JAIL))
+            .is_some_and(|jail| jail.enabled);
+    let created_log_target = recidive && set_log_target(session).await?;
+
     // Reload fail2ban to apply changes
     let result = reload(session).await?;
     if result.exit_status != 0 {
@@ -72,15 +153,24 @@ pub async fn configure(session: &Session, config: &Fail2banConfig) -> Result<()>
         ));
     }
 
-    Ok(())
+    Ok(Fail2banChanges {
+        backup,
+        created_log_target,
+    })
 }
 
-/// Configure fail2ban with custom content
-async fn configure_with_content(session: &Session, content: &str) -> Result<()> {
-    let config_file = "/etc/fail2ban/jail.d/biusrv.conf";
-
+/// Configure fail2ban with custom content, returns the backup of the replaced file
+async fn configure_with_content(
+    session: &Session,
+    config_file: &str,
+    content: &str,
+) -> Result<Option<String>> {
     // Create the configuration file
-    utils::create_file(session, config_file, content, Some("644")).await?;
+    let (_, backup) =
+        utils::create_file_with_backup(session, config_file, content, Some("644")).await?;
+    if session.is_dry_run() {
+        return Ok(backup);
+    }
 
     // Verify content was written correctly
     let verify_cmd = format!("cat {}", config_file);
@@ -89,32 +179,108 @@ async fn configure_with_content(session: &Session, content: &str) -> Result<()>
         return Err(anyhow!("Fail2ban config verification failed"));
     }
 
-    Ok(())
+    Ok(backup)
 }
 
 /// Configure a specific jail
 async fn configure_jails(
     session: &Session,
+    config_file: &str,
+    defaults: Option<&Fail2banDefaults>,
+    jails: &HashMap<String, Fail2banJailConfig>,
+) -> Result<Option<String>> {
+    let content = jails_content(defaults, jails);
+
+    let (_, backup) =
+        utils::create_file_with_backup(session, config_file, content.trim(), Some("644")).await?;
+    if session.is_dry_run() {
+        return Ok(backup);
+    }
+
+    let verify_cmd = format!("cat {}", config_file);
+    let result = session.execute_with_sudo(&verify_cmd).await?;
+    if !result.output.contains(content.trim()) {
+        return Err(anyhow!("Fail2ban config verification failed"));
+    }
+
+    Ok(backup)
+}
+
+/// Jail file text, an optional [DEFAULT] section followed by the jails sorted by name
+fn jails_content(
+    defaults: Option<&Fail2banDefaults>,
     jails: &HashMap<String, Fail2banJailConfig>,
-) -> Result<()> {
-    let config_file = "/etc/fail2ban/jail.d/biusrv.conf";
+) -> String {
     let mut content = String::new();
 
+    if let Some(defaults) = defaults {
+        content.push_str("[DEFAULT]\n");
+        for (key, value) in [
+            ("maxretry", defaults.maxretry.map(u32::from)),
+            ("findtime", defaults.findtime),
+            ("bantime", defaults.bantime),
+        ] {
+            if let Some(value) = value {
+                content.push_str(&format!("{} = {}\n", key, value));
+            }
+        }
+        if let Some(ref ignoreip) = defaults.ignoreip {
+            content.push_str(&format!("ignoreip = {}\n", ignoreip.join(" ")));
+        }
+        if let Some(ref options) = defaults.options {
+            let mut options = options.iter().collect::<Vec<_>>();
+            options.sort();
+            for (key, value) in options {
+                content.push_str(&format!("{} = {}\n", key, value));
+            }
+        }
+        content.push('\n');
+    }
+
+    // sort jails and options so re-runs produce the same file
+    let mut jails = jails.iter().collect::<Vec<_>>();
+    jails.sort_by(|a, b| a.0.cmp(b.0));
+
     for (jail_name, jail_config) in jails {
+        let recidive = jail_name.as_str() == RECIDIVE_JAIL;
         content.push_str(&format!("[{}]\n", jail_name));
         content.push_str(&format!("enabled = {}\n", jail_config.enabled));
-        content.push_str(&format!("port = {}\n", jail_config.port));
-        content.push_str(&format!("filter = {}\n", jail_config.filter));
-        content.push_str(&format!("maxretry = {}\n", jail_config.maxretry));
-        content.push_str(&format!("findtime = {}\n", jail_config.findtime));
-        content.push_str(&format!("bantime = {}\n", jail_config.bantime));
+        if let Some(ref port) = jail_config.port {
+            content.push_str(&format!("port = {}\n", port));
+        }
+        let filter = jail_config
+            .filter
+            .as_deref()
+            .or(recidive.then_some(RECIDIVE_JAIL));
+        if let Some(filter) = filter {
+            content.push_str(&format!("filter = {}\n", filter));
+        }
+        for (key, value, recidive_default) in [
+            (
+                "maxretry",
+                jail_config.maxretry.map(u32::from),
+                RECIDIVE_MAXRETRY,
+            ),
+            ("findtime", jail_config.findtime, RECIDIVE_FINDTIME),
+            ("bantime", jail_config.bantime, RECIDIVE_BANTIME),
+        ] {
+            if let Some(value) = value.or(recidive.then_some(recidive_default)) {
+                content.push_str(&format!("{} = {}\n", key, value));
+            }
+        }
         if let Some(ref ignoreip) = jail_config.ignoreip {
             content.push_str(&format!("ignoreip = {}\n", ignoreip.join(" ")));
         }
-        if let Some(ref logpath) = jail_config.logpath {
+        let logpath = jail_config
+            .logpath
+            .as_deref()
+            .or(recidive.then_some(LOG_FILE));
+        if let Some(logpath) = logpath {
             content.push_str(&format!("logpath = {}\n", logpath));
         }
         if let Some(ref options) = jail_config.options {
+            let mut options = options.iter().collect::<Vec<_>>();
+            options.sort();
             for (key, value) in options {
                 content.push_str(&format!("{} = {}\n", key, value));
             }
@@ -122,15 +288,7 @@ async fn configure_jails(
         content.push_str("\n");
     }
 
-    utils::create_file(session, config_file, content.trim(), Some("644")).await?;
-
-    let verify_cmd = format!("cat {}", config_file);
-    let result = session.execute_with_sudo(&verify_cmd).await?;
-    if !result.output.contains(content.trim()) {
-        return Err(anyhow!("Fail2ban config verification failed"));
-    }
-
-    Ok(())
+    content
 }
 
 /// Reload fail2ban configuration
@@ -141,14 +299,14 @@ pub async fn reload(session: &Session) -> Result<CommandResult> {
 
 /// Get fail2ban status
 pub async fn status(session: &Session) -> Result<CommandResult> {
-    let result = session.execute_with_sudo("fail2ban-client status").await?;
+    let result = session.query_with_sudo("fail2ban-client status").await?;
     Ok(result)
 }
 
 /// Get status of a specific jail
 pub async fn jail_status(session: &Session, jail_name: &str) -> Result<CommandResult> {
     let result = session
-        .execute_with_sudo(&format!("fail2ban-client status {}", jail_name))
+        .query_with_sudo(&format!("fail2ban-client status {}", jail_name))
         .await?;
     Ok(result)
 }
@@ -157,6 +315,9 @@ pub async fn jail_status(session: &Session, jail_name: &str) -> Result<CommandRe
 pub async fn unban_ip(session: &Session, jail_name: &str, ip: &str) -> Result<()> {
     let cmd = format!("fail2ban-client set {} unbanip {}", jail_name, ip);
     session.execute_with_sudo(&cmd).await?;
+    if session.is_dry_run() {
+        return Ok(());
+    }
 
     // Verify IP was unbanned
     let verify_result = session
@@ -176,6 +337,9 @@ pub async fn unban_ip(session: &Session, jail_name: &str, ip: &str) -> Result<()
 pub async fn ban_ip(session: &Session, jail_name: &str, ip: &str) -> Result<()> {
     let cmd = format!("fail2ban-client set {} banip {}", jail_name, ip);
     session.execute_with_sudo(&cmd).await?;
+    if session.is_dry_run() {
+        return Ok(());
+    }
 
     // Verify IP was banned
     let verify_result = session
@@ -196,3 +360,47 @@ pub async fn ban_ip(session: &Session, jail_name: &str, ip: &str) -> Result<()>
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn jail(enabled: bool) -> Fail2banJailConfig {
+        Fail2banJailConfig {
+            enabled,
+            port: None,
+            filter: None,
+            maxretry: None,
+            findtime: None,
+            bantime: None,
+            logpath: None,
+            ignoreip: None,
+            options: None,
+        }
+    }
+
+    #[test]
+    fn recidive_jail_gets_defaults() {
+        let jails = HashMap::from([(RECIDIVE_JAIL.to_string(), jail(true))]);
+        assert_eq!(
+            jails_content(None, &jails).trim(),
+            "[recidive]\nenabled = true\nfilter = recidive\nmaxretry = 5\n\
+             findtime = 86400\nbantime = 604800\nlogpath = /var/log/fail2ban.log"
+        );
+    }
+
+    #[test]
+    fn recidive_defaults_yield_to_config() {
+        let mut recidive = jail(true);
+        recidive.bantime = Some(3600);
+        let jails = HashMap::from([
+            (RECIDIVE_JAIL.to_string(), recidive),
+            ("sshd".to_string(), jail(true)),
+        ]);
+        let content = jails_content(None, &jails);
+        assert!(content.contains("bantime = 3600\n"));
+        assert!(!content.contains("bantime = 604800"));
+        // only recidive gets the defaults
+        assert!(content.contains("[sshd]\nenabled = true\n\n"));
+    }
+}
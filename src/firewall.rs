@@ -1,27 +1,96 @@
 use std::collections::HashSet;
 
 use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose, Engine as _};
+use serde::Serialize;
 
 use crate::config::{FirewallConfig, FirewallPolicy};
 use crate::ssh::{OsType, Session};
 use crate::utils::{self, truncate_error_message};
 
-/// Parse port specification (e.g., "80/tcp", "53/udp", "22", "1234:4567/tcp")
-fn parse_port_spec(port_spec: &str) -> Result<(String, String)> {
-    let (port_str, protocol) = if let Some(slash_pos) = port_spec.find('/') {
-        let port_str = &port_spec[..slash_pos];
-        let protocol = port_spec[slash_pos + 1..].trim().to_lowercase();
+/// Protocol of a port specification, `Both` expands into a tcp and an udp rule
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Protocol {
+    Tcp,
+    Udp,
+    Both,
+}
 
-        if protocol != "tcp" && protocol != "udp" {
-            return Err(anyhow!(
-                "Invalid protocol: {}. Must be 'tcp' or 'udp'",
-                protocol
-            ));
+impl Protocol {
+    /// iptables protocol names this expands into
+    fn names(&self) -> &'static [&'static str] {
+        match self {
+            Protocol::Tcp => &["tcp"],
+            Protocol::Udp => &["udp"],
+            Protocol::Both => &["tcp", "udp"],
         }
+    }
+}
+
+/// A parsed port specification
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct PortSpec {
+    // single port "80" or range "1000:2000"
+    ports: String,
+    protocol: Protocol,
+    // only match packets arriving on this interface
+    interface: Option<String>,
+}
+
+impl PortSpec {
+    /// iptables match arguments, one per protocol, e.g. "-i eth1 -p tcp --dport 3306"
+    fn match_args(&self) -> Vec<String> {
+        let interface = match self.interface {
+            Some(ref interface) => format!("-i {} ", interface),
+            None => String::new(),
+        };
+
+        self.protocol
+            .names()
+            .iter()
+            .map(|protocol| format!("{}-p {} --dport {}", interface, protocol, self.ports))
+            .collect()
+    }
+}
+
+/// Validate an interface name such as "eth1" or "ens+"
+fn parse_interface(interface: &str) -> Result<String> {
+    let valid = !interface.is_empty()
+        && interface.len() <= 15
+        && interface
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_' | '+'));
+    if !valid {
+        return Err(anyhow!("Invalid interface name: {}", interface));
+    }
+    Ok(interface.to_string())
+}
+
+/// Parse port specification (e.g., "80/tcp", "53/udp", "53/both", "22", "1234:4567/tcp"),
+/// optionally scoped to an input interface with "@", e.g. "3306/tcp@eth1"
+fn parse_port_spec(port_spec: &str) -> Result<PortSpec> {
+    let (port_spec, interface) = match port_spec.split_once('@') {
+        Some((port_spec, interface)) => (port_spec, Some(parse_interface(interface.trim())?)),
+        None => (port_spec, None),
+    };
+
+    let (port_str, protocol) = if let Some(slash_pos) = port_spec.find('/') {
+        let port_str = &port_spec[..slash_pos];
+        let protocol = match port_spec[slash_pos + 1..].trim().to_lowercase().as_str() {
+            "tcp" => Protocol::Tcp,
+            "udp" => Protocol::Udp,
+            "both" => Protocol::Both,
+            other => {
+                return Err(anyhow!(
+                    "Invalid protocol: {}. Must be 'tcp', 'udp' or 'both'",
+                    other
+                ));
+            }
+        };
 
         (port_str, protocol)
     } else {
-        (port_spec, "tcp".to_string())
+        (port_spec, Protocol::Tcp)
     };
 
     let port_str = if let Some(colon_pos) = port_str.find(":") {
@@ -42,31 +111,126 @@ fn parse_port_spec(port_spec: &str) -> Result<(String, String)> {
         format!("{}", port)
     };
 
-    Ok((port_str, protocol))
+    Ok(PortSpec {
+        ports: port_str,
+        protocol,
+        interface,
+    })
+}
+
+/// Check port specifications locally, before connecting to any server
+pub fn validate_port_specs<S: AsRef<str>>(port_specs: &[S]) -> Result<()> {
+    for port_spec in port_specs {
+        parse_port_spec(port_spec.as_ref())
+            .map_err(|e| anyhow!("Invalid port spec '{}': {}", port_spec.as_ref(), e))?;
+    }
+    Ok(())
+}
+
+/// Check whether a port specification matches the given port and protocol
+fn port_spec_covers(port_spec: &str, port: u16, protocol: &str) -> Result<bool> {
+    let spec = parse_port_spec(port_spec)?;
+    if !spec.protocol.names().contains(&protocol) {
+        return Ok(false);
+    }
+
+    let covered = match spec.ports.split_once(':') {
+        Some((start, end)) => (start.parse::<u16>()?..=end.parse::<u16>()?).contains(&port),
+        None => spec.ports.parse::<u16>()? == port,
+    };
+
+    Ok(covered)
+}
+
+/// Pre-flight check that the firewall config cannot block the SSH port
+pub fn validate_ssh_port(ssh_port: u16, config: &FirewallConfig) -> Result<()> {
+    if let Some(ref deny_ports) = config.deny_ports {
+        for port_spec in deny_ports.iter() {
+            if port_spec_covers(port_spec, ssh_port, "tcp")? {
+                return Err(anyhow!(
+                    "deny_ports entry '{}' would block the SSH port {}/tcp",
+                    port_spec,
+                    ssh_port
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Run a rule command, failing on a non-zero exit status
+async fn run_rule(session: &Session, command: &str) -> Result<()> {
+    let result = session.execute_with_sudo(command).await?;
+    if result.exit_status != 0 {
+        return Err(anyhow!(
+            "'{}' failed (exit code: {}) - {}",
+            command,
+            result.exit_status,
+            truncate_error_message(result.output.trim(), 3)
+        ));
+    }
+    Ok(())
+}
+
+/// Capture the current rules with iptables-save
+pub async fn backup_rules(session: &Session) -> Result<String> {
+    let result = session.query_with_sudo("iptables-save").await?;
+    if result.exit_status != 0 {
+        return Err(anyhow!(
+            "Failed to back up iptables rules (exit code: {}) - {}",
+            result.exit_status,
+            truncate_error_message(result.output.trim(), 3)
+        ));
+    }
+    Ok(result.output)
+}
+
+/// Restore rules captured by `backup_rules`
+pub async fn restore_rules(session: &Session, rules: &str) -> Result<()> {
+    let encoded = general_purpose::STANDARD.encode(format!("{}\n", rules));
+    let command = format!("echo '{}' | base64 -d | iptables-restore", encoded);
+    let result = session.execute_with_sudo(&command).await?;
+    if result.exit_status != 0 {
+        return Err(anyhow!(
+            "Failed to restore iptables rules (exit code: {}) - {}",
+            result.exit_status,
+            truncate_error_message(result.output.trim(), 3)
+        ));
+    }
+    Ok(())
 }
 
-/// Setup iptables with basic rules
+/// Setup iptables with basic rules, the previous rules are restored if any step fails
 pub async fn setup(session: &Session, ssh_port: u16, config: &FirewallConfig) -> Result<()> {
     // Check if iptables is available
-    let check_result = session.execute_with_sudo("which iptables").await?;
-    if check_result.exit_status != 0 {
+    if !session.has_command("iptables").await? {
         return Err(anyhow!("iptables is not available on this system"));
     }
 
+    let backup = backup_rules(session).await?;
+    if let Err(e) = apply_setup(session, ssh_port, config).await {
+        return match restore_rules(session, &backup).await {
+            Ok(()) => Err(e.context("Firewall setup failed, previous rules restored")),
+            Err(restore_err) => Err(e.context(format!(
+                "Firewall setup failed and restoring previous rules failed too: {}",
+                restore_err
+            ))),
+        };
+    }
+
+    Ok(())
+}
+
+async fn apply_setup(session: &Session, ssh_port: u16, config: &FirewallConfig) -> Result<()> {
     // Set default policies
-    session
-        .execute_with_sudo("iptables -P INPUT ACCEPT")
-        .await?;
-    session
-        .execute_with_sudo("iptables -P FORWARD ACCEPT")
-        .await?;
-    session
-        .execute_with_sudo("iptables -P OUTPUT ACCEPT")
-        .await?;
+    run_rule(session, "iptables -P INPUT ACCEPT").await?;
+    run_rule(session, "iptables -P FORWARD ACCEPT").await?;
+    run_rule(session, "iptables -P OUTPUT ACCEPT").await?;
 
     // Flush and Delete existing rules
-    session.execute_with_sudo("iptables -F").await?;
-    session.execute_with_sudo("iptables -X").await?;
+    run_rule(session, "iptables -F").await?;
+    run_rule(session, "iptables -X").await?;
 
     // Setup firewall based on policy
     match config.policy {
@@ -79,36 +243,33 @@ pub async fn setup(session: &Session, ssh_port: u16, config: &FirewallConfig) ->
 
 async fn setup_whitelist(session: &Session, ssh_port: u16, config: &FirewallConfig) -> Result<()> {
     // Allow loopback
-    session
-        .execute_with_sudo("iptables -A INPUT -i lo -j ACCEPT")
-        .await?;
+    run_rule(session, "iptables -A INPUT -i lo -j ACCEPT").await?;
 
     // Allow established and related connections
-    session
-        .execute_with_sudo("iptables -A INPUT -m state --state ESTABLISHED,RELATED -j ACCEPT")
-        .await?;
+    run_rule(
+        session,
+        "iptables -A INPUT -m state --state ESTABLISHED,RELATED -j ACCEPT",
+    )
+    .await?;
 
     // Allow SSH (port 22) by default to prevent lockout
-    session
-        .execute_with_sudo(&format!(
-            "iptables -A INPUT -p tcp --dport {} -j ACCEPT",
-            ssh_port
-        ))
-        .await?;
+    run_rule(
+        session,
+        &format!("iptables -A INPUT -p tcp --dport {} -j ACCEPT", ssh_port),
+    )
+    .await?;
 
     // Set ICMP rules
     if config.enable_icmp {
-        session
-            .execute_with_sudo("iptables -A INPUT -p icmp -j ACCEPT")
-            .await?;
+        run_rule(session, "iptables -A INPUT -p icmp -j ACCEPT").await?;
     } else {
         if let Some(allow_ping) = config.allow_ping {
             if allow_ping {
-                session
-                    .execute_with_sudo(
-                        "iptables -A INPUT -p icmp --icmp-type echo-request -j ACCEPT",
-                    )
-                    .await?;
+                run_rule(
+                    session,
+                    "iptables -A INPUT -p icmp --icmp-type echo-request -j ACCEPT",
+                )
+                .await?;
             }
         }
     }
@@ -116,25 +277,23 @@ async fn setup_whitelist(session: &Session, ssh_port: u16, config: &FirewallConf
     // Set allowed ports
     if let Some(ref allow_ports) = config.allow_ports {
         let mut chk_list = HashSet::new();
-        chk_list.insert((ssh_port.to_string(), "tcp".to_string()));
+        chk_list.insert(format!("-p tcp --dport {}", ssh_port));
         for port_spec in allow_ports.iter() {
-            let (port, protocol) = parse_port_spec(port_spec)?;
-            if chk_list.insert((port.clone(), protocol.clone())) {
-                session
-                    .execute_with_sudo(&format!(
-                        "iptables -A INPUT -p {} --dport {} -j ACCEPT",
-                        protocol, port
-                    ))
+            for match_args in parse_port_spec(port_spec)?.match_args() {
+                if chk_list.insert(match_args.clone()) {
+                    run_rule(
+                        session,
+                        &format!("iptables -A INPUT {} -j ACCEPT", match_args),
+                    )
                     .await?;
+                }
             }
         }
     }
 
     // Set restrictive default policies
-    session.execute_with_sudo("iptables -P INPUT DROP").await?;
-    session
-        .execute_with_sudo("iptables -P FORWARD DROP")
-        .await?;
+    run_rule(session, "iptables -P INPUT DROP").await?;
+    run_rule(session, "iptables -P FORWARD DROP").await?;
 
     Ok(())
 }
@@ -142,15 +301,15 @@ async fn setup_whitelist(session: &Session, ssh_port: u16, config: &FirewallConf
 async fn setup_blacklist(session: &Session, ssh_port: u16, config: &FirewallConfig) -> Result<()> {
     // Set ICMP rules
     if !config.enable_icmp {
-        session
-            .execute_with_sudo("iptables -A INPUT -p icmp -j DROP")
-            .await?;
+        run_rule(session, "iptables -A INPUT -p icmp -j DROP").await?;
     } else {
         if let Some(allow_ping) = config.allow_ping {
             if !allow_ping {
-                session
-                    .execute_with_sudo("iptables -A INPUT -p icmp --icmp-type echo-request -j DROP")
-                    .await?;
+                run_rule(
+                    session,
+                    "iptables -A INPUT -p icmp --icmp-type echo-request -j DROP",
+                )
+                .await?;
             }
         }
     }
@@ -158,16 +317,16 @@ async fn setup_blacklist(session: &Session, ssh_port: u16, config: &FirewallConf
     // Set denied ports (protect SSH port from being denied)
     if let Some(ref deny_ports) = config.deny_ports {
         let mut chk_list = HashSet::new();
-        chk_list.insert((ssh_port.to_string(), "tcp".to_string()));
+        chk_list.insert(format!("-p tcp --dport {}", ssh_port));
         for port_spec in deny_ports.iter() {
-            let (port, protocol) = parse_port_spec(port_spec)?;
-            if chk_list.insert((port.clone(), protocol.clone())) {
-                session
-                    .execute_with_sudo(&format!(
-                        "iptables -A INPUT -p {} --dport {} -j DROP",
-                        protocol, port
-                    ))
+            for match_args in parse_port_spec(port_spec)?.match_args() {
+                if chk_list.insert(match_args.clone()) {
+                    run_rule(
+                        session,
+                        &format!("iptables -A INPUT {} -j DROP", match_args),
+                    )
                     .await?;
+                }
             }
         }
     }
@@ -186,10 +345,7 @@ pub async fn save_rules(session: &Session) -> Result<()> {
 
 async fn save_rules_debian(session: &Session) -> Result<()> {
     // Try netfilter-persistent first (best for Debian/Ubuntu)
-    let check_result = session
-        .execute_with_sudo("which netfilter-persistent")
-        .await?;
-    if check_result.exit_status != 0 {
+    if !session.has_command("netfilter-persistent").await? {
         // Try to install iptables-persistent
         let install_result = utils::install(&session, "iptables-persistent").await?;
         if install_result.exit_status != 0 {
@@ -216,10 +372,13 @@ async fn save_rules_debian(session: &Session) -> Result<()> {
     Ok(())
 }
 
+/// Save command of the iptables service on RedHat, before the file fallbacks
+const REDHAT_SAVE_COMMAND: &str = "service iptables save";
+
 async fn save_rules_redhat(session: &Session) -> Result<()> {
     // check firewalld
     let check_result = session
-        .execute_with_sudo("systemctl is-active firewalld")
+        .query_with_sudo("systemctl is-active firewalld")
         .await?;
     if check_result.exit_status == 0 {
         utils::stop_service(&session, "firewalld").await?;
@@ -228,7 +387,7 @@ async fn save_rules_redhat(session: &Session) -> Result<()> {
     // Try to enable iptables service
     utils::enable_service(&session, "iptables").await?;
 
-    let save_result = session.execute_with_sudo("serivce iptables save").await?;
+    let save_result = session.execute_with_sudo(REDHAT_SAVE_COMMAND).await?;
     if save_result.exit_status == 0 {
         return Ok(());
     }
@@ -268,7 +427,7 @@ async fn save_rules_arch(session: &Session) -> Result<()> {
 
 /// Get iptables status
 pub async fn status(session: &Session) -> Result<String> {
-    let result = session.execute_with_sudo("iptables -L -n -v").await?;
+    let result = session.query_with_sudo("iptables -L -n -v").await?;
     if result.exit_status != 0 {
         return Err(anyhow!(
             "Failed to get iptables status (exit code: {}) - {}",
@@ -279,37 +438,98 @@ pub async fn status(session: &Session) -> Result<String> {
     Ok(result.output)
 }
 
-/// Allow a port
-pub async fn allow_port(session: &Session, port_spec: &str) -> Result<()> {
-    let (port, protocol) = parse_port_spec(port_spec)?;
-
-    // Check if rule already exists
-    let check_cmd = format!(
-        "iptables -C INPUT -p {} --dport {} -j ACCEPT",
-        protocol, port
-    );
-    let check_result = session.execute_with_sudo(&check_cmd).await?;
-
-    if check_result.exit_status == 0 {
-        // Rule already exists
-        return Ok(());
-    }
-
-    // Add the rule
-    let cmd = format!(
-        "iptables -A INPUT -p {} --dport {} -j ACCEPT",
-        protocol, port
-    );
-    let result = session.execute_with_sudo(&cmd).await?;
+/// A single iptables rule, as listed by `iptables -S`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FirewallRule {
+    pub chain: String,
+    pub protocol: Option<String>,
+    // single port "80" or range "1000:2000"
+    pub port: Option<String>,
+    pub source: Option<String>,
+    pub interface: Option<String>,
+    pub target: Option<String>,
+}
 
+/// Get the parsed iptables rules
+pub async fn rules(session: &Session) -> Result<Vec<FirewallRule>> {
+    let result = session.query_with_sudo("iptables -S").await?;
     if result.exit_status != 0 {
         return Err(anyhow!(
-            "Port {} was not allowed successfully (exit code: {}) - {}",
-            port_spec,
+            "Failed to list iptables rules (exit code: {}) - {}",
             result.exit_status,
-            truncate_error_message(&result.output.trim(), 3)
+            truncate_error_message(result.output.trim(), 3)
         ));
     }
+    Ok(parse_rules(&result.output))
+}
+
+/// Parse `iptables -S` output, chain policies and chain declarations are skipped
+fn parse_rules(output: &str) -> Vec<FirewallRule> {
+    let mut rules = vec![];
+
+    for line in output.lines() {
+        let fields = line.split_whitespace().collect::<Vec<_>>();
+        if fields.first() != Some(&"-A") || fields.len() < 2 {
+            continue;
+        }
+
+        let mut rule = FirewallRule {
+            chain: fields[1].to_string(),
+            protocol: None,
+            port: None,
+            source: None,
+            interface: None,
+            target: None,
+        };
+
+        let mut i = 2;
+        while i + 1 < fields.len() {
+            let value = Some(fields[i + 1].to_string());
+            match fields[i] {
+                "-p" => rule.protocol = value,
+                "--dport" => rule.port = value,
+                "-s" => rule.source = value,
+                "-i" => rule.interface = value,
+                "-j" => rule.target = value,
+                _ => {
+                    i += 1;
+                    continue;
+                }
+            }
+            i += 2;
+        }
+
+        rules.push(rule);
+    }
+
+    rules
+}
+
+/// Allow a port
+pub async fn allow_port(session: &Session, port_spec: &str) -> Result<()> {
+    for match_args in parse_port_spec(port_spec)?.match_args() {
+        // Check if rule already exists
+        let check_cmd = format!("iptables -C INPUT {} -j ACCEPT", match_args);
+        let check_result = session.query_with_sudo(&check_cmd).await?;
+
+        if check_result.exit_status == 0 {
+            // Rule already exists
+            continue;
+        }
+
+        // Add the rule
+        let cmd = format!("iptables -A INPUT {} -j ACCEPT", match_args);
+        let result = session.execute_with_sudo(&cmd).await?;
+
+        if result.exit_status != 0 {
+            return Err(anyhow!(
+                "Port {} was not allowed successfully (exit code: {}) - {}",
+                port_spec,
+                result.exit_status,
+                truncate_error_message(&result.output.trim(), 3)
+            ));
+        }
+    }
 
     Ok(())
 }
@@ -327,28 +547,28 @@ pub async fn allow_ports<S: AsRef<str>>(session: &Session, port_specs: &[S]) ->
 
 /// Deny a port
 pub async fn deny_port(session: &Session, port_spec: &str) -> Result<()> {
-    let (port, protocol) = parse_port_spec(port_spec)?;
-
-    // Check if rule already exists
-    let check_cmd = format!("iptables -C INPUT -p {} --dport {} -j DROP", protocol, port);
-    let check_result = session.execute_with_sudo(&check_cmd).await?;
-
-    if check_result.exit_status == 0 {
-        // Rule already exists
-        return Ok(());
-    }
+    for match_args in parse_port_spec(port_spec)?.match_args() {
+        // Check if rule already exists
+        let check_cmd = format!("iptables -C INPUT {} -j DROP", match_args);
+        let check_result = session.query_with_sudo(&check_cmd).await?;
+
+        if check_result.exit_status == 0 {
+            // Rule already exists
+            continue;
+        }
 
-    // Add the rule
-    let cmd = format!("iptables -A INPUT -p {} --dport {} -j DROP", protocol, port);
-    let result = session.execute_with_sudo(&cmd).await?;
+        // Add the rule
+        let cmd = format!("iptables -A INPUT {} -j DROP", match_args);
+        let result = session.execute_with_sudo(&cmd).await?;
 
-    if result.exit_status != 0 {
-        return Err(anyhow!(
-            "Port {} was not denied successfully (exit code: {}) - {}",
-            port_spec,
-            result.exit_status,
-            truncate_error_message(&result.output.trim(), 3)
-        ));
+        if result.exit_status != 0 {
+            return Err(anyhow!(
+                "Port {} was not denied successfully (exit code: {}) - {}",
+                port_spec,
+                result.exit_status,
+                truncate_error_message(&result.output.trim(), 3)
+            ));
+        }
     }
 
     Ok(())
@@ -367,30 +587,25 @@ pub async fn deny_ports<S: AsRef<str>>(session: &Session, port_specs: &[S]) -> R
 
 /// Delete a port
 pub async fn delete_port(session: &Session, allow: bool, port_spec: &str) -> Result<()> {
-    let (port, protocol) = parse_port_spec(port_spec)?;
     let action = if allow { "ACCEPT" } else { "DROP" };
 
-    let check_cmd = format!(
-        "iptables -C INPUT -p {} --dport {} -j {}",
-        protocol, port, action
-    );
-    let check_result = session.execute_with_sudo(&check_cmd).await?;
-    if check_result.exit_status != 0 {
-        return Ok(());
-    }
+    for match_args in parse_port_spec(port_spec)?.match_args() {
+        let check_cmd = format!("iptables -C INPUT {} -j {}", match_args, action);
+        let check_result = session.query_with_sudo(&check_cmd).await?;
+        if check_result.exit_status != 0 {
+            continue;
+        }
 
-    let delete_cmd = format!(
-        "iptables -D INPUT -p {} --dport {} -j {}",
-        protocol, port, action
-    );
-    let delete_result = session.execute_with_sudo(&delete_cmd).await?;
-    if delete_result.exit_status != 0 {
-        return Err(anyhow!(
-            "Port {} was not deleted successfully (exit code: {}) - {}",
-            port_spec,
-            delete_result.exit_status,
-            truncate_error_message(&delete_result.output.trim(), 3)
-        ));
+        let delete_cmd = format!("iptables -D INPUT {} -j {}", match_args, action);
+        let delete_result = session.execute_with_sudo(&delete_cmd).await?;
+        if delete_result.exit_status != 0 {
+            return Err(anyhow!(
+                "Port {} was not deleted successfully (exit code: {}) - {}",
+                port_spec,
+                delete_result.exit_status,
+                truncate_error_message(&delete_result.output.trim(), 3)
+            ));
+        }
     }
 
     Ok(())
@@ -410,3 +625,20 @@ pub async fn delete_ports<S: AsRef<str>>(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redhat_saves_through_the_iptables_service() {
+        assert_eq!(REDHAT_SAVE_COMMAND, "service iptables save");
+    }
+
+    #[test]
+    fn port_spec_covers_single_port_on_interface() {
+        assert!(port_spec_covers("22/tcp@eth0", 22, "tcp").unwrap());
+        assert!(!port_spec_covers("22/tcp@eth0", 2222, "tcp").unwrap());
+        assert!(!port_spec_covers("22/udp@eth0", 22, "tcp").unwrap());
+    }
+}
@@ -1,84 +1,413 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
+use base64::{engine::general_purpose, Engine as _};
+use russh::keys::ssh_key::{
+    private::{Ed25519Keypair, KeypairData},
+    LineEnding, PrivateKey,
+};
 
-use crate::config::{Fail2banConfig, FirewallConfig, InitConfig, SshdConfig};
+use crate::config::{
+    Fail2banConfig, FirewallConfig, GenerateKeyConfig, InitConfig, MountConfig, SshdConfig,
+    UpdateConfig, UserConfig,
+};
 use crate::fail2ban;
 use crate::firewall;
-use crate::ssh::{CommandResult, OsType, Session};
+use crate::ssh::{Client, CommandResult, OsType, Session};
+use crate::sshd;
 use crate::utils::{self, truncate_error_message};
 
+/// Outcome of an init step
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepStatus {
+    /// The step modified the server
+    Changed,
+    /// The server was already in the desired state
+    Unchanged,
+}
+
+impl std::fmt::Display for StepStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StepStatus::Changed => write!(f, "changed"),
+            StepStatus::Unchanged => write!(f, "already configured"),
+        }
+    }
+}
+
+/// Phases of server initialization, in execution order
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum InitPhase {
+    Update,
+    Packages,
+    User,
+    Sudo,
+    Sshd,
+    Fail2ban,
+    Sysctl,
+    Mounts,
+    Commands,
+    Firewall,
+    Reload,
+}
+
+impl InitPhase {
+    pub const ALL: [InitPhase; 11] = [
+        InitPhase::Update,
+        InitPhase::Packages,
+        InitPhase::User,
+        InitPhase::Sudo,
+        InitPhase::Sshd,
+        InitPhase::Fail2ban,
+        InitPhase::Sysctl,
+        InitPhase::Mounts,
+        InitPhase::Commands,
+        InitPhase::Firewall,
+        InitPhase::Reload,
+    ];
+
+    /// Stable name of the phase
+    pub fn name(&self) -> &'static str {
+        match self {
+            InitPhase::Update => "update",
+            InitPhase::Packages => "packages",
+            InitPhase::User => "user",
+            InitPhase::Sudo => "sudo",
+            InitPhase::Sshd => "sshd",
+            InitPhase::Fail2ban => "fail2ban",
+            InitPhase::Sysctl => "sysctl",
+            InitPhase::Mounts => "mounts",
+            InitPhase::Commands => "commands",
+            InitPhase::Firewall => "firewall",
+            InitPhase::Reload => "reload",
+        }
+    }
+}
+
+impl InitPhase {
+    /// Parse a phase from its stable name
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|phase| phase.name() == name)
+    }
+}
+
+impl std::fmt::Display for InitPhase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// Kernel parameters written by the sysctl phase
+pub const SYSCTL_FILE: &str = "/etc/sysctl.d/99-biusrv.conf";
+
+const FSTAB: &str = "/etc/fstab";
+
+/// Mountpoints of the fstab entries outside the block managed by biusrv
+fn fstab_mountpoints(fstab: &str) -> Vec<&str> {
+    let mut mountpoints = vec![];
+    let mut in_block = false;
+    for line in fstab.lines().map(str::trim) {
+        if line == utils::BLOCK_BEGIN {
+            in_block = true;
+        } else if line == utils::BLOCK_END {
+            in_block = false;
+        } else if !in_block && !line.is_empty() && !line.starts_with('#') {
+            if let Some(mountpoint) = line.split_whitespace().nth(1) {
+                mountpoints.push(mountpoint);
+            }
+        }
+    }
+    mountpoints
+}
+
+/// Remote file recording completed init phases, one name per line
+pub const CHECKPOINT_FILE: &str = "/var/lib/biusrv/init-progress";
+
+/// Read the completed phases from the remote checkpoint file
+pub async fn read_checkpoint(session: &Session) -> Result<HashSet<InitPhase>> {
+    let result = session
+        .query_with_sudo(&format!("cat {} 2>/dev/null", CHECKPOINT_FILE))
+        .await?;
+
+    Ok(result
+        .output
+        .lines()
+        .filter_map(|line| InitPhase::from_name(line.trim()))
+        .collect())
+}
+
+/// Record a phase as completed in the remote checkpoint file
+pub async fn mark_completed(session: &Session, phase: InitPhase) -> Result<()> {
+    let dir = CHECKPOINT_FILE
+        .rsplit_once('/')
+        .map(|(d, _)| d)
+        .unwrap_or("/");
+    let command = format!(
+        "mkdir -p {dir} && (grep -qx {name} {file} 2>/dev/null || echo {name} >> {file})",
+        dir = dir,
+        name = phase.name(),
+        file = CHECKPOINT_FILE
+    );
+    checkpoint_command(session, &command).await
+}
+
+/// Remove a phase from the remote checkpoint file
+pub async fn unmark_completed(session: &Session, phase: InitPhase) -> Result<()> {
+    let command = format!(
+        "[ ! -f {file} ] || sed -i '/^{name}$/d' {file}",
+        name = phase.name(),
+        file = CHECKPOINT_FILE
+    );
+    checkpoint_command(session, &command).await
+}
+
+/// Delete the remote checkpoint file
+pub async fn clear_checkpoint(session: &Session) -> Result<()> {
+    checkpoint_command(session, &format!("rm -f {}", CHECKPOINT_FILE)).await
+}
+
+async fn checkpoint_command(session: &Session, command: &str) -> Result<()> {
+    let result = session.execute_with_sudo(command).await?;
+    if result.exit_status != 0 {
+        return Err(anyhow!(
+            "Failed to update checkpoint {} (exit code: {}) - {}",
+            CHECKPOINT_FILE,
+            result.exit_status,
+            truncate_error_message(result.output.trim(), 3)
+        ));
+    }
+
+    Ok(())
+}
+
+/// Progress of an init run, reported through the callback of [`InitServer::run_phases`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InitEvent {
+    /// A phase is starting
+    Started(InitPhase),
+    /// A phase finished, with its outcome if the phase reports one
+    Finished(InitPhase, Option<StepStatus>),
+    /// A phase is skipped because the checkpoint records it as completed
+    Skipped(InitPhase),
+    /// The system update needs a reboot to take full effect
+    RebootRequired,
+}
+
+/// Changes made to a server during an init run, used for rollback
+#[derive(Debug, Default)]
+pub struct InitChanges {
+    // phases that changed the server, in the order they ran
+    pub phases: Vec<InitPhase>,
+    // users that did not exist before this run
+    pub created_users: Vec<String>,
+    // iptables-save output from before the firewall phase
+    pub firewall_backup: Option<String>,
+    // files the fail2ban phase replaced
+    pub fail2ban: fail2ban::Fail2banChanges,
+}
+
+impl InitChanges {
+    /// Remember a phase for rollback if it changed the server
+    fn record(&mut self, phase: InitPhase, status: StepStatus) {
+        if status == StepStatus::Changed {
+            self.phases.push(phase);
+        }
+    }
+}
+
+/// Login shell used for new users when none is configured
+pub const DEFAULT_SHELL: &str = "/bin/bash";
+
+/// Hash a password with SHA-512 crypt for `chpasswd -e`
+fn hash_password(password: &str) -> Result<String> {
+    sha_crypt::sha512_simple(password, &sha_crypt::Sha512Params::default())
+        .map_err(|e| anyhow!("Failed to hash password: {:?}", e))
+}
+
 #[derive(Debug)]
 pub struct InitServer {
-    new_username: String,
-    new_password: String,
+    // the legacy new_username comes first and receives the sshd keys
+    users: Vec<UserConfig>,
+    update_password: bool,
+    hash_password: bool,
+    shell: String,
 
+    pub update_config: UpdateConfig,
     pub sshd_config: Option<SshdConfig>,
     pub firewall_config: Option<FirewallConfig>,
     pub fail2ban_config: Option<Fail2banConfig>,
+    pub sysctl: Option<HashMap<String, String>>,
+    pub mounts: Option<Vec<MountConfig>>,
 
     pub packages: Option<Vec<String>>,
     pub commands: Option<Vec<String>>,
 }
 
+/// Command list of a sudoers entry, ALL unless the user is restricted
+fn sudo_commands(user: &UserConfig) -> String {
+    match user.sudo_commands {
+        Some(ref commands) if !commands.is_empty() => commands
+            .iter()
+            .map(|command| {
+                // characters with a meaning in sudoers must be escaped inside arguments
+                let mut escaped = String::new();
+                for c in command.trim().chars() {
+                    if matches!(c, ',' | ':' | '=' | '\\') {
+                        escaped.push('\\');
+                    }
+                    escaped.push(c);
+                }
+                format!(" {}", escaped)
+            })
+            .collect::<Vec<_>>()
+            .join(","),
+        _ => "ALL".to_string(),
+    }
+}
+
 impl InitServer {
     pub fn new(init_config: &InitConfig) -> Self {
+        let mut users = vec![];
+        if let Some(ref username) = init_config.new_username {
+            users.push(UserConfig {
+                username: username.clone(),
+                password: init_config.new_password.clone(),
+                public_key: None,
+                sudo: Some(true),
+                sudo_commands: init_config.sudo_commands.clone(),
+                shell: None,
+            });
+        }
+        users.extend(init_config.users.iter().flatten().cloned());
+
         Self {
-            new_username: init_config.new_username.clone(),
-            new_password: init_config.new_password.clone(),
+            users,
+            update_password: init_config.update_password.unwrap_or(false),
+            hash_password: init_config.hash_password.unwrap_or(true),
+            shell: init_config
+                .shell
+                .clone()
+                .unwrap_or_else(|| DEFAULT_SHELL.to_string()),
+            update_config: init_config.update.clone().unwrap_or_default(),
             sshd_config: init_config.sshd.clone(),
             firewall_config: init_config.firewall.clone(),
             fail2ban_config: init_config.fail2ban.clone(),
+            sysctl: init_config.sysctl.clone(),
+            mounts: init_config.mounts.clone(),
             packages: init_config.packages.clone(),
             commands: init_config.commands.clone(),
         }
     }
 
     pub async fn update_system(&self, session: &Session) -> Result<()> {
-        let result = utils::update_system(session).await?;
+        let result = utils::update_system(
+            session,
+            self.update_config.mode.unwrap_or_default(),
+            self.update_config.autoremove.unwrap_or(false),
+        )
+        .await?;
         if result.exit_status != 0 {
             return Err(anyhow!(
-                "Failed to update system (exit code: {}) - {}",
+                "Failed to update system (exit code: {}), last lines of {}:\n{}",
                 result.exit_status,
-                truncate_error_message(&result.output.trim(), 3)
+                utils::UPDATE_LOG,
+                result.output.trim()
             ));
         }
         Ok(())
     }
 
-    pub async fn create_user(&self, session: &Session) -> Result<()> {
-        //let create_cmd = format!("useradd -m -s /bin/bash {}", self.new_username);
-        let create_cmd = format!("useradd -m {}", self.new_username);
-        session.execute_with_sudo(&create_cmd).await?;
+    /// Users from the config that do not exist on the server yet
+    pub async fn missing_users(&self, session: &Session) -> Result<Vec<String>> {
+        let mut missing = vec![];
+        for user in self.users.iter() {
+            let verify_cmd = format!("id {}", user.username);
+            if session.query_with_sudo(&verify_cmd).await?.exit_status != 0 {
+                missing.push(user.username.clone());
+            }
+        }
+        Ok(missing)
+    }
+
+    pub async fn create_user(&self, session: &Session) -> Result<StepStatus> {
+        let mut status = StepStatus::Unchanged;
+        for user in self.users.iter() {
+            if self.create_one_user(session, user).await? == StepStatus::Changed {
+                status = StepStatus::Changed;
+            }
+        }
+        Ok(status)
+    }
 
-        // verify if user is created
-        let verify_cmd = format!("id {}", self.new_username);
-        let result = session.execute_with_sudo(&verify_cmd).await?;
-        if result.exit_status != 0 {
-            return Err(anyhow!(
-                "User verification failed (exit code: {}) - {}",
-                result.exit_status,
-                truncate_error_message(&result.output.trim(), 3)
-            ));
+    async fn shell_exists(session: &Session, shell: &str) -> Result<bool> {
+        session.has_command(shell).await
+    }
+
+    async fn create_one_user(&self, session: &Session, user: &UserConfig) -> Result<StepStatus> {
+        // skip existing user unless the password should be refreshed
+        let verify_cmd = format!("id {}", user.username);
+        let exists = session.query_with_sudo(&verify_cmd).await?.exit_status == 0;
+        if exists && (!self.update_password || user.password.is_none()) {
+            return Ok(StepStatus::Unchanged);
         }
 
-        let password_cmd = format!(
-            "echo '{}:{}' | chpasswd",
-            self.new_username, self.new_password
-        );
+        if !exists {
+            let shell = user.shell.as_deref().unwrap_or(&self.shell);
+            let create_cmd = if Self::shell_exists(session, shell).await? {
+                format!("useradd -m -s {} {}", shell, user.username)
+            } else {
+                log::warn!(
+                    "Shell '{}' not found on {}, creating '{}' with the default shell",
+                    shell,
+                    session.client(),
+                    user.username
+                );
+                format!("useradd -m {}", user.username)
+            };
+            session.execute_with_sudo(&create_cmd).await?;
+
+            // verify if user is created
+            if !session.is_dry_run() {
+                let result = session.execute_with_sudo(&verify_cmd).await?;
+                if result.exit_status != 0 {
+                    return Err(anyhow!(
+                        "User verification failed (exit code: {}) - {}",
+                        result.exit_status,
+                        truncate_error_message(result.output.trim(), 3)
+                    ));
+                }
+            }
+        }
+
+        let Some(ref password) = user.password else {
+            return Ok(StepStatus::Changed);
+        };
+
+        // hashing locally keeps the plaintext off the server,
+        // base64 keeps quotes and other special characters out of the shell
+        let (secret, chpasswd) = if self.hash_password {
+            (hash_password(password)?, "chpasswd -e")
+        } else {
+            (password.clone(), "chpasswd")
+        };
+        let encoded = general_purpose::STANDARD.encode(format!("{}:{}\n", user.username, secret));
+        let password_cmd = format!("echo '{}' | base64 -d | {}", encoded, chpasswd);
         session.execute_with_sudo(&password_cmd).await?;
+        if session.is_dry_run() {
+            return Ok(StepStatus::Changed);
+        }
 
         // verify if password is set, use passwd -S to check
-        let verify_cmd = format!("passwd -S {}", self.new_username);
+        let verify_cmd = format!("passwd -S {}", user.username);
         let result = session.execute_with_sudo(&verify_cmd).await?;
         if !result
             .output
-            .contains(format!("{} P", self.new_username).as_str())
+            .contains(format!("{} P", user.username).as_str())
         {
             return Err(anyhow!("Password verification failed: {}", result.output));
         }
 
-        Ok(())
+        Ok(StepStatus::Changed)
     }
 
     pub async fn install_required(&self, session: &Session) -> Result<()> {
@@ -120,24 +449,63 @@ impl InitServer {
         Ok(())
     }
 
-    pub async fn setup_sudo(&self, session: &Session) -> Result<()> {
+    pub async fn setup_sudo(&self, session: &Session) -> Result<StepStatus> {
         // check sudo command exists
-        let sudo_cmd = "which sudo";
-        let result = session.execute_with_sudo(sudo_cmd).await?;
-        if result.exit_status != 0 {
+        if !session.has_command("sudo").await? {
             utils::install(session, "sudo").await?;
         }
 
-        let sudo_cmd = format!(
-            "echo '{} ALL=(ALL) NOPASSWD:ALL' > /etc/sudoers.d/{}",
-            self.new_username, self.new_username
+        let mut status = StepStatus::Unchanged;
+        for user in self.users.iter().filter(|user| user.sudo.unwrap_or(false)) {
+            if Self::setup_user_sudo(session, user).await? == StepStatus::Changed {
+                status = StepStatus::Changed;
+            }
+        }
+
+        Ok(status)
+    }
+
+    async fn setup_user_sudo(session: &Session, user: &UserConfig) -> Result<StepStatus> {
+        let username = &user.username;
+        let sudo_line = format!("{} ALL=(ALL) NOPASSWD:{}", username, sudo_commands(user));
+        let sudoers_file = format!("/etc/sudoers.d/{}", username);
+
+        let current = session
+            .query_with_sudo(&format!("cat {}", sudoers_file))
+            .await?;
+        if current.exit_status == 0 && current.output.trim() == sudo_line {
+            return Ok(StepStatus::Unchanged);
+        }
+
+        // sudo ignores files in sudoers.d whose name contains a dot,
+        // so the candidate can be staged next to the real file and moved atomically
+        let tmp_file = format!("/etc/sudoers.d/.{}.biusrv-tmp", username);
+        utils::create_file(session, &tmp_file, &format!("{}\n", sudo_line), Some("440")).await?;
+
+        // Only install the file once visudo accepts it, a broken sudoers locks out sudo
+        let install_cmd = format!(
+            "visudo -cf {tmp} && mv -f {tmp} {file} || {{ status=$?; rm -f {tmp}; exit $status; }}",
+            tmp = tmp_file,
+            file = sudoers_file
         );
-        session.execute_with_sudo(&sudo_cmd).await?;
+        let result = session.execute_with_sudo(&install_cmd).await?;
+        if result.exit_status != 0 {
+            return Err(anyhow!(
+                "Sudoers validation failed for {}, file not installed (exit code: {}) - {}",
+                username,
+                result.exit_status,
+                truncate_error_message(result.output.trim(), 3)
+            ));
+        }
+        if session.is_dry_run() {
+            return Ok(StepStatus::Changed);
+        }
 
         // verify sudo configuration
         let verify_cmd = format!(
-            "grep '{} ALL=(ALL) NOPASSWD:ALL' /etc/sudoers.d/{}",
-            self.new_username, self.new_username
+            "grep -Fx -- {} {}",
+            shell_words::quote(&sudo_line),
+            sudoers_file
         );
         let result = session.execute_with_sudo(&verify_cmd).await?;
         if result.exit_status != 0 {
@@ -148,7 +516,7 @@ impl InitServer {
             ));
         }
 
-        Ok(())
+        Ok(StepStatus::Changed)
     }
 
     pub async fn setup_firewall(
@@ -156,24 +524,164 @@ impl InitServer {
         session: &Session,
         ssh_port: u16,
         config: &FirewallConfig,
-    ) -> Result<()> {
+    ) -> Result<StepStatus> {
+        // Refuse configs that would lock us out before touching any rule
+        firewall::validate_ssh_port(ssh_port, config)?;
+
+        // Snapshot rules to detect whether the rebuild changed anything
+        let before = session.query_with_sudo("iptables -S").await?;
+
         // Setup firewall
         firewall::setup(session, ssh_port, config).await?;
 
         // Save firewall rules
         firewall::save_rules(session).await?;
 
-        Ok(())
+        let after = session.query_with_sudo("iptables -S").await?;
+        if !session.is_dry_run() && before.output == after.output {
+            return Ok(StepStatus::Unchanged);
+        }
+
+        Ok(StepStatus::Changed)
     }
 
-    pub async fn setup_fail2ban(&self, session: &Session, config: &Fail2banConfig) -> Result<()> {
+    /// Write the kernel parameters to [`SYSCTL_FILE`], apply them and
+    /// check that each one is in effect
+    pub async fn setup_sysctl(
+        &self,
+        session: &Session,
+        sysctl: &HashMap<String, String>,
+    ) -> Result<StepStatus> {
+        let mut params = sysctl.iter().collect::<Vec<_>>();
+        params.sort();
+        let content = params
+            .iter()
+            .map(|(key, value)| format!("{} = {}\n", key, value))
+            .collect::<String>();
+
+        let mut status = StepStatus::Unchanged;
+        let current = session
+            .query_with_sudo(&format!("cat {}", SYSCTL_FILE))
+            .await?;
+        if current.exit_status != 0 || current.output != content {
+            status = StepStatus::Changed;
+            utils::create_file(session, SYSCTL_FILE, &content, Some("644")).await?;
+
+            let result = session.execute_with_sudo("sysctl --system").await?;
+            if result.exit_status != 0 {
+                return Err(anyhow!(
+                    "Failed to apply sysctl settings (exit code: {}) - {}",
+                    result.exit_status,
+                    truncate_error_message(result.output.trim(), 3)
+                ));
+            }
+            if session.is_dry_run() {
+                return Ok(status);
+            }
+        }
+
+        // a later file in sysctl.d or a missing kernel module can keep a value from applying
+        for (key, value) in params {
+            let result = session
+                .query_with_sudo(&format!("sysctl -n {}", shell_words::quote(key)))
+                .await?;
+            let actual = result.output.split_whitespace().collect::<Vec<_>>();
+            if result.exit_status != 0 || actual != value.split_whitespace().collect::<Vec<_>>() {
+                return Err(anyhow!(
+                    "sysctl {} is '{}' instead of '{}'",
+                    key,
+                    actual.join(" "),
+                    value
+                ));
+            }
+        }
+
+        Ok(status)
+    }
+
+    /// Create the mountpoints, add the fstab entries in a managed block and mount them.
+    /// Mountpoints that already have an fstab entry of their own are left alone
+    pub async fn setup_mounts(
+        &self,
+        session: &Session,
+        mounts: &[MountConfig],
+    ) -> Result<StepStatus> {
+        let fstab = session.query_with_sudo(&format!("cat {}", FSTAB)).await?;
+        let existing = fstab_mountpoints(&fstab.output);
+
+        let mut entries = String::new();
+        let mut managed = vec![];
+        for mount in mounts {
+            if existing.contains(&mount.mountpoint.as_str()) {
+                log::info!("{} is already in {}, skipping", mount.mountpoint, FSTAB);
+                continue;
+            }
+            utils::create_dir(session, &mount.mountpoint, None).await?;
+            entries.push_str(&format!(
+                "{} {} {} {} 0 2\n",
+                mount.device,
+                mount.mountpoint,
+                mount.fstype,
+                mount.options.as_deref().unwrap_or("defaults,nofail")
+            ));
+            managed.push(mount);
+        }
+        if entries.is_empty() {
+            return Ok(StepStatus::Unchanged);
+        }
+
+        let mut status = StepStatus::Unchanged;
+        if utils::append_block(session, FSTAB, &entries).await? {
+            status = StepStatus::Changed;
+        }
+
+        let result = session.execute_with_sudo("mount -a").await?;
+        if result.exit_status != 0 {
+            return Err(anyhow!(
+                "Failed to mount filesystems (exit code: {}) - {}",
+                result.exit_status,
+                truncate_error_message(result.output.trim(), 3)
+            ));
+        }
+        if session.is_dry_run() {
+            return Ok(status);
+        }
+
+        for mount in managed {
+            let result = session
+                .query_command(&format!("mountpoint -q {}", mount.mountpoint))
+                .await?;
+            if result.exit_status != 0 {
+                return Err(anyhow!(
+                    "{} is not mounted after mount -a",
+                    mount.mountpoint
+                ));
+            }
+        }
+
+        Ok(status)
+    }
+
+    pub async fn setup_fail2ban(
+        &self,
+        session: &Session,
+        config: &Fail2banConfig,
+    ) -> Result<(StepStatus, fail2ban::Fail2banChanges)> {
+        let read_cmd = format!("cat {}", fail2ban::config_file(config));
+        let before = session.query_with_sudo(&read_cmd).await?;
+
         // Install and start fail2ban
         fail2ban::setup(session, config.backend.as_deref()).await?;
 
         // Configure fail2ban
-        fail2ban::configure(session, config).await?;
+        let changes = fail2ban::configure(session, config).await?;
 
-        Ok(())
+        let after = session.query_with_sudo(&read_cmd).await?;
+        if !session.is_dry_run() && before.exit_status == 0 && before.output == after.output {
+            return Ok((StepStatus::Unchanged, changes));
+        }
+
+        Ok((StepStatus::Changed, changes))
     }
 
     pub async fn reload_sshd(&self, session: &Session) -> Result<CommandResult> {
@@ -190,72 +698,640 @@ impl InitServer {
             }
         }
 
+        if let Some(ref config) = self.sshd_config {
+            if config.verify_effective.unwrap_or(false) && !session.is_dry_run() {
+                Self::verify_effective_sshd(session, config).await?;
+            }
+        }
+
         Ok(result)
     }
 
-    pub async fn configure_sshd(&self, session: &Session, config: &SshdConfig) -> Result<()> {
-        let config_file = "/etc/ssh/sshd_config.d/biusrv.conf";
-        let mut config_content = String::new();
+    /// Connect afresh the way the server is reached after init, as the new user
+    /// on the new port, and check that `whoami` answers with that user.
+    /// Authenticates with `key`, else the generated key, the user's password
+    /// or the key of the current client
+    pub async fn verify_login(&self, client: &Client, key: Option<&str>) -> Result<Client> {
+        let user = self.users.first();
+        let username = user.map_or(client.user(), |user| user.username.as_str());
+        let port = self
+            .sshd_config
+            .as_ref()
+            .and_then(|config| config.new_port)
+            .unwrap_or(client.port());
 
-        // First: Add public key to authorized_keys (priority 1)
-        if let Some(ref public_key) = config.public_key {
-            let ssh_dir = format!("/home/{}/.ssh", self.new_username);
-            let auth_file = format!("{}/authorized_keys", ssh_dir);
-
-            // Create .ssh directory and set permissions
-            utils::create_dir(session, &ssh_dir, Some("700")).await?;
-
-            // Add public key and set file permissions
-            utils::create_file(session, &auth_file, public_key, Some("600")).await?;
-
-            // Set ownership for both directory and file
-            let chown_cmd = format!(
-                "chown {}:{} {} && chown {}:{} {}",
-                self.new_username,
-                self.new_username,
-                ssh_dir,
-                self.new_username,
-                self.new_username,
-                auth_file
-            );
-            session.execute_with_sudo(&chown_cmd).await?;
-
-            // Verify public key was added correctly
+        let mut verify = Client::new(client.host().to_string(), username.to_string());
+        verify.with_port(port);
+        let generated = self
+            .sshd_config
+            .as_ref()
+            .and_then(|config| config.generate_key.as_ref())
+            .map(|generate_key| local_path(&generate_key.path).to_string_lossy().to_string());
+        if let Some(key) = key.map(str::to_string).or(generated) {
+            verify.with_private_key(key);
+        } else if let Some(password) = user.and_then(|user| user.password.clone()) {
+            verify.with_password(password);
+        } else if let Some(keypath) = client.keypath() {
+            verify.with_private_key(keypath.to_string());
+        } else {
+            return Err(anyhow!(
+                "No credentials to verify the login as {} with, pass a private key",
+                username
+            ));
+        }
+
+        let session = verify
+            .connect()
+            .await
+            .with_context(|| format!("Verification login as {} failed", verify))?;
+        let result = session.query_command("whoami").await?;
+        if result.exit_status != 0 || result.output.trim() != username {
+            return Err(anyhow!(
+                "Verification login as {} answered whoami with '{}' (exit code: {})",
+                verify,
+                result.output.trim(),
+                result.exit_status
+            ));
+        }
+
+        Ok(verify)
+    }
+
+    /// Before reloading, make sure the new sshd config does not lock us out:
+    /// it must pass `sshd -t`, a new port must be free, and if the current login
+    /// (user, password or key) is disabled, another user must be able to log in
+    async fn check_lockout(&self, session: &Session, config: &SshdConfig) -> Result<()> {
+        let result = session.query_with_sudo("sshd -t").await?;
+        if result.exit_status != 0 {
+            return Err(anyhow!(
+                "Refusing to reload sshd, the config is invalid (exit code: {}) - {}",
+                result.exit_status,
+                truncate_error_message(result.output.trim(), 3)
+            ));
+        }
+
+        let client = session.client();
+        if let Some(port) = config.new_port.filter(|port| *port != client.port()) {
+            let result = session
+                .query_with_sudo(&format!("ss -Htlnp 'sport = :{}'", port))
+                .await?;
+            if result.exit_status != 0 {
+                log::warn!(
+                    "Failed to check whether port {} is free on {}, skipping check",
+                    port,
+                    client
+                );
+            } else if !result.output.trim().is_empty() && !result.output.contains("\"sshd") {
+                return Err(anyhow!(
+                    "Refusing to reload sshd, port {} is already in use - {}",
+                    port,
+                    truncate_error_message(result.output.trim(), 1)
+                ));
+            }
+        }
+
+        let options: HashMap<String, String> = sshd::options(config)
+            .into_iter()
+            .map(|(key, value)| (key.to_lowercase(), value.to_lowercase()))
+            .collect();
+        let disabled = |key: &str| options.get(key).is_some_and(|value| value == "no");
+        let password_auth = !disabled("passwordauthentication");
+        let pubkey_auth = !disabled("pubkeyauthentication");
+        let root_login = options.get("permitrootlogin").map(String::as_str);
+        let sftp_only = |user: &str| {
+            config
+                .sftp_only
+                .iter()
+                .flatten()
+                .any(|entry| entry.user == user)
+        };
+
+        let user = client.user();
+        let root_blocked = user == "root"
+            && match root_login {
+                Some("no") => true,
+                Some("prohibit-password" | "without-password" | "forced-commands-only") => {
+                    client.uses_password()
+                }
+                _ => false,
+            };
+        let reason = if root_blocked {
+            "root login is disabled".to_string()
+        } else if client.uses_password() && !password_auth {
+            "password authentication is disabled".to_string()
+        } else if !client.uses_password() && !pubkey_auth {
+            "public key authentication is disabled".to_string()
+        } else if sftp_only(user) {
+            format!("{} is restricted to SFTP", user)
+        } else {
+            return Ok(());
+        };
+
+        // another way in: the current user unless it is root, or a configured sudo user
+        let mut candidates = vec![];
+        if user != "root" {
+            candidates.push((user.to_string(), None));
+        }
+        for other in self.users.iter() {
+            if other.username != user && other.sudo.unwrap_or(false) {
+                candidates.push((other.username.clone(), other.password.as_ref()));
+            }
+        }
+        for (candidate, password) in candidates {
+            if sftp_only(&candidate) {
+                continue;
+            }
+            if password_auth && password.is_some() {
+                log::info!(
+                    "{} on {}, {} can log in with a password",
+                    reason,
+                    client,
+                    candidate
+                );
+                return Ok(());
+            }
+            if pubkey_auth && Self::has_authorized_keys(session, &candidate).await? {
+                log::info!(
+                    "{} on {}, {} can log in with a key",
+                    reason,
+                    client,
+                    candidate
+                );
+                return Ok(());
+            }
+        }
+
+        Err(anyhow!(
+            "Refusing to reload sshd: {} for the current login ({}) and no other login would work, \
+             install a public key for a sudo user or set lockout_check = false",
+            reason,
+            client
+        ))
+    }
+
+    /// Whether the user has at least one public key in authorized_keys on the server
+    async fn has_authorized_keys(session: &Session, username: &str) -> Result<bool> {
+        let command = format!(
+            "home=$(getent passwd {} | cut -d: -f6) && [ -n \"$home\" ] && grep -qE '^[^#]*(ssh-|ecdsa-|sk-)' \"$home/.ssh/authorized_keys\"",
+            username
+        );
+        let result = session.query_with_sudo(&command).await?;
+        Ok(result.exit_status == 0)
+    }
+
+    /// Compare the options we set against the effective values reported by `sshd -T`,
+    /// a later include file or the main config may still override them
+    async fn verify_effective_sshd(session: &Session, config: &SshdConfig) -> Result<()> {
+        let result = session.query_with_sudo("sshd -T").await?;
+        if result.exit_status != 0 {
+            return Err(anyhow!(
+                "Failed to read effective sshd config (exit code: {}) - {}",
+                result.exit_status,
+                truncate_error_message(result.output.trim(), 3)
+            ));
+        }
+
+        // sshd -T prints lowercase keys, repeated for multi-valued options
+        let mut effective: HashMap<String, Vec<String>> = HashMap::new();
+        for line in result.output.lines() {
+            if let Some((key, value)) = line.trim().split_once(' ') {
+                effective
+                    .entry(key.to_lowercase())
+                    .or_default()
+                    .push(value.trim().to_lowercase());
+            }
+        }
+
+        let mut expected = vec![];
+        if let Some(port) = config.new_port {
+            expected.push(("Port".to_string(), port.to_string()));
+        }
+        expected.extend(sshd::options(config));
+
+        let mut mismatches = vec![];
+        for (key, value) in expected {
+            match effective.get(&key.to_lowercase()) {
+                Some(values) if values.contains(&value.trim().to_lowercase()) => {}
+                Some(values) => mismatches.push(format!(
+                    "{} is '{}' (expected '{}')",
+                    key,
+                    values.join(", "),
+                    value
+                )),
+                None => log::warn!(
+                    "sshd option '{}' not reported by sshd -T on {}, skipping check",
+                    key,
+                    session.client()
+                ),
+            }
+        }
+
+        if !mismatches.is_empty() {
+            mismatches.sort();
+            return Err(anyhow!(
+                "sshd is not using the configured options: {}",
+                mismatches.join("; ")
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Generate an Ed25519 keypair locally, or reuse the one at the configured path,
+    /// and return the public key in OpenSSH format
+    fn generate_key(
+        session: &Session,
+        username: &str,
+        config: &GenerateKeyConfig,
+    ) -> Result<String> {
+        let key_type = config.key_type.as_deref().unwrap_or("ed25519");
+        if !key_type.eq_ignore_ascii_case("ed25519") {
+            return Err(anyhow!(
+                "Unsupported key type '{}', only ed25519 is supported",
+                key_type
+            ));
+        }
+
+        let path = local_path(&config.path);
+        let path = path.as_path();
+        let private_key = if path.exists() {
+            PrivateKey::read_openssh_file(path)
+                .map_err(|e| anyhow!("Failed to read private key {}: {}", config.path, e))?
+        } else {
+            let keypair = Ed25519Keypair::from_seed(&rand::random::<[u8; 32]>());
+            let comment = format!("{}@{}", username, session.client().host());
+            let private_key = PrivateKey::new(KeypairData::from(keypair), comment)
+                .map_err(|e| anyhow!("Failed to generate private key: {}", e))?;
+
+            if !session.is_dry_run() {
+                if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+                    std::fs::create_dir_all(dir)?;
+                }
+                private_key
+                    .write_openssh_file(path, LineEnding::LF)
+                    .map_err(|e| anyhow!("Failed to save private key {}: {}", config.path, e))?;
+            }
+            private_key
+        };
+
+        private_key
+            .public_key()
+            .to_openssh()
+            .map_err(|e| anyhow!("Failed to encode public key: {}", e))
+    }
+
+    async fn install_public_keys(
+        session: &Session,
+        username: &str,
+        public_keys: &[String],
+    ) -> Result<StepStatus> {
+        if public_keys.is_empty() {
+            return Ok(StepStatus::Unchanged);
+        }
+
+        let ssh_dir = format!("/home/{}/.ssh", username);
+        let auth_file = format!("{}/authorized_keys", ssh_dir);
+
+        let current = session
+            .query_with_sudo(&format!("cat {}", auth_file))
+            .await?;
+        if current.exit_status == 0 && public_keys.iter().all(|key| current.output.contains(key)) {
+            return Ok(StepStatus::Unchanged);
+        }
+
+        // Create .ssh directory and set permissions
+        utils::create_dir(session, &ssh_dir, Some("700")).await?;
+
+        // Add public keys and set file permissions
+        utils::create_file_with_backup(session, &auth_file, &public_keys.join("\n"), Some("600"))
+            .await?;
+
+        // Set ownership for both directory and file
+        let chown_cmd = format!(
+            "chown {}:{} {} && chown {}:{} {}",
+            username, username, ssh_dir, username, username, auth_file
+        );
+        session.execute_with_sudo(&chown_cmd).await?;
+
+        // Verify public keys were added correctly
+        if !session.is_dry_run() {
             let verify_cmd = format!("cat {}", auth_file);
             let result = session.execute_with_sudo(&verify_cmd).await?;
-            if !result.output.contains(public_key) {
+            if !public_keys.iter().all(|key| result.output.contains(key)) {
                 return Err(anyhow!("Public key verification failed: {}", result.output));
             }
         }
 
+        Ok(StepStatus::Changed)
+    }
+
+    pub async fn configure_sshd(
+        &self,
+        session: &Session,
+        config: &SshdConfig,
+    ) -> Result<StepStatus> {
+        let config_file = sshd::CONFIG_FILE;
+        let mut config_content = String::new();
+        let mut status = StepStatus::Unchanged;
+
+        let sftp_only = config.sftp_only.as_deref().unwrap_or_default();
+        for entry in sftp_only {
+            sshd::validate_sftp_only(entry)?;
+        }
+
+        // First: Add public keys to authorized_keys (priority 1)
+        for (index, user) in self.users.iter().enumerate() {
+            let mut public_keys = vec![];
+
+            // keys from the sshd config belong to the first user
+            if index == 0 {
+                if let Some(ref generate_key) = config.generate_key {
+                    public_keys.push(Self::generate_key(session, &user.username, generate_key)?);
+                }
+                if let Some(ref public_key) = config.public_key {
+                    public_keys.push(public_key.trim().to_string());
+                }
+            }
+            if let Some(ref public_key) = user.public_key {
+                public_keys.push(public_key.trim().to_string());
+            }
+
+            if Self::install_public_keys(session, &user.username, &public_keys).await?
+                == StepStatus::Changed
+            {
+                status = StepStatus::Changed;
+            }
+        }
+
         // Second: Configure SSH settings (priority 2)
         // Change SSH port
         if let Some(port) = config.new_port {
             config_content.push_str(&format!("Port {}\n", port));
         }
 
-        // Apply SSH configuration options
-        if let Some(ref sshd_options) = config.options {
-            for (key, value) in sshd_options {
-                config_content.push_str(&format!("{} {}\n", key, value));
+        // Apply SSH configuration options, sorted so re-runs produce the same file
+        for (key, value) in sshd::options(config) {
+            config_content.push_str(&format!("{} {}\n", key, value));
+        }
+
+        // Confine SFTP-only users, the chroot must be in place before sshd reloads
+        for entry in sftp_only {
+            let result = session
+                .execute_with_sudo(&sshd::sftp_only_command(entry))
+                .await?;
+            if result.exit_status != 0 {
+                return Err(anyhow!(
+                    "Failed to prepare chroot {} for {} (exit code: {}) - {}",
+                    entry.chroot,
+                    entry.user,
+                    result.exit_status,
+                    truncate_error_message(result.output.trim(), 3)
+                ));
             }
+            if result.output.contains("changed") {
+                status = StepStatus::Changed;
+            }
+            config_content.push_str(&sshd::sftp_only_block(entry));
         }
 
         // Write configuration to file
         if !config_content.is_empty() {
-            utils::create_file(session, config_file, config_content.trim(), Some("644")).await?;
+            let current = session
+                .query_with_sudo(&format!("cat {}", config_file))
+                .await?;
+            if current.exit_status != 0 || current.output.trim() != config_content.trim() {
+                status = StepStatus::Changed;
 
-            // Verify content was written correctly
-            let verify_cmd = format!("cat {}", config_file);
-            let result = session.execute_with_sudo(&verify_cmd).await?;
-            if !result.output.contains(config_content.trim()) {
-                return Err(anyhow!("SSH config verification failed: {}", result.output));
+                utils::create_file_with_backup(
+                    session,
+                    config_file,
+                    config_content.trim(),
+                    Some("644"),
+                )
+                .await?;
+
+                // Verify content was written correctly
+                if !session.is_dry_run() {
+                    let verify_cmd = format!("cat {}", config_file);
+                    let result = session.execute_with_sudo(&verify_cmd).await?;
+                    if !result.output.contains(config_content.trim()) {
+                        return Err(anyhow!("SSH config verification failed: {}", result.output));
+                    }
+                }
+            }
+        }
+
+        Ok(status)
+    }
+
+    /// Run every configured phase in `selected` and not in `done`,
+    /// recording each in the checkpoint.
+    /// `on_event` is called as phases start, finish or are skipped,
+    /// and `changes` collects what a rollback has to undo.
+    pub async fn run_phases<F>(
+        &self,
+        session: &Session,
+        selected: &HashSet<InitPhase>,
+        done: &HashSet<InitPhase>,
+        changes: &mut InitChanges,
+        mut on_event: F,
+    ) -> Result<()>
+    where
+        F: FnMut(InitEvent),
+    {
+        let pending = |phase: InitPhase, on_event: &mut F| {
+            if !selected.contains(&phase) {
+                return false;
+            }
+            if done.contains(&phase) {
+                on_event(InitEvent::Skipped(phase));
+                return false;
+            }
+            on_event(InitEvent::Started(phase));
+            true
+        };
+
+        if pending(InitPhase::Update, &mut on_event) {
+            self.update_system(session).await?;
+            mark_completed(session, InitPhase::Update).await?;
+            on_event(InitEvent::Finished(InitPhase::Update, None));
+
+            match utils::reboot_required(session).await {
+                Ok(true) => on_event(InitEvent::RebootRequired),
+                Ok(false) => {}
+                Err(e) => log::warn!("Failed to check whether a reboot is required: {}", e),
+            }
+        }
+
+        if pending(InitPhase::Packages, &mut on_event) {
+            self.install_required(session).await?;
+            mark_completed(session, InitPhase::Packages).await?;
+            on_event(InitEvent::Finished(InitPhase::Packages, None));
+        }
+
+        if pending(InitPhase::User, &mut on_event) {
+            changes.created_users = self.missing_users(session).await?;
+            let status = self.create_user(session).await?;
+            changes.record(InitPhase::User, status);
+            mark_completed(session, InitPhase::User).await?;
+            on_event(InitEvent::Finished(InitPhase::User, Some(status)));
+        }
+
+        if pending(InitPhase::Sudo, &mut on_event) {
+            let status = self.setup_sudo(session).await?;
+            changes.record(InitPhase::Sudo, status);
+            mark_completed(session, InitPhase::Sudo).await?;
+            on_event(InitEvent::Finished(InitPhase::Sudo, Some(status)));
+        }
+
+        let mut ssh_port = 22;
+        if let Some(ref sshd_config) = self.sshd_config {
+            if let Some(port) = sshd_config.new_port {
+                ssh_port = port;
+            }
+            if pending(InitPhase::Sshd, &mut on_event) {
+                let status = self.configure_sshd(session, sshd_config).await?;
+                changes.record(InitPhase::Sshd, status);
+                mark_completed(session, InitPhase::Sshd).await?;
+                on_event(InitEvent::Finished(InitPhase::Sshd, Some(status)));
+            }
+        }
+
+        if let Some(ref fail2ban_config) = self.fail2ban_config {
+            if pending(InitPhase::Fail2ban, &mut on_event) {
+                let (status, fail2ban_changes) =
+                    self.setup_fail2ban(session, fail2ban_config).await?;
+                changes.fail2ban = fail2ban_changes;
+                changes.record(InitPhase::Fail2ban, status);
+                mark_completed(session, InitPhase::Fail2ban).await?;
+                on_event(InitEvent::Finished(InitPhase::Fail2ban, Some(status)));
+            }
+        }
+
+        if let Some(ref sysctl) = self.sysctl {
+            if pending(InitPhase::Sysctl, &mut on_event) {
+                let status = self.setup_sysctl(session, sysctl).await?;
+                changes.record(InitPhase::Sysctl, status);
+                mark_completed(session, InitPhase::Sysctl).await?;
+                on_event(InitEvent::Finished(InitPhase::Sysctl, Some(status)));
+            }
+        }
+
+        if let Some(ref mounts) = self.mounts {
+            if pending(InitPhase::Mounts, &mut on_event) {
+                let status = self.setup_mounts(session, mounts).await?;
+                changes.record(InitPhase::Mounts, status);
+                mark_completed(session, InitPhase::Mounts).await?;
+                on_event(InitEvent::Finished(InitPhase::Mounts, Some(status)));
+            }
+        }
+
+        if let Some(ref commands) = self.commands {
+            if pending(InitPhase::Commands, &mut on_event) {
+                self.execute_custom_commands(session, commands).await?;
+                mark_completed(session, InitPhase::Commands).await?;
+                on_event(InitEvent::Finished(InitPhase::Commands, None));
+            }
+        }
+
+        if let Some(ref firewall_config) = self.firewall_config {
+            if pending(InitPhase::Firewall, &mut on_event) {
+                changes.firewall_backup = Some(firewall::backup_rules(session).await?);
+                let status = self
+                    .setup_firewall(session, ssh_port, firewall_config)
+                    .await?;
+                changes.record(InitPhase::Firewall, status);
+                mark_completed(session, InitPhase::Firewall).await?;
+                on_event(InitEvent::Finished(InitPhase::Firewall, Some(status)));
             }
         }
 
+        // Reload even when done so that a resumed run picks up earlier sshd changes
+        if selected.contains(&InitPhase::Reload) {
+            on_event(InitEvent::Started(InitPhase::Reload));
+            if let Some(ref sshd_config) = self.sshd_config {
+                if sshd_config.lockout_check.unwrap_or(true) && !session.is_dry_run() {
+                    self.check_lockout(session, sshd_config).await?;
+                }
+            }
+            self.reload_sshd(session).await?;
+            mark_completed(session, InitPhase::Reload).await?;
+            on_event(InitEvent::Finished(InitPhase::Reload, None));
+        }
+
         Ok(())
     }
 
+    /// Undo a completed phase, returns false if the phase cannot be reverted.
+    /// Only users created by this run are removed, together with their sudoers files,
+    /// and the firewall gets back the rules saved before the phase ran.
+    pub async fn rollback(
+        &self,
+        session: &Session,
+        phase: InitPhase,
+        changes: &InitChanges,
+    ) -> Result<bool> {
+        let created_users = &changes.created_users;
+        let command = match phase {
+            InitPhase::User | InitPhase::Sudo if created_users.is_empty() => return Ok(false),
+            InitPhase::User => created_users
+                .iter()
+                .map(|user| format!("userdel -r {}", user))
+                .collect::<Vec<_>>()
+                .join(" && "),
+            InitPhase::Sudo => created_users
+                .iter()
+                .map(|user| format!("rm -f /etc/sudoers.d/{}", user))
+                .collect::<Vec<_>>()
+                .join(" && "),
+            InitPhase::Sshd => format!("rm -f {}", sshd::CONFIG_FILE),
+            // put back a config file the phase replaced, remove the ones it created
+            InitPhase::Fail2ban => {
+                let config_file = self
+                    .fail2ban_config
+                    .as_ref()
+                    .map_or(fail2ban::CONFIG_FILE, fail2ban::config_file);
+                let restore = match changes.fail2ban.backup {
+                    Some(ref backup) => format!("mv -f {} {}", backup, config_file),
+                    None => format!("rm -f {}", config_file),
+                };
+                let log_target = if changes.fail2ban.created_log_target {
+                    format!(" && rm -f {}", fail2ban::DAEMON_CONFIG_FILE)
+                } else {
+                    String::new()
+                };
+                format!("{}{} && fail2ban-client reload", restore, log_target)
+            }
+            InitPhase::Sysctl => format!("rm -f {} && sysctl --system", SYSCTL_FILE),
+            // put back the rules the server had before init and keep them across reboots
+            InitPhase::Firewall => {
+                let Some(ref rules) = changes.firewall_backup else {
+                    return Ok(false);
+                };
+                firewall::restore_rules(session, rules).await?;
+                firewall::save_rules(session).await?;
+                return Ok(true);
+            }
+            InitPhase::Update
+            | InitPhase::Packages
+            | InitPhase::Mounts
+            | InitPhase::Commands
+            | InitPhase::Reload => return Ok(false),
+        };
+
+        let result = session.execute_with_sudo(&command).await?;
+        if result.exit_status != 0 {
+            return Err(anyhow!(
+                "Failed to roll back {} (exit code: {}) - {}",
+                phase,
+                result.exit_status,
+                truncate_error_message(result.output.trim(), 3)
+            ));
+        }
+
+        Ok(true)
+    }
+
     pub async fn execute_custom_commands(
         &self,
         session: &Session,
@@ -277,3 +1353,11 @@ impl InitServer {
         Ok(())
     }
 }
+
+/// Local path with a leading `~/` expanded to the home directory
+fn local_path(path: &str) -> std::path::PathBuf {
+    match path.strip_prefix("~/").zip(dirs::home_dir()) {
+        Some((rest, home)) => home.join(rest),
+        None => std::path::PathBuf::from(path),
+    }
+}
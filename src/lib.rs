@@ -1,3 +1,48 @@
+//! Server initialization and management over SSH.
+//!
+//! The CLI is a thin layer over these modules, which can also be used directly:
+//! connect with [`ssh::Client`], then pass the [`ssh::Session`] to the functions
+//! in [`init`], [`firewall`], [`fail2ban`], [`transfer`] or [`script`].
+//!
+//! ```no_run
+//! use std::collections::HashSet;
+//!
+//! use biusrv::{
+//!     config::Config,
+//!     firewall,
+//!     init::{InitChanges, InitPhase, InitServer},
+//!     ssh::Client,
+//! };
+//!
+//! #[tokio::main]
+//! async fn main() -> anyhow::Result<()> {
+//!     let mut client = Client::new("203.0.113.10".into(), "root".into());
+//!     client.with_private_key("/home/me/.ssh/id_ed25519".into());
+//!     let session = client.connect().await?;
+//!
+//!     // Distro and kernel detected while connecting, e.g. ubuntu 22.04
+//!     let os = session.os_info();
+//!     println!("{} {} ({})", os.id, os.version, os.kernel);
+//!
+//!     // Run individual init steps with settings from a config file
+//!     let config = Config::load("config.toml")?;
+//!     let init = InitServer::new(config.init.as_ref().expect("no [init] section"));
+//!     init.update_system(&session).await?;
+//!     init.install_required(&session).await?;
+//!
+//!     // Or run the whole sequence and follow its progress
+//!     let mut changes = InitChanges::default();
+//!     let phases = HashSet::from(InitPhase::ALL);
+//!     init.run_phases(&session, &phases, &HashSet::new(), &mut changes, |event| {
+//!         println!("{:?}", event)
+//!     })
+//!     .await?;
+//!
+//!     println!("{}", firewall::status(&session).await?);
+//!     Ok(())
+//! }
+//! ```
+
 /// Configuration serialization and deserialization.
 pub mod config;
 
@@ -19,13 +64,18 @@ pub mod utils;
 /// Fail2ban management functionality.
 pub mod fail2ban;
 
+/// sshd configuration options.
+pub mod sshd;
+
 /// Script execution functionality.
 pub mod script;
 
 /// CLI interface and commands.
 pub mod cli;
 
-/// Macro for retrying operations with exponential backoff
+/// Macro for retrying operations with exponential backoff,
+/// the delays follow the global [`utils::RetryPolicy`].
+/// Errors that are not [`utils::Retryable`] are returned at once
 #[macro_export]
 macro_rules! retry_operation {
     // Simple version without logging
@@ -39,12 +89,14 @@ macro_rules! retry_operation {
                     break;
                 }
                 Err(e) => {
-                    if attempt < $max_retry {
-                        // Exponential backoff: 1s, 2s, 4s, 8s...
-                        let delay = std::time::Duration::from_millis(1000 * (1 << attempt));
-                        tokio::time::sleep(delay).await;
-                    }
+                    let retry = attempt < $max_retry && $crate::utils::Retryable::is_retryable(&e);
                     result = Some(Err(e));
+                    if !retry {
+                        break;
+                    }
+
+                    let delay = $crate::utils::RetryPolicy::get().delay(attempt);
+                    tokio::time::sleep(delay).await;
                 }
             }
         }
@@ -63,18 +115,21 @@ macro_rules! retry_operation {
                     break;
                 }
                 Err(e) => {
-                    if attempt < $max_retry {
+                    if attempt < $max_retry && !$crate::utils::Retryable::is_retryable(&e) {
+                        log::error!("{} failed, not retrying: {}", $log_prefix, e);
+                        result = Some(Err(e));
+                        break;
+                    } else if attempt < $max_retry {
+                        let delay = $crate::utils::RetryPolicy::get().delay(attempt);
                         log::warn!(
-                            "{} failed (attempt {}/{}): {}, retrying in {}s...",
+                            "{} failed (attempt {}/{}): {}, retrying in {:.1}s...",
                             $log_prefix,
                             attempt + 1,
                             $max_retry + 1,
                             e,
-                            1 << attempt
+                            delay.as_secs_f64()
                         );
 
-                        // Exponential backoff: 1s, 2s, 4s, 8s...
-                        let delay = std::time::Duration::from_millis(1000 * (1 << attempt));
                         tokio::time::sleep(delay).await;
                     } else {
                         log::error!(
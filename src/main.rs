@@ -1,10 +1,12 @@
+use std::io::IsTerminal;
 use std::str::FromStr;
 
 use clap::Parser;
 use log::{error, LevelFilter};
 
-use biusrv::cli::{Cli, Commands};
+use biusrv::cli::{executor::TasksFailed, reporter::Reporter, Cli, Commands};
 use biusrv::config::Config;
+use biusrv::utils::RetryPolicy;
 
 #[tokio::main]
 async fn main() {
@@ -15,6 +17,24 @@ async fn main() {
         .filter_level(LevelFilter::from_str(&cli.log_level).unwrap_or(LevelFilter::Warn))
         .init();
 
+    // keep stdout clean when it carries file contents
+    let stderr = match &cli.command {
+        Commands::Manage(manage_cmd) => manage_cmd.writes_stdout(),
+        _ => false,
+    };
+    let terminal = if stderr {
+        std::io::stderr().is_terminal()
+    } else {
+        std::io::stdout().is_terminal()
+    };
+    Reporter::init(Reporter {
+        stream: cli.global.stream || !terminal,
+        format: cli.global.output,
+        stderr,
+    });
+
+    RetryPolicy::init(cli.global.retry_policy());
+
     let config = match Config::load(cli.config) {
         Ok(cfg) => cfg,
         Err(e) => {
@@ -26,9 +46,9 @@ async fn main() {
     match cli.command {
         Commands::Init(init_cmd) => {
             if let Some(init_config) = &config.init {
-                if let Err(e) = init_cmd.execute(init_config).await {
+                if let Err(e) = init_cmd.execute(init_config, &cli.global).await {
                     error!("Init command failed: {}", e);
-                    std::process::exit(1);
+                    std::process::exit(exit_code(&e));
                 }
             } else {
                 error!("Init configuration not found");
@@ -37,9 +57,9 @@ async fn main() {
         }
         Commands::Manage(manage_cmd) => {
             if let Some(manage_config) = &config.manage {
-                if let Err(e) = manage_cmd.execute(manage_config).await {
+                if let Err(e) = manage_cmd.execute(manage_config, &cli.global).await {
                     error!("Manage command failed: {}", e);
-                    std::process::exit(1);
+                    std::process::exit(exit_code(&e));
                 }
             } else {
                 error!("Manage configuration not found");
@@ -48,3 +68,12 @@ async fn main() {
         }
     }
 }
+
+/// Exit with the worst remote exit status when tasks failed, 1 otherwise
+fn exit_code(error: &anyhow::Error) -> i32 {
+    error
+        .chain()
+        .find_map(|e| e.downcast_ref::<TasksFailed>())
+        .map(TasksFailed::exit_code)
+        .unwrap_or(1)
+}
@@ -1,6 +1,10 @@
-use std::{collections::HashMap, fs, path::Path};
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
 use serde::{Deserialize, Serialize};
 
 use crate::{ssh::Session, transfer::TransferConfig, utils::truncate_error_message};
@@ -9,13 +13,53 @@ use crate::{ssh::Session, transfer::TransferConfig, utils::truncate_error_messag
 #[derive(Debug, Deserialize, Serialize)]
 pub struct ScriptConfig {
     pub info: ScriptInfo,
+    /// Script files (relative to this file) whose actions are merged in
+    pub include: Option<Vec<String>>,
     pub script: HashMap<String, ScriptAction>,
 }
 
 impl ScriptConfig {
-    /// Load script config from a single file
+    /// Load script config from a single file, merging included scripts
     pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
-        let path = path.as_ref();
+        let mut stack = vec![];
+        Self::load_with_includes(path.as_ref(), &mut stack)
+    }
+
+    /// Recursively load includes, local actions override included ones
+    fn load_with_includes(path: &Path, stack: &mut Vec<PathBuf>) -> Result<Self> {
+        let canonical = fs::canonicalize(path)
+            .with_context(|| format!("Failed to resolve script path: {}", path.display()))?;
+        if stack.contains(&canonical) {
+            return Err(anyhow!(
+                "Script include cycle detected: {}",
+                canonical.display()
+            ));
+        }
+
+        let mut config = Self::parse(&canonical)?;
+
+        if let Some(includes) = config.include.take() {
+            stack.push(canonical.clone());
+
+            let base_dir = canonical.parent().unwrap_or_else(|| Path::new("."));
+            let mut script = HashMap::new();
+            for include in includes.iter() {
+                let included = Self::load_with_includes(&base_dir.join(include), stack)
+                    .with_context(|| format!("Failed to include script: {}", include))?;
+                script.extend(included.script);
+            }
+
+            stack.pop();
+
+            script.extend(config.script);
+            config.script = script;
+        }
+
+        Ok(config)
+    }
+
+    /// Parse a single script file without resolving includes
+    fn parse(path: &Path) -> Result<Self> {
         let contents = fs::read_to_string(path)?;
 
         if let Some(ext) = path.extension() {
@@ -61,6 +105,7 @@ pub enum ScriptActionType {
 #[derive(Debug, Deserialize, Serialize)]
 pub struct CommandAction {
     pub sudo: Option<bool>,
+    pub env: Option<HashMap<String, String>>,
     pub cmds: Vec<String>,
 }
 
@@ -70,6 +115,7 @@ pub struct TransferAction {
     pub remote: String,
     pub force: Option<bool>,
     pub resume: Option<bool>,
+    pub no_clobber_newer: Option<bool>,
     pub max_retry: Option<u32>,
 }
 
@@ -101,11 +147,14 @@ impl std::fmt::Display for ScriptActionType {
 
 impl CommandAction {
     pub async fn execute(&self, session: &Session) -> Result<()> {
+        let no_env = HashMap::new();
+        let env = self.env.as_ref().unwrap_or(&no_env);
+
         for cmd in self.cmds.iter() {
             let result = if self.sudo.unwrap_or(false) {
-                session.execute_with_sudo(cmd).await?
+                session.execute_with_sudo_and_env(cmd, env).await?
             } else {
-                session.execute_command(cmd).await?
+                session.execute_command_with_env(cmd, env).await?
             };
 
             if result.exit_status != 0 {
@@ -124,9 +173,25 @@ impl CommandAction {
 
 impl TransferAction {
     pub async fn execute(&self, session: &Session, is_upload: bool) -> Result<()> {
+        if session.is_dry_run() {
+            let (from, to) = if is_upload {
+                (&self.local, &self.remote)
+            } else {
+                (&self.remote, &self.local)
+            };
+            session.dry_run_note(&format!(
+                "🔍 [dry-run] {} transfer {} -> {}",
+                session.client(),
+                from,
+                to
+            ));
+            return Ok(());
+        }
+
         let transfer_config = TransferConfig {
             force: self.force.unwrap_or(false),
             resume: self.resume.unwrap_or(false),
+            no_clobber_newer: self.no_clobber_newer.unwrap_or(false),
             max_retry: self.max_retry.unwrap_or(0),
             ..Default::default()
         };
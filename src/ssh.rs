@@ -1,30 +1,70 @@
 #![allow(dead_code)]
 /// SSH related functionality.
+use std::borrow::Cow;
+use std::collections::HashMap;
 use std::future::Future;
-use std::sync::Arc;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use anyhow::{anyhow, Context, Result};
+use base64::{engine::general_purpose, Engine as _};
 use crossterm::terminal;
 use russh::{
     client::{self, Config, Handle, Msg},
+    compression,
     keys::{load_secret_key, ssh_key, PrivateKeyWithHashAlg},
-    Channel,
+    Channel, SshId,
 };
 use russh_sftp::client::SftpSession;
+use serde::{Deserialize, Serialize};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
-    sync::mpsc,
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, OwnedSemaphorePermit, Semaphore},
 };
 
+use crate::retry_operation;
 use crate::transfer::{TransferConfig, TransferSession};
+use crate::utils::PermanentError;
 
-#[derive(Debug)]
+/// SOCKS5 proxy over direct-tcpip channels.
+pub mod socks;
+
+/// Remote shell used unless the server config overrides it
+pub const DEFAULT_SHELL: &str = "sh";
+
+/// Unanswered keepalives before a session is considered dead
+pub const KEEPALIVE_MAX: usize = 3;
+
+/// Receives the line for each command a dry-run session skips,
+/// without one the lines are logged at info level
+#[derive(Clone)]
+pub struct DryRunHook(Arc<dyn Fn(&str) + Send + Sync>);
+
+impl std::fmt::Debug for DryRunHook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("DryRunHook")
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Client {
     host: String,
     port: u16,
     username: String,
     password: Option<String>,
     keypath: Option<String>,
+    client_id: Option<String>,
+    dry_run: bool,
+    dry_run_hook: Option<DryRunHook>,
+    connection_limit: Option<Arc<Semaphore>>,
+    sudo_password: Option<String>,
+    os_type: Option<OsType>,
+    shell: String,
+    keepalive: Option<Duration>,
+    compression: bool,
+    connect_retries: u32,
 }
 
 impl Client {
@@ -35,6 +75,16 @@ impl Client {
             username,
             password: None,
             keypath: None,
+            client_id: None,
+            dry_run: false,
+            dry_run_hook: None,
+            connection_limit: None,
+            sudo_password: None,
+            os_type: None,
+            shell: DEFAULT_SHELL.to_string(),
+            keepalive: None,
+            compression: false,
+            connect_retries: 0,
         }
     }
 
@@ -50,10 +100,47 @@ impl Client {
         &self.username
     }
 
+    pub fn with_username(&mut self, username: String) {
+        self.username = username;
+    }
+
+    pub fn keypath(&self) -> Option<&str> {
+        self.keypath.as_deref()
+    }
+
+    /// Whether sessions log in with the password, it takes precedence over the key
+    pub fn uses_password(&self) -> bool {
+        self.password.is_some()
+    }
+
+    /// Forget the password so key authentication is used
+    pub fn clear_password(&mut self) {
+        self.password = None;
+    }
+
     pub fn with_password(&mut self, password: String) {
         self.password = Some(password);
     }
 
+    /// Shell that wraps sudo commands and starts empty interactive sessions
+    pub fn with_shell(&mut self, shell: String) {
+        self.shell = shell;
+    }
+
+    pub fn shell(&self) -> &str {
+        &self.shell
+    }
+
+    /// Skip OS detection and assume this distro family
+    pub fn with_os_type(&mut self, os_type: OsType) {
+        self.os_type = Some(os_type);
+    }
+
+    /// Password fed to `sudo -S`, without it sudo must be passwordless
+    pub fn with_sudo_password(&mut self, password: String) {
+        self.sudo_password = Some(password);
+    }
+
     pub fn with_private_key(&mut self, keypath: String) {
         self.keypath = Some(keypath);
     }
@@ -62,8 +149,87 @@ impl Client {
         self.port = port;
     }
 
+    /// Override the identification string sent during the handshake,
+    /// e.g. "SSH-2.0-OpenSSH_9.6"
+    pub fn with_client_id(&mut self, client_id: String) -> Result<()> {
+        validate_client_id(&client_id)?;
+        self.client_id = Some(client_id);
+        Ok(())
+    }
+
+    /// Print commands instead of executing them on sessions from this client
+    pub fn with_dry_run(&mut self, dry_run: bool) {
+        self.dry_run = dry_run;
+    }
+
+    pub fn is_dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    /// Hand the dry-run lines to `hook` instead of the log
+    pub fn with_dry_run_hook(&mut self, hook: impl Fn(&str) + Send + Sync + 'static) {
+        self.dry_run_hook = Some(DryRunHook(Arc::new(hook)));
+    }
+
+    /// Send a keepalive after `interval` without traffic so idle connections
+    /// survive NAT and firewall timeouts, the session is dropped after
+    /// [`KEEPALIVE_MAX`] unanswered keepalives
+    pub fn with_keepalive(&mut self, interval: Duration) {
+        self.keepalive = Some(interval);
+    }
+
+    /// Prefer zlib compression, the server may still refuse it
+    pub fn with_compression(&mut self, compression: bool) {
+        self.compression = compression;
+    }
+
+    /// Retry the TCP connect and handshake this many times with the global
+    /// retry backoff, rejected credentials are not retried
+    pub fn with_connect_retries(&mut self, retries: u32) {
+        self.connect_retries = retries;
+    }
+
+    /// Share a limit on live connections with other clients,
+    /// a permit is held for as long as the session is open
+    pub fn with_connection_limit(&mut self, limit: Arc<Semaphore>) {
+        self.connection_limit = Some(limit);
+    }
+
+    /// Open a session, any failure is wrapped in a `ConnectError`
     pub async fn connect(&self) -> Result<Session> {
-        let config = Config::default();
+        let permit = match self.connection_limit {
+            Some(ref limit) => Some(Arc::clone(limit).acquire_owned().await?),
+            None => None,
+        };
+
+        let mut session = retry_operation!(
+            self.connect_retries,
+            self.try_connect().await,
+            format!("Connecting to {}", self)
+        )
+        .map_err(|e| anyhow::Error::new(ConnectError(e)))?;
+        session._permit = permit;
+
+        Ok(session)
+    }
+
+    /// Open a session without taking a connection permit
+    async fn try_connect(&self) -> Result<Session> {
+        let mut config = Config::default();
+        if let Some(ref client_id) = self.client_id {
+            config.client_id = SshId::Standard(client_id.clone());
+        }
+        if self.compression {
+            config.preferred.compression = Cow::Borrowed(&[
+                compression::ZLIB,
+                compression::ZLIB_LEGACY,
+                compression::NONE,
+            ]);
+        }
+        if let Some(interval) = self.keepalive {
+            config.keepalive_interval = Some(interval);
+            config.keepalive_max = KEEPALIVE_MAX;
+        }
         let config = Arc::new(config);
 
         let handler = Handler {};
@@ -75,7 +241,8 @@ impl Client {
                 .await?
         } else if let Some(ref keypath) = self.keypath {
             let key_pair = load_secret_key(keypath, None)
-                .with_context(|| format!("Failed to load private key from: {}", keypath))?;
+                .with_context(|| format!("Failed to load private key from: {}", keypath))
+                .map_err(PermanentError::wrap)?;
             session
                 .authenticate_publickey(
                     &self.username,
@@ -86,29 +253,84 @@ impl Client {
                 )
                 .await?
         } else {
-            return Err(anyhow!(
+            return Err(PermanentError::wrap(anyhow!(
                 "No authentication method provided (need password or private key)"
-            ));
+            )));
         };
 
         if !auth_result.success() {
-            return Err(anyhow!(
-                "SSH authentication failed for user: {}",
-                self.username
-            ));
+            return Err(PermanentError::wrap(AuthFailed(self.username.clone())));
         }
 
-        let channel = session.channel_open_session().await?;
-        let os_type = detect_os_type(channel).await?;
+        let os_info = match self.os_type {
+            Some(os_type) => OsInfo::from(os_type),
+            None => detect_os(&session).await?,
+        };
 
         Ok(Session {
-            user: self.username.clone(),
-            os_type,
+            client: self.clone(),
+            os_info,
             handler: session,
+            _permit: None,
+            commands: Mutex::new(HashMap::new()),
+            package_manager: Mutex::new(None),
         })
     }
 }
 
+/// Delay between checks while waiting for a reboot
+const REBOOT_POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// Time allowed for a single reconnect attempt after a reboot
+const REBOOT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Failure to establish a session: unreachable host, handshake or authentication
+#[derive(Debug)]
+pub struct ConnectError(anyhow::Error);
+
+impl ConnectError {
+    pub fn inner(&self) -> &anyhow::Error {
+        &self.0
+    }
+
+    /// Whether the error, or any error it was wrapped in, is a connection failure
+    pub fn is_connect_error(error: &anyhow::Error) -> bool {
+        error.chain().any(|e| e.is::<ConnectError>())
+    }
+}
+
+impl std::fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:#}", self.0)
+    }
+}
+
+impl std::error::Error for ConnectError {}
+
+/// The server rejected the credentials of the user
+#[derive(Debug)]
+pub struct AuthFailed(pub String);
+
+impl AuthFailed {
+    /// Whether the error, or a connection failure it wraps, is a rejected login
+    pub fn is_auth_failed(error: &anyhow::Error) -> bool {
+        error.chain().any(|e| {
+            e.is::<AuthFailed>()
+                || e.downcast_ref::<ConnectError>()
+                    .is_some_and(|e| Self::is_auth_failed(e.inner()))
+                || e.downcast_ref::<PermanentError>()
+                    .is_some_and(|e| Self::is_auth_failed(e.inner()))
+        })
+    }
+}
+
+impl std::fmt::Display for AuthFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SSH authentication failed for user: {}", self.0)
+    }
+}
+
+impl std::error::Error for AuthFailed {}
+
 impl std::fmt::Display for Client {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}@{}:{}", self.username, self.host, self.port)
@@ -121,19 +343,156 @@ pub struct CommandResult {
     pub exit_status: u32,
 }
 
+/// Exact bytes written by a command, stdout and stderr kept apart
+#[derive(Debug, Default)]
+pub struct CommandOutput {
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    pub exit_status: u32,
+}
+
 pub struct Session {
-    user: String,
-    os_type: OsType,
+    client: Client,
+    os_info: OsInfo,
     handler: Handle<Handler>,
+    _permit: Option<OwnedSemaphorePermit>,
+    // cached `which` probes, see `has_command`
+    commands: Mutex<HashMap<String, bool>>,
+    // resolved once, see `package_manager`
+    package_manager: Mutex<Option<&'static str>>,
 }
 
 impl Session {
     pub fn current_user(&self) -> &str {
-        &self.user
+        self.client.user()
+    }
+
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    pub fn is_dry_run(&self) -> bool {
+        self.client.dry_run
+    }
+
+    /// Announce an action skipped in dry-run mode
+    pub(crate) fn dry_run_note(&self, line: &str) {
+        match self.client.dry_run_hook {
+            Some(ref hook) => (hook.0)(line),
+            None => log::info!("{}", line),
+        }
     }
 
     pub fn os_type(&self) -> OsType {
-        self.os_type
+        self.os_info.family
+    }
+
+    /// Distro and kernel detected at connect time
+    pub fn os_info(&self) -> &OsInfo {
+        &self.os_info
+    }
+
+    /// Check whether a command is available (as root), cached for the session
+    pub async fn has_command(&self, name: &str) -> Result<bool> {
+        if let Some(&found) = self.commands.lock().unwrap().get(name) {
+            return Ok(found);
+        }
+
+        let found = self
+            .query_with_sudo(&format!("which {}", name))
+            .await?
+            .exit_status
+            == 0;
+        self.commands
+            .lock()
+            .unwrap()
+            .insert(name.to_string(), found);
+
+        Ok(found)
+    }
+
+    /// Package manager binary for the OS, resolved once per session.
+    /// `apt-get` and `dnf` are preferred, `apt` and `yum` are the fallbacks
+    pub async fn package_manager(&self) -> Result<&'static str> {
+        if let Some(binary) = *self.package_manager.lock().unwrap() {
+            return Ok(binary);
+        }
+
+        let (preferred, fallback) = match self.os_info.family {
+            OsType::Debian => ("apt-get", "apt"),
+            OsType::RedHat => ("dnf", "yum"),
+            OsType::Arch => ("pacman", "pacman"),
+        };
+        let binary = if self.has_command(preferred).await? {
+            preferred
+        } else {
+            fallback
+        };
+        *self.package_manager.lock().unwrap() = Some(binary);
+        Ok(binary)
+    }
+
+    /// Drop cached command probes, e.g. after installing or removing packages
+    pub fn forget_commands(&self) {
+        self.commands.lock().unwrap().clear();
+    }
+
+    /// Reboot the server and wait until SSH answers again, returning the new session.
+    /// The connection dropping while `reboot` runs is expected, the connection
+    /// permit of this session is handed over to the new one.
+    pub async fn reboot_and_wait(mut self, timeout: Duration) -> Result<Session> {
+        match self.execute_with_sudo("reboot").await {
+            Ok(result) if result.exit_status != 0 => {
+                return Err(anyhow!(
+                    "Failed to reboot (exit code: {}) - {}",
+                    result.exit_status,
+                    result.output.trim()
+                ));
+            }
+            Ok(_) => {}
+            Err(e) => log::debug!("Connection to {} dropped by reboot: {}", self.client, e),
+        }
+
+        let deadline = Instant::now() + timeout;
+
+        // Wait for the old connection to go down, so we don't reconnect before the reboot
+        while !self.is_dry_run() && !self.handler.is_closed() {
+            if Instant::now() >= deadline {
+                return Err(anyhow!(
+                    "Timed out waiting for {} to go down for reboot",
+                    self.client
+                ));
+            }
+            tokio::time::sleep(REBOOT_POLL_INTERVAL).await;
+        }
+
+        let client = self.client.clone();
+        let permit = self._permit.take();
+        drop(self);
+
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(anyhow::Error::new(ConnectError(anyhow!(
+                    "{} did not come back within {}s after reboot",
+                    client,
+                    timeout.as_secs()
+                ))));
+            }
+
+            match tokio::time::timeout(remaining.min(REBOOT_CONNECT_TIMEOUT), client.try_connect())
+                .await
+            {
+                Ok(Ok(mut session)) => {
+                    session._permit = permit;
+                    return Ok(session);
+                }
+                Ok(Err(e)) => log::debug!("{} not back yet: {}", client, e),
+                Err(_) => log::debug!("{} not back yet: connect timed out", client),
+            }
+
+            tokio::time::sleep(REBOOT_POLL_INTERVAL.min(remaining)).await;
+        }
     }
 
     pub async fn open_sftp_session(
@@ -152,7 +511,76 @@ impl Session {
         Ok(channel)
     }
 
+    /// Open a channel to `host:port` as seen from the server (direct-tcpip)
+    pub async fn open_direct_tcpip(
+        &self,
+        host: &str,
+        port: u16,
+        originator: SocketAddr,
+    ) -> Result<Channel<Msg>> {
+        let channel = self
+            .handler
+            .channel_open_direct_tcpip(
+                host,
+                port as u32,
+                originator.ip().to_string(),
+                originator.port() as u32,
+            )
+            .await
+            .with_context(|| format!("Failed to open a channel to {}:{}", host, port))?;
+        Ok(channel)
+    }
+
+    /// Listen on `local_addr` and forward each accepted connection to
+    /// `remote_host:remote_port` as seen from the server. Runs until the listener fails
+    pub async fn forward_local(
+        &self,
+        local_addr: &str,
+        remote_host: &str,
+        remote_port: u16,
+    ) -> Result<()> {
+        let listener = TcpListener::bind(local_addr)
+            .await
+            .with_context(|| format!("Failed to listen on {}", local_addr))?;
+        log::info!(
+            "Forwarding {} to {}:{} via {}",
+            listener.local_addr()?,
+            remote_host,
+            remote_port,
+            self.client
+        );
+
+        loop {
+            let (stream, peer) = listener.accept().await?;
+            // a refused channel only fails this connection
+            match self.open_direct_tcpip(remote_host, remote_port, peer).await {
+                Ok(channel) => {
+                    tokio::spawn(proxy(stream, channel));
+                }
+                Err(e) => log::warn!("{:#}", e),
+            }
+        }
+    }
+
+    /// Execute a command, in dry-run mode it is printed and reported as successful
     pub async fn execute_command<S: AsRef<str>>(&self, command: S) -> Result<CommandResult> {
+        if self.is_dry_run() {
+            self.dry_run_note(&format!(
+                "🔍 [dry-run] {} $ {}",
+                self.client,
+                command.as_ref()
+            ));
+            return Ok(CommandResult {
+                output: String::new(),
+                exit_status: 0,
+            });
+        }
+
+        self.query_command(command).await
+    }
+
+    /// Execute a read-only command, it runs even in dry-run mode
+    pub async fn query_command<S: AsRef<str>>(&self, command: S) -> Result<CommandResult> {
         let mut channel = self.handler.channel_open_session().await?;
         channel.exec(true, command.as_ref()).await?;
 
@@ -160,6 +588,64 @@ impl Session {
         Ok(result)
     }
 
+    /// Execute a command, `on_output` is called with each chunk of output as it arrives.
+    /// In dry-run mode it is printed and reported as successful
+    pub async fn execute_command_streaming<S, F>(
+        &self,
+        command: S,
+        on_output: F,
+    ) -> Result<CommandResult>
+    where
+        S: AsRef<str>,
+        F: FnMut(&[u8]),
+    {
+        if self.is_dry_run() {
+            self.dry_run_note(&format!(
+                "🔍 [dry-run] {} $ {}",
+                self.client,
+                command.as_ref()
+            ));
+            return Ok(CommandResult {
+                output: String::new(),
+                exit_status: 0,
+            });
+        }
+
+        let mut channel = self.handler.channel_open_session().await?;
+        channel.exec(true, command.as_ref()).await?;
+
+        let result = wait_result_from_channel_streaming(&mut channel, on_output).await?;
+        Ok(result)
+    }
+
+    /// Execute a command and capture its output unmodified, for binary or
+    /// non-UTF-8 data. In dry-run mode it is printed and reported as successful
+    pub async fn execute_command_bytes<S: AsRef<str>>(&self, command: S) -> Result<CommandOutput> {
+        if self.is_dry_run() {
+            self.dry_run_note(&format!(
+                "🔍 [dry-run] {} $ {}",
+                self.client,
+                command.as_ref()
+            ));
+            return Ok(CommandOutput::default());
+        }
+
+        let mut channel = self.handler.channel_open_session().await?;
+        channel.exec(true, command.as_ref()).await?;
+
+        wait_output_from_channel(&mut channel).await
+    }
+
+    /// Execute a command with extra environment variables
+    pub async fn execute_command_with_env<S: AsRef<str>>(
+        &self,
+        command: S,
+        env: &HashMap<String, String>,
+    ) -> Result<CommandResult> {
+        let command = env_command(command.as_ref(), env)?;
+        self.execute_command(&command).await
+    }
+
     pub async fn execute_commands<S: AsRef<str>>(
         &self,
         commands: &[S],
@@ -174,16 +660,100 @@ impl Session {
     }
 
     pub async fn execute_with_sudo(&self, command: &str) -> Result<CommandResult> {
+        let result = self.execute_command(self.sudo_command(command)).await?;
+        self.check_sudo(result.exit_status, &result.output)?;
+        Ok(result)
+    }
+
+    /// Execute a command with sudo, streaming its output to `on_output`
+    pub async fn execute_with_sudo_streaming<F: FnMut(&[u8])>(
+        &self,
+        command: &str,
+        on_output: F,
+    ) -> Result<CommandResult> {
+        let result = self
+            .execute_command_streaming(self.sudo_command(command), on_output)
+            .await?;
+        self.check_sudo(result.exit_status, &result.output)?;
+        Ok(result)
+    }
+
+    /// Execute a command with sudo and capture its output unmodified
+    pub async fn execute_with_sudo_bytes(&self, command: &str) -> Result<CommandOutput> {
+        let output = self
+            .execute_command_bytes(self.sudo_command(command))
+            .await?;
+        self.check_sudo(output.exit_status, &String::from_utf8_lossy(&output.stderr))?;
+        Ok(output)
+    }
+
+    /// Execute a read-only command with sudo, it runs even in dry-run mode
+    pub async fn query_with_sudo(&self, command: &str) -> Result<CommandResult> {
+        let result = self.query_command(self.sudo_command(command)).await?;
+        self.check_sudo(result.exit_status, &result.output)?;
+        Ok(result)
+    }
+
+    fn sudo_command(&self, command: &str) -> String {
         // check if current user is root
         if self.current_user() == "root" {
-            self.execute_command(command).await
+            command.to_string()
+        } else if let Some(ref password) = self.client.sudo_password {
+            // feed the password on stdin, an empty prompt keeps it out of the output
+            let quoted_command = shell_words::quote(command);
+            let encoded = general_purpose::STANDARD.encode(format!("{}\n", password));
+            format!(
+                "echo '{}' | base64 -d | sudo -S -p '' {} -c {}",
+                encoded, self.client.shell, quoted_command
+            )
         } else {
+            // -n fails right away instead of waiting on a password prompt
             let quoted_command = shell_words::quote(command);
-            let sudo_command = format!("sudo sh -c {}", quoted_command);
-            self.execute_command(&sudo_command).await
+            format!("sudo -n {} -c {}", self.client.shell, quoted_command)
+        }
+    }
+
+    /// An empty interactive command starts the configured shell
+    fn shell_command<'a>(&'a self, command: &'a str) -> &'a str {
+        if command.trim().is_empty() {
+            &self.client.shell
+        } else {
+            command
         }
     }
 
+    /// Turn sudo's own password failures into a clear error
+    fn check_sudo(&self, exit_status: u32, output: &str) -> Result<()> {
+        if exit_status == 0 || self.current_user() == "root" {
+            return Ok(());
+        }
+
+        if output.contains("a password is required") {
+            return Err(PermanentError::wrap(anyhow!(
+                "sudo on {} requires a password, set sudo_password for this server",
+                self.client
+            )));
+        }
+        if output.contains("incorrect password attempt") || output.contains("Sorry, try again") {
+            return Err(PermanentError::wrap(anyhow!(
+                "sudo password rejected on {}",
+                self.client
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Execute a command with sudo and extra environment variables
+    pub async fn execute_with_sudo_and_env(
+        &self,
+        command: &str,
+        env: &HashMap<String, String>,
+    ) -> Result<CommandResult> {
+        let command = env_command(command, env)?;
+        self.execute_with_sudo(&command).await
+    }
+
     pub async fn interactive(&self, command: &str) -> Result<u32> {
         let mut stdin = tokio::io::stdin();
         let mut stdout = tokio::io::stdout();
@@ -215,7 +785,7 @@ impl Session {
                 &[],
             )
             .await?;
-        channel.exec(true, command).await?;
+        channel.exec(true, self.shell_command(command)).await?;
 
         let code;
         let mut buf = [0u8; 1024];
@@ -282,7 +852,7 @@ impl Session {
                 &[],
             )
             .await?;
-        channel.exec(true, command).await?;
+        channel.exec(true, self.shell_command(command)).await?;
 
         let code;
         let mut input_closed = false;
@@ -327,29 +897,186 @@ impl Session {
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Validate an SSH identification string (RFC 4253 section 4.2)
+pub fn validate_client_id(client_id: &str) -> Result<()> {
+    let version = client_id
+        .strip_prefix("SSH-2.0-")
+        .ok_or_else(|| anyhow!("SSH client id must start with 'SSH-2.0-': {}", client_id))?;
+
+    // CR LF is appended on the wire, the whole line is limited to 255 bytes
+    if client_id.len() > 253 {
+        return Err(anyhow!("SSH client id is too long: {}", client_id));
+    }
+
+    let (software, comments) = match version.split_once(' ') {
+        Some((software, comments)) => (software, Some(comments)),
+        None => (version, None),
+    };
+
+    if software.is_empty() || !software.chars().all(|c| c.is_ascii_graphic() && c != '-') {
+        return Err(anyhow!(
+            "Invalid software version in SSH client id: {}",
+            client_id
+        ));
+    }
+
+    if let Some(comments) = comments {
+        if !comments.chars().all(|c| c.is_ascii_graphic() || c == ' ') {
+            return Err(anyhow!("Invalid comments in SSH client id: {}", client_id));
+        }
+    }
+
+    Ok(())
+}
+
+/// Wrap a command as `env KEY=val ... sh -c <command>` with quoted values
+pub fn env_command(command: &str, env: &HashMap<String, String>) -> Result<String> {
+    if env.is_empty() {
+        return Ok(command.to_string());
+    }
+
+    // sort keys so the generated command line is stable
+    let mut keys = env.keys().collect::<Vec<_>>();
+    keys.sort();
+
+    let mut parts = vec!["env".to_string()];
+    for key in keys {
+        let valid = key
+            .chars()
+            .next()
+            .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+            && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !valid {
+            return Err(anyhow!("Invalid environment variable name: {}", key));
+        }
+        parts.push(shell_words::quote(&format!("{}={}", key, env[key])).into_owned());
+    }
+    parts.push("sh -c".to_string());
+    parts.push(shell_words::quote(command).into_owned());
+
+    Ok(parts.join(" "))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum OsType {
     Debian,
     RedHat,
     Arch,
 }
 
-pub async fn detect_os_type(mut channel: Channel<Msg>) -> Result<OsType> {
+/// Remote distro and kernel, captured when the session connects
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OsInfo {
+    pub family: OsType,
+    /// os-release ID, e.g. "ubuntu"
+    pub id: String,
+    /// os-release VERSION_ID, e.g. "22.04", empty on rolling releases
+    pub version: String,
+    /// `uname -r`
+    pub kernel: String,
+}
+
+/// Only the family is known when OS detection is skipped
+impl From<OsType> for OsInfo {
+    fn from(family: OsType) -> Self {
+        Self {
+            family,
+            id: String::new(),
+            version: String::new(),
+            kernel: String::new(),
+        }
+    }
+}
+
+/// Fields of /etc/os-release used for detection
+#[derive(Debug, Default)]
+struct OsRelease {
+    id_like: String,
+    id: String,
+    version_id: String,
+}
+
+/// Detect the OS with the probe script, falling back to reading
+/// /etc/os-release directly for minimal shells where the script misbehaves
+async fn detect_os(handle: &Handle<Handler>) -> Result<OsInfo> {
+    let channel = handle.channel_open_session().await?;
+    let error = match detect_os_info(channel).await {
+        Ok(os_info) => return Ok(os_info),
+        Err(e) => e,
+    };
+    log::debug!(
+        "OS probe script failed ({}), reading /etc/os-release",
+        error
+    );
+
+    let mut channel = handle.channel_open_session().await?;
+    // the kernel is optional, a missing uname must not fail detection
+    channel
+        .exec(true, "cat /etc/os-release; uname -r 2>/dev/null || true")
+        .await?;
+    let result = wait_result_from_channel(&mut channel).await?;
+    let release = parse_os_release(&result.output);
+    if release.id.is_empty() && release.id_like.is_empty() {
+        return Err(error);
+    }
+
+    Ok(OsInfo {
+        family: classify_os(&release.id_like, &release.id)?,
+        id: release.id,
+        version: release.version_id,
+        kernel: kernel_release(&result.output).to_string(),
+    })
+}
+
+/// Kernel release printed by `uname -r` after the os-release contents,
+/// empty when uname did not print one
+fn kernel_release(output: &str) -> &str {
+    match output.trim_end().lines().last() {
+        Some(line) if !line.contains('=') && !line.trim_start().starts_with('#') => line.trim(),
+        _ => "",
+    }
+}
+
+/// Extract ID_LIKE, ID and VERSION_ID from /etc/os-release contents
+fn parse_os_release(contents: &str) -> OsRelease {
+    let mut release = OsRelease::default();
+
+    for line in contents.lines() {
+        let Some((key, value)) = line.trim().split_once('=') else {
+            continue;
+        };
+        let value = value.trim().trim_matches(|c| c == '"' || c == '\'');
+        match key {
+            "ID" => release.id = value.to_string(),
+            "ID_LIKE" => release.id_like = value.to_string(),
+            "VERSION_ID" => release.version_id = value.to_string(),
+            _ => {}
+        }
+    }
+
+    release
+}
+
+pub async fn detect_os_type(channel: Channel<Msg>) -> Result<OsType> {
+    Ok(detect_os_info(channel).await?.family)
+}
+
+/// Run the probe script, which prints `id_like:id:version_id:kernel`
+pub async fn detect_os_info(mut channel: Channel<Msg>) -> Result<OsInfo> {
     let os_detect_command = r#"
+kernel=$(uname -r 2>/dev/null)
 case "$(uname -s)" in
     Linux)
         if [ -f /etc/os-release ]; then
             os_id=$(grep '^ID=' /etc/os-release | cut -d'=' -f2 | tr -d '"')
             os_id_like=$(grep '^ID_LIKE=' /etc/os-release | cut -d'=' -f2 | tr -d '"')
-            if [ -n "$os_id_like" ]; then
-                echo "$os_id_like:$os_id"
-            else
-                echo ":$os_id"
-            fi
+            version_id=$(grep '^VERSION_ID=' /etc/os-release | cut -d'=' -f2 | tr -d '"')
+            echo "$os_id_like:$os_id:$version_id:$kernel"
         elif [ -f /etc/redhat-release ]; then
-            echo "rhel:rhel"
+            echo "rhel:rhel::$kernel"
         elif [ -f /etc/debian_version ]; then
-            echo "debian:debian"
+            echo "debian:debian:$(cat /etc/debian_version):$kernel"
         else
             exit 1
         fi
@@ -364,12 +1091,20 @@ esac"#;
         return Err(anyhow!("Failed to detect OS type from /etc/os-release"));
     }
 
-    let parts = result.output.trim().split(':').collect::<Vec<&str>>();
-    if parts.len() != 2 {
+    let parts = result.output.trim().splitn(4, ':').collect::<Vec<&str>>();
+    if parts.len() != 4 {
         return Err(anyhow!("Failed to detect OS type from /etc/os-release"));
     }
-    let (os_id_like, os_id) = (parts[0], parts[1]);
+    Ok(OsInfo {
+        family: classify_os(parts[0], parts[1])?,
+        id: parts[1].to_string(),
+        version: parts[2].to_string(),
+        kernel: parts[3].to_string(),
+    })
+}
 
+/// Map os-release ID_LIKE and ID to a distro family
+fn classify_os(os_id_like: &str, os_id: &str) -> Result<OsType> {
     // check id_like and id
     if os_id_like.contains("debian")
         || matches!(
@@ -398,23 +1133,34 @@ esac"#;
 }
 
 pub async fn wait_result_from_channel(channel: &mut Channel<Msg>) -> Result<CommandResult> {
-    let mut result = CommandResult {
-        output: String::new(),
-        exit_status: 0,
-    };
+    wait_result_from_channel_streaming(channel, |_| {}).await
+}
+
+/// Wait for a command to finish, passing stdout and stderr chunks to `on_output`
+/// as they arrive
+pub async fn wait_result_from_channel_streaming<F: FnMut(&[u8])>(
+    channel: &mut Channel<Msg>,
+    mut on_output: F,
+) -> Result<CommandResult> {
+    let mut output = Vec::new();
+    let mut exit_status = 0;
 
     while let Some(data) = channel.wait().await {
         match data {
             russh::ChannelMsg::Data { data } => {
-                result.output.push_str(&String::from_utf8_lossy(&data));
+                on_output(&data);
+                output.extend_from_slice(&data);
             }
             russh::ChannelMsg::ExtendedData { data, ext } => {
                 if ext == 1 {
-                    result.output.push_str(&String::from_utf8_lossy(&data));
+                    on_output(&data);
+                    output.extend_from_slice(&data);
                 }
             }
-            russh::ChannelMsg::ExitStatus { exit_status } => {
-                result.exit_status = exit_status;
+            russh::ChannelMsg::ExitStatus {
+                exit_status: status,
+            } => {
+                exit_status = status;
                 break;
             }
             russh::ChannelMsg::Close => break,
@@ -422,6 +1168,11 @@ pub async fn wait_result_from_channel(channel: &mut Channel<Msg>) -> Result<Comm
         }
     }
 
+    let mut result = CommandResult {
+        output: String::from_utf8_lossy(&output).into_owned(),
+        exit_status,
+    };
+
     // Remove trailing newlines before returning
     if result.output.ends_with("\n") {
         result.output.pop();
@@ -430,6 +1181,36 @@ pub async fn wait_result_from_channel(channel: &mut Channel<Msg>) -> Result<Comm
     Ok(result)
 }
 
+/// Wait for a command to finish, keeping stdout and stderr as raw bytes
+pub async fn wait_output_from_channel(channel: &mut Channel<Msg>) -> Result<CommandOutput> {
+    let mut output = CommandOutput::default();
+
+    while let Some(data) = channel.wait().await {
+        match data {
+            russh::ChannelMsg::Data { data } => output.stdout.extend_from_slice(&data),
+            russh::ChannelMsg::ExtendedData { data, ext: 1 } => {
+                output.stderr.extend_from_slice(&data)
+            }
+            russh::ChannelMsg::ExitStatus { exit_status } => {
+                output.exit_status = exit_status;
+                break;
+            }
+            russh::ChannelMsg::Close => break,
+            _ => {}
+        }
+    }
+
+    Ok(output)
+}
+
+/// Copy data both ways between a local connection and a forwarded channel
+pub(crate) async fn proxy(mut stream: TcpStream, channel: Channel<Msg>) {
+    let mut remote = channel.into_stream();
+    if let Err(e) = tokio::io::copy_bidirectional(&mut stream, &mut remote).await {
+        log::debug!("Forwarded connection closed: {}", e);
+    }
+}
+
 #[derive(Debug)]
 struct Handler {}
 
@@ -443,3 +1224,41 @@ impl client::Handler for Handler {
         async { Ok(true) }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UBUNTU: &str = "PRETTY_NAME=\"Ubuntu 22.04.4 LTS\"\nNAME=\"Ubuntu\"\nVERSION_ID=\"22.04\"\nID=ubuntu\nID_LIKE=debian\n";
+
+    #[test]
+    fn parse_os_release_reads_id_fields() {
+        let release = parse_os_release(UBUNTU);
+        assert_eq!(release.id, "ubuntu");
+        assert_eq!(release.id_like, "debian");
+        assert_eq!(release.version_id, "22.04");
+    }
+
+    #[test]
+    fn kernel_release_is_the_trailing_line() {
+        let output = format!("{}6.8.0-45-generic\n", UBUNTU);
+        assert_eq!(kernel_release(&output), "6.8.0-45-generic");
+        assert_eq!(parse_os_release(&output).id, "ubuntu");
+        // uname missing
+        assert_eq!(kernel_release(UBUNTU), "");
+        assert_eq!(kernel_release(""), "");
+    }
+
+    #[test]
+    fn classify_os_by_id_and_id_like() {
+        assert_eq!(classify_os("debian", "ubuntu").unwrap(), OsType::Debian);
+        assert_eq!(classify_os("", "debian").unwrap(), OsType::Debian);
+        assert_eq!(
+            classify_os("rhel centos fedora", "rocky").unwrap(),
+            OsType::RedHat
+        );
+        assert_eq!(classify_os("", "amzn").unwrap(), OsType::RedHat);
+        assert_eq!(classify_os("arch", "endeavouros").unwrap(), OsType::Arch);
+        assert!(classify_os("", "alpine").is_err());
+    }
+}
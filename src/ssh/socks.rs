@@ -0,0 +1,118 @@
+/// Minimal SOCKS5 server (RFC 1928) for dynamic port forwarding:
+/// every CONNECT request becomes a direct-tcpip channel of the session.
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context, Result};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+use super::{proxy, Session};
+
+const VERSION: u8 = 5;
+const NO_AUTH: u8 = 0;
+const NO_ACCEPTABLE_METHOD: u8 = 0xff;
+const CMD_CONNECT: u8 = 1;
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+
+// reply codes
+const SUCCEEDED: u8 = 0;
+const CONNECTION_REFUSED: u8 = 5;
+const COMMAND_NOT_SUPPORTED: u8 = 7;
+const ADDRESS_NOT_SUPPORTED: u8 = 8;
+
+/// Accept SOCKS5 clients on `local_addr` and connect them through the server.
+/// Runs until the listener fails
+pub async fn serve(session: Arc<Session>, local_addr: &str) -> Result<()> {
+    let listener = TcpListener::bind(local_addr)
+        .await
+        .with_context(|| format!("Failed to listen on {}", local_addr))?;
+    log::info!(
+        "SOCKS5 proxy on {} via {}",
+        listener.local_addr()?,
+        session.client()
+    );
+
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let session = Arc::clone(&session);
+        tokio::spawn(async move {
+            if let Err(e) = handle_client(&session, stream, peer).await {
+                log::warn!("SOCKS5 client {}: {:#}", peer, e);
+            }
+        });
+    }
+}
+
+async fn handle_client(session: &Session, mut stream: TcpStream, peer: SocketAddr) -> Result<()> {
+    // greeting: version, number of methods, methods
+    let mut header = [0u8; 2];
+    stream.read_exact(&mut header).await?;
+    if header[0] != VERSION {
+        return Err(anyhow!("unsupported SOCKS version {}", header[0]));
+    }
+    let mut methods = vec![0u8; header[1] as usize];
+    stream.read_exact(&mut methods).await?;
+    if !methods.contains(&NO_AUTH) {
+        stream.write_all(&[VERSION, NO_ACCEPTABLE_METHOD]).await?;
+        return Err(anyhow!("client does not offer unauthenticated access"));
+    }
+    stream.write_all(&[VERSION, NO_AUTH]).await?;
+
+    // request: version, command, reserved, address type, address, port
+    let mut request = [0u8; 4];
+    stream.read_exact(&mut request).await?;
+    if request[1] != CMD_CONNECT {
+        reply(&mut stream, COMMAND_NOT_SUPPORTED).await?;
+        return Err(anyhow!("unsupported SOCKS command {}", request[1]));
+    }
+
+    let host = match request[3] {
+        ATYP_IPV4 => {
+            let mut addr = [0u8; 4];
+            stream.read_exact(&mut addr).await?;
+            Ipv4Addr::from(addr).to_string()
+        }
+        ATYP_DOMAIN => {
+            let len = stream.read_u8().await? as usize;
+            let mut name = vec![0u8; len];
+            stream.read_exact(&mut name).await?;
+            String::from_utf8(name).context("domain name is not UTF-8")?
+        }
+        ATYP_IPV6 => {
+            let mut addr = [0u8; 16];
+            stream.read_exact(&mut addr).await?;
+            Ipv6Addr::from(addr).to_string()
+        }
+        atyp => {
+            reply(&mut stream, ADDRESS_NOT_SUPPORTED).await?;
+            return Err(anyhow!("unsupported address type {}", atyp));
+        }
+    };
+    let port = stream.read_u16().await?;
+
+    let channel = match session.open_direct_tcpip(&host, port, peer).await {
+        Ok(channel) => channel,
+        Err(e) => {
+            reply(&mut stream, CONNECTION_REFUSED).await?;
+            return Err(e);
+        }
+    };
+    reply(&mut stream, SUCCEEDED).await?;
+    log::debug!("SOCKS5 {} connected to {}:{}", peer, host, port);
+
+    proxy(stream, channel).await;
+    Ok(())
+}
+
+// The bound address is not meaningful for a tunnel, clients ignore it
+async fn reply(stream: &mut TcpStream, code: u8) -> Result<()> {
+    stream
+        .write_all(&[VERSION, code, 0, ATYP_IPV4, 0, 0, 0, 0, 0, 0])
+        .await?;
+    Ok(())
+}
@@ -0,0 +1,284 @@
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, Result};
+
+use crate::config::{SftpOnlyConfig, SshdConfig};
+
+/// sshd configuration file managed by biusrv
+pub const CONFIG_FILE: &str = "/etc/ssh/sshd_config.d/biusrv.conf";
+
+/// Options applied by `hardened = true`, the configured `options` override them
+pub const HARDENED_DEFAULTS: &[(&str, &str)] = &[
+    ("ClientAliveCountMax", "2"),
+    ("ClientAliveInterval", "300"),
+    ("KbdInteractiveAuthentication", "no"),
+    ("LoginGraceTime", "30"),
+    ("MaxAuthTries", "3"),
+    ("PasswordAuthentication", "no"),
+    ("PermitEmptyPasswords", "no"),
+    ("PermitRootLogin", "no"),
+    ("PubkeyAuthentication", "yes"),
+    ("X11Forwarding", "no"),
+];
+
+// keywords of sshd_config(5), OpenSSH 9.x
+const DIRECTIVES: &[&str] = &[
+    "AcceptEnv",
+    "AddressFamily",
+    "AllowAgentForwarding",
+    "AllowGroups",
+    "AllowStreamLocalForwarding",
+    "AllowTcpForwarding",
+    "AllowUsers",
+    "AuthenticationMethods",
+    "AuthorizedKeysCommand",
+    "AuthorizedKeysCommandUser",
+    "AuthorizedKeysFile",
+    "AuthorizedPrincipalsCommand",
+    "AuthorizedPrincipalsCommandUser",
+    "AuthorizedPrincipalsFile",
+    "Banner",
+    "CASignatureAlgorithms",
+    "ChannelTimeout",
+    "ChallengeResponseAuthentication",
+    "ChrootDirectory",
+    "Ciphers",
+    "ClientAliveCountMax",
+    "ClientAliveInterval",
+    "Compression",
+    "DenyGroups",
+    "DenyUsers",
+    "DisableForwarding",
+    "ExposeAuthInfo",
+    "FingerprintHash",
+    "ForceCommand",
+    "GatewayPorts",
+    "GSSAPIAuthentication",
+    "GSSAPICleanupCredentials",
+    "GSSAPIKeyExchange",
+    "GSSAPIStrictAcceptorCheck",
+    "GSSAPIStoreCredentialsOnRekey",
+    "HostbasedAcceptedAlgorithms",
+    "HostbasedAuthentication",
+    "HostbasedUsesNameFromPacketOnly",
+    "HostCertificate",
+    "HostKey",
+    "HostKeyAgent",
+    "HostKeyAlgorithms",
+    "IgnoreRhosts",
+    "IgnoreUserKnownHosts",
+    "Include",
+    "IPQoS",
+    "KbdInteractiveAuthentication",
+    "KerberosAuthentication",
+    "KerberosGetAFSToken",
+    "KerberosOrLocalPasswd",
+    "KerberosTicketCleanup",
+    "KexAlgorithms",
+    "ListenAddress",
+    "LoginGraceTime",
+    "LogLevel",
+    "LogVerbose",
+    "MACs",
+    "MaxAuthTries",
+    "MaxSessions",
+    "MaxStartups",
+    "ModuliFile",
+    "PasswordAuthentication",
+    "PermitEmptyPasswords",
+    "PermitListen",
+    "PermitOpen",
+    "PermitRootLogin",
+    "PermitTTY",
+    "PermitTunnel",
+    "PermitUserEnvironment",
+    "PermitUserRC",
+    "PerSourceMaxStartups",
+    "PerSourceNetBlockSize",
+    "PerSourcePenalties",
+    "PerSourcePenaltyExemptList",
+    "PidFile",
+    "Port",
+    "PrintLastLog",
+    "PrintMotd",
+    "PubkeyAcceptedAlgorithms",
+    "PubkeyAuthOptions",
+    "PubkeyAuthentication",
+    "RekeyLimit",
+    "RequiredRSASize",
+    "RevokedKeys",
+    "RDomain",
+    "SecurityKeyProvider",
+    "SetEnv",
+    "StreamLocalBindMask",
+    "StreamLocalBindUnlink",
+    "StrictModes",
+    "Subsystem",
+    "SyslogFacility",
+    "TCPKeepAlive",
+    "TrustedUserCAKeys",
+    "UnusedConnectionTimeout",
+    "UseDNS",
+    "UsePAM",
+    "VersionAddendum",
+    "X11DisplayOffset",
+    "X11Forwarding",
+    "X11UseLocalhost",
+    "XAuthLocation",
+];
+
+/// The directive as spelled in sshd_config(5), None if sshd does not know it
+pub fn canonical_directive(key: &str) -> Option<&'static str> {
+    DIRECTIVES
+        .iter()
+        .find(|directive| directive.eq_ignore_ascii_case(key))
+        .copied()
+}
+
+/// Spell boolean values the way sshd expects them, other values are kept as they are
+pub fn normalize_value(value: &str) -> String {
+    let value = value.trim();
+    match value.to_ascii_lowercase().as_str() {
+        "yes" | "true" | "on" => "yes".to_string(),
+        "no" | "false" | "off" => "no".to_string(),
+        _ => value.to_string(),
+    }
+}
+
+/// Options to write, hardened defaults first if enabled, sorted by directive.
+/// Known directives get their canonical spelling, unknown ones are kept verbatim
+pub fn options(config: &SshdConfig) -> Vec<(String, String)> {
+    // keyed case-insensitively so `permitrootlogin` overrides `PermitRootLogin`
+    let mut options: BTreeMap<String, (String, String)> = BTreeMap::new();
+    if config.hardened.unwrap_or(false) {
+        for (key, value) in HARDENED_DEFAULTS {
+            options.insert(
+                key.to_ascii_lowercase(),
+                (key.to_string(), value.to_string()),
+            );
+        }
+    }
+    for (key, value) in config.options.iter().flatten() {
+        let key = key.trim();
+        let name = canonical_directive(key).unwrap_or(key);
+        options.insert(
+            key.to_ascii_lowercase(),
+            (name.to_string(), normalize_value(value)),
+        );
+    }
+
+    let mut options: Vec<_> = options.into_values().collect();
+    options.sort();
+    options
+}
+
+/// Warnings for configured options sshd does not know, with the closest directive if any
+pub fn check_options(config: &SshdConfig) -> Vec<String> {
+    let mut warnings = vec![];
+    let mut keys: Vec<_> = config.options.iter().flatten().map(|(k, _)| k).collect();
+    keys.sort();
+    for key in keys {
+        if canonical_directive(key.trim()).is_some() {
+            continue;
+        }
+        match closest_directive(key.trim()) {
+            Some(directive) => warnings.push(format!(
+                "Unknown sshd option '{}', did you mean '{}'?",
+                key, directive
+            )),
+            None => warnings.push(format!(
+                "Unknown sshd option '{}', sshd may reject the config",
+                key
+            )),
+        }
+    }
+    warnings
+}
+
+/// Check an SFTP-only entry before it is put into the config and shell commands
+pub fn validate_sftp_only(entry: &SftpOnlyConfig) -> Result<()> {
+    let unsafe_char = |c: char| c.is_whitespace() || ";&|$`'\"\\*?".contains(c);
+    if entry.user.is_empty() || entry.user.contains(unsafe_char) {
+        return Err(anyhow!("Invalid SFTP-only user: '{}'", entry.user));
+    }
+    if !entry.chroot.starts_with('/') || entry.chroot == "/" || entry.chroot.contains(unsafe_char) {
+        return Err(anyhow!(
+            "Invalid chroot for SFTP-only user '{}': '{}', expected an absolute directory",
+            entry.user,
+            entry.chroot
+        ));
+    }
+    if let Some(ref writable) = entry.writable {
+        if writable.is_empty()
+            || writable.starts_with('/')
+            || writable.split('/').any(|part| part == "..")
+            || writable.contains(unsafe_char)
+        {
+            return Err(anyhow!(
+                "Invalid writable directory for SFTP-only user '{}': '{}', expected a path inside the chroot",
+                entry.user,
+                writable
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// `Match User` block confining the user to SFTP inside the chroot.
+/// sshd ends a Match block at the end of an included file, so it goes last
+pub fn sftp_only_block(entry: &SftpOnlyConfig) -> String {
+    format!(
+        "Match User {}\n    ChrootDirectory {}\n    ForceCommand internal-sftp\n    AllowTcpForwarding no\n    X11Forwarding no\n",
+        entry.user, entry.chroot
+    )
+}
+
+/// Shell command giving the chroot the ownership sshd requires (root owned,
+/// not group or world writable) and the writable directory to the user.
+/// Prints `changed` if anything had to be fixed
+pub fn sftp_only_command(entry: &SftpOnlyConfig) -> String {
+    let mut command = format!(
+        "if [ \"$(stat -c %U:%G:%a {dir} 2>/dev/null)\" != root:root:755 ]; then \
+         mkdir -p {dir} && chown root:root {dir} && chmod 755 {dir} && echo changed; fi",
+        dir = entry.chroot
+    );
+    if let Some(ref writable) = entry.writable {
+        command.push_str(&format!(
+            " && if [ \"$(stat -c %U {dir} 2>/dev/null)\" != {user} ]; then \
+             mkdir -p {dir} && chown {user}: {dir} && chmod 755 {dir} && echo changed; fi",
+            dir = format!("{}/{}", entry.chroot.trim_end_matches('/'), writable),
+            user = entry.user
+        ));
+    }
+    command
+}
+
+// a directive within a few typos of the key
+fn closest_directive(key: &str) -> Option<&'static str> {
+    let key = key.to_ascii_lowercase();
+    DIRECTIVES
+        .iter()
+        .map(|directive| {
+            (
+                edit_distance(&key, &directive.to_ascii_lowercase()),
+                *directive,
+            )
+        })
+        .filter(|(distance, _)| *distance <= 3)
+        .min()
+        .map(|(_, directive)| directive)
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != *cb);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
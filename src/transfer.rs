@@ -1,16 +1,57 @@
 /// SFTP related functionality.
-use std::{collections::VecDeque, io::SeekFrom, time::Instant};
-
-use anyhow::{anyhow, Result};
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{ErrorKind, SeekFrom},
+    path::Path,
+    time::{Instant, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Context, Result};
 use russh_sftp::{client::SftpSession, protocol::OpenFlags};
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
-
-use crate::retry_operation;
+use serde::Serialize;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWrite, AsyncWriteExt},
+    sync::mpsc,
+    task::JoinSet,
+};
+
+use crate::{retry_operation, ssh::Session, utils::glob_match};
+
+/// Concurrent reads of a download, each on its own handle
+const PIPELINE_DEPTH: usize = 4;
+
+/// Exclude globs read from the root of an uploaded directory
+pub const IGNORE_FILE: &str = ".biusrvignore";
+
+// Files hashed per remote sha256sum call, keeps the command line short
+const MANIFEST_BATCH: usize = 256;
+
+/// Read up to `len` bytes at `offset`, fewer only at the end of the file
+async fn read_chunk<R>(file: &mut R, offset: u64, len: usize) -> std::io::Result<Vec<u8>>
+where
+    R: AsyncRead + AsyncSeek + Unpin,
+{
+    file.seek(SeekFrom::Start(offset)).await?;
+    let mut buffer = vec![0u8; len];
+    let mut filled = 0;
+    while filled < len {
+        let bytes_read = file.read(&mut buffer[filled..]).await?;
+        if bytes_read == 0 {
+            break;
+        }
+        filled += bytes_read;
+    }
+    buffer.truncate(filled);
+    Ok(buffer)
+}
 
 #[derive(Debug)]
 pub struct TransferConfig {
     pub force: bool,
     pub resume: bool,
+    /// Refuse to overwrite a remote file newer than the local source on upload.
+    /// Checked before `force` and `resume`, so it wins over both.
+    pub no_clobber_newer: bool,
     pub max_retry: u32,
     pub chunk_size: usize,
     pub progress_interval: f64,
@@ -21,6 +62,7 @@ impl Default for TransferConfig {
         Self {
             force: false,
             resume: false,
+            no_clobber_newer: false,
             max_retry: 0,
             chunk_size: 64 * 1024,
             progress_interval: 1.0,
@@ -81,6 +123,91 @@ impl TransferSession {
         &self.session
     }
 
+    /// Read a remote file fully into memory, without touching the local filesystem
+    pub async fn read_remote_bytes(&self, remote_path: &str) -> Result<Vec<u8>> {
+        let mut remote_file = self.session.open(remote_path).await?;
+        let mut contents = Vec::new();
+        remote_file.read_to_end(&mut contents).await?;
+        Ok(contents)
+    }
+
+    /// Read a remote text file fully into memory
+    pub async fn read_remote_to_string(&self, remote_path: &str) -> Result<String> {
+        let contents = self.read_remote_bytes(remote_path).await?;
+        String::from_utf8(contents)
+            .map_err(|_| anyhow!("Remote file '{}' is not valid UTF-8", remote_path))
+    }
+
+    /// Remove a remote directory tree bottom-up, returns the number of removed entries.
+    /// A symlink is removed itself and never followed; `/` and the home directory are refused.
+    pub async fn remove_dir_all(&self, remote_dir: &str) -> Result<u64> {
+        let metadata = self.session.symlink_metadata(remote_dir).await?;
+        if metadata.is_symlink() {
+            self.session.remove_file(remote_dir).await?;
+            return Ok(1);
+        }
+        if !metadata.is_dir() {
+            return Err(anyhow!("Remote path '{remote_dir}' is not a directory"));
+        }
+
+        let canonical = self.session.canonicalize(remote_dir).await?;
+        let home = self.session.canonicalize(".").await?;
+        if canonical == "/" || canonical == home {
+            return Err(anyhow!(
+                "Refusing to remove remote directory '{remote_dir}' ({canonical})"
+            ));
+        }
+
+        let mut removed = 0;
+        let dir_files = read_remote_dir(&self.session, &canonical).await?;
+
+        // directories are listed parents first, so walk them in reverse
+        for dir_file in dir_files.iter().rev() {
+            for path in dir_file.files.iter().chain(dir_file.symlinks.iter()) {
+                self.session.remove_file(path).await?;
+                removed += 1;
+            }
+            self.session.remove_dir(&dir_file.path).await?;
+            removed += 1;
+        }
+
+        Ok(removed)
+    }
+
+    /// Build a manifest of the regular files under a remote directory,
+    /// hashed with remote `sha256sum` in batches per directory.
+    pub async fn manifest(&self, ssh: &Session, remote_dir: &str) -> Result<Vec<FileEntry>> {
+        let remote_dir = remote_dir.trim_end_matches('/');
+        let dir_files = read_remote_dir(&self.session, remote_dir).await?;
+
+        let mut entries = vec![];
+        for dir_file in dir_files.iter() {
+            let mut hashes = HashMap::new();
+            for batch in dir_file.files.chunks(MANIFEST_BATCH) {
+                let command = format!("sha256sum -- {} 2>/dev/null", shell_words::join(batch));
+                let result = ssh.query_command(command).await?;
+                hashes.extend(parse_sha256sum(&result.output));
+            }
+
+            for path in dir_file.files.iter() {
+                let metadata = self.session.metadata(path).await?;
+                entries.push(FileEntry {
+                    path: path
+                        .strip_prefix(remote_dir)
+                        .unwrap_or(path)
+                        .trim_start_matches('/')
+                        .to_string(),
+                    size: metadata.len(),
+                    mode: format!("{:04o}", metadata.permissions.unwrap_or(0) & 0o7777),
+                    sha256: hashes.remove(path),
+                });
+            }
+        }
+
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(entries)
+    }
+
     pub async fn upload(&self, local_path: &str, remote_path: &str) -> Result<u64> {
         self.upload_with_callback(local_path, remote_path, no_callback)
             .await
@@ -101,6 +228,22 @@ impl TransferSession {
             .await
     }
 
+    pub async fn upload_reader<R>(&self, reader: &mut R, remote_path: &str) -> Result<u64>
+    where
+        R: AsyncRead + Unpin,
+    {
+        self.upload_reader_with_callback(reader, remote_path, no_callback)
+            .await
+    }
+
+    pub async fn download_to_writer<W>(&self, remote_path: &str, writer: &mut W) -> Result<u64>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        self.download_to_writer_with_callback(remote_path, writer, no_callback)
+            .await
+    }
+
     pub async fn upload_dir(&self, local_dir: &str, remote_dir: &str) -> Result<u64> {
         self.upload_dir_with_callback(local_dir, remote_dir, no_callback)
             .await
@@ -163,7 +306,8 @@ impl TransferSession {
         C: Fn(&TransferProgress),
     {
         let mut local_file = tokio::fs::File::open(local_path).await?;
-        let local_size = local_file.metadata().await?.len();
+        let local_metadata = local_file.metadata().await?;
+        let local_size = local_metadata.len();
 
         let metadata = if let Ok(meta) = self.session.metadata(remote_path).await {
             if !meta.is_regular() {
@@ -174,6 +318,21 @@ impl TransferSession {
             None
         };
 
+        // never stomp a newer remote file, even in force or resume mode
+        if self.config.no_clobber_newer {
+            if let Some(remote_mtime) = metadata.as_ref().and_then(|meta| meta.mtime) {
+                let local_mtime = local_metadata
+                    .modified()?
+                    .duration_since(UNIX_EPOCH)?
+                    .as_secs();
+                if u64::from(remote_mtime) > local_mtime {
+                    return Err(anyhow!(
+                        "Remote file '{remote_path}' is newer than local file '{local_path}'"
+                    ));
+                }
+            }
+        }
+
         let (mut remote_file, remote_size) = match (self.config.force, self.config.resume) {
             (true, _) => (self.session.create(remote_path).await?, 0),
             (false, false) => {
@@ -226,7 +385,7 @@ impl TransferSession {
     where
         C: Fn(&TransferProgress),
     {
-        let mut remote_file = self.session.open(remote_path).await?;
+        let remote_file = self.session.open(remote_path).await?;
         let metadata = remote_file.metadata().await?;
         if !metadata.is_regular() {
             return Err(anyhow!("Remote path '{remote_path}' exists but not file"));
@@ -260,7 +419,6 @@ impl TransferSession {
                 } else if local_size > remote_size {
                     return Err(anyhow!("Local file is larger than remote file"));
                 } else {
-                    remote_file.seek(SeekFrom::Start(local_size)).await?;
                     local_file.seek(SeekFrom::Start(local_size)).await?;
                     (local_file, local_size)
                 }
@@ -274,7 +432,59 @@ impl TransferSession {
             remote_path.to_string(),
         );
 
-        self.copy_file_with_callback(&mut remote_file, &mut local_file, progress, callback)
+        self.download_pipelined(remote_path, &mut local_file, progress, callback)
+            .await
+    }
+
+    /// Upload from any reader, e.g. stdin, to a remote file.
+    /// The size is not known up front, so resume and the newer check do not apply.
+    pub async fn upload_reader_with_callback<R, C>(
+        &self,
+        reader: &mut R,
+        remote_path: &str,
+        callback: C,
+    ) -> Result<u64>
+    where
+        R: AsyncRead + Unpin,
+        C: Fn(&TransferProgress),
+    {
+        if let Ok(meta) = self.session.metadata(remote_path).await {
+            if !meta.is_regular() {
+                return Err(anyhow!("Remote path '{remote_path}' exists but not file"));
+            }
+            if !self.config.force {
+                return Err(anyhow!("Remote file already exists"));
+            }
+        }
+
+        let mut remote_file = self.session.create(remote_path).await?;
+        let progress = TransferProgress::new(0, 0, "-".to_string(), remote_path.to_string());
+
+        self.copy_file_with_callback(reader, &mut remote_file, progress, callback)
+            .await
+    }
+
+    /// Download a remote file into any writer, e.g. stdout.
+    pub async fn download_to_writer_with_callback<W, C>(
+        &self,
+        remote_path: &str,
+        writer: &mut W,
+        callback: C,
+    ) -> Result<u64>
+    where
+        W: AsyncWrite + Unpin,
+        C: Fn(&TransferProgress),
+    {
+        let remote_file = self.session.open(remote_path).await?;
+        let metadata = remote_file.metadata().await?;
+        if !metadata.is_regular() {
+            return Err(anyhow!("Remote path '{remote_path}' exists but not file"));
+        }
+
+        let progress =
+            TransferProgress::new(metadata.len(), 0, "-".to_string(), remote_path.to_string());
+
+        self.download_pipelined(remote_path, writer, progress, callback)
             .await
     }
 
@@ -294,7 +504,6 @@ impl TransferSession {
 
         let mut last_time = progress.start_time;
         let mut done_bytes = progress.done_bytes;
-
         let mut buffer = vec![0u8; self.config.chunk_size];
         loop {
             let bytes_read =
@@ -318,11 +527,89 @@ impl TransferSession {
             }
         }
 
+        write_file.flush().await?;
+
         progress.update(done_bytes, Instant::now());
         callback(&progress);
 
+        Ok(done_bytes)
+    }
+
+    /// Download `remote_path` from `progress.done_bytes` on, with PIPELINE_DEPTH
+    /// reads in flight: each reader has its own handle and takes every
+    /// PIPELINE_DEPTH-th chunk, the writer collects the chunks in turn
+    async fn download_pipelined<W, C>(
+        &self,
+        remote_path: &str,
+        write_file: &mut W,
+        mut progress: TransferProgress,
+        callback: C,
+    ) -> Result<u64>
+    where
+        W: AsyncWrite + Unpin,
+        C: Fn(&TransferProgress),
+    {
+        progress.start_time = Instant::now();
+
+        let mut last_time = progress.start_time;
+        let mut done_bytes = progress.done_bytes;
+        let max_retry = self.config.max_retry;
+        let chunk_size = self.config.chunk_size;
+
+        let mut readers = JoinSet::new();
+        let mut chunks = Vec::with_capacity(PIPELINE_DEPTH);
+        for slot in 0..PIPELINE_DEPTH {
+            let mut file = self.session.open(remote_path).await?;
+            let (sender, receiver) = mpsc::channel::<Result<Vec<u8>>>(1);
+            chunks.push(receiver);
+
+            let start = done_bytes;
+            readers.spawn(async move {
+                for index in (slot..).step_by(PIPELINE_DEPTH) {
+                    let offset = start + (index * chunk_size) as u64;
+                    let chunk = retry_operation!(
+                        max_retry,
+                        read_chunk(&mut file, offset, chunk_size).await
+                    )
+                    .map_err(anyhow::Error::from);
+                    let last = chunk
+                        .as_ref()
+                        .map_or(true, |chunk| chunk.len() < chunk_size);
+                    // the writer is gone after the end of the file or an error
+                    if sender.send(chunk).await.is_err() || last {
+                        break;
+                    }
+                }
+            });
+        }
+
+        for index in 0.. {
+            let chunk = match chunks[index % PIPELINE_DEPTH].recv().await {
+                Some(chunk) => chunk?,
+                None => return Err(anyhow!("Reading '{}' stopped unexpectedly", remote_path)),
+            };
+            retry_operation!(max_retry, write_file.write_all(&chunk).await)?;
+            done_bytes += chunk.len() as u64;
+
+            // Update progress periodically (at most once per second)
+            let now = Instant::now();
+            if now.duration_since(last_time).as_secs_f64() >= self.config.progress_interval {
+                progress.update(done_bytes, now);
+                callback(&progress);
+                last_time = now;
+            }
+
+            if chunk.len() < chunk_size {
+                break;
+            }
+        }
+        readers.shutdown().await;
+
         write_file.flush().await?;
 
+        progress.update(done_bytes, Instant::now());
+        callback(&progress);
+
         Ok(done_bytes)
     }
 
@@ -352,7 +639,8 @@ impl TransferSession {
             remote_dir
         };
 
-        let dir_files = read_local_dir(local_dir).await?;
+        let ignore = IgnoreRules::load(local_dir).await?;
+        let dir_files = read_local_dir_with_ignore(local_dir, &ignore).await?;
 
         // create remote dir first
         for dir_file in dir_files.iter() {
@@ -480,6 +768,11 @@ pub fn no_callback(_: &TransferProgress) {}
 
 // Read local directory
 pub async fn read_local_dir(path: &str) -> Result<Vec<DirFile>> {
+    read_local_dir_with_ignore(path, &IgnoreRules::default()).await
+}
+
+/// Read local directory, skipping ignored entries and pruning ignored directories
+pub async fn read_local_dir_with_ignore(path: &str, ignore: &IgnoreRules) -> Result<Vec<DirFile>> {
     let mut dir_files = vec![];
     let mut queue = VecDeque::new();
 
@@ -496,6 +789,15 @@ pub async fn read_local_dir(path: &str) -> Result<Vec<DirFile>> {
 
             let file_type = entry.file_type().await?;
 
+            let relative = entry_path
+                .strip_prefix(path)
+                .unwrap_or(&entry_path)
+                .trim_start_matches(['/', '\\'])
+                .replace('\\', "/");
+            if ignore.is_ignored(&relative, file_type.is_dir()) {
+                continue;
+            }
+
             if file_type.is_dir() {
                 queue.push_back(DirFile::new(entry_path));
             } else if file_type.is_file() {
@@ -539,6 +841,99 @@ pub async fn read_remote_dir(session: &SftpSession, path: &str) -> Result<Vec<Di
     Ok(dir_files)
 }
 
+/// Exclude patterns of a directory upload in `.gitignore` style: a pattern with a `/`
+/// matches the path relative to the root, otherwise the entry name, and a trailing `/`
+/// only matches directories
+#[derive(Debug, Clone, Default)]
+pub struct IgnoreRules {
+    // pattern and whether it only matches directories
+    patterns: Vec<(String, bool)>,
+}
+
+impl IgnoreRules {
+    /// Load the ignore file at the root of a local directory, empty if there is none
+    pub async fn load(root: &str) -> Result<Self> {
+        let path = Path::new(root).join(IGNORE_FILE);
+        match tokio::fs::read_to_string(&path).await {
+            Ok(contents) => Ok(Self::parse(&contents)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        }
+    }
+
+    /// Parse one pattern per line, blank lines and `#` comments are skipped
+    pub fn parse(contents: &str) -> Self {
+        let patterns = contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| {
+                let dir_only = line.ends_with('/');
+                // a leading `/` is kept to root the pattern
+                let pattern = line.trim_end_matches('/').to_string();
+                (pattern, dir_only)
+            })
+            .filter(|(pattern, _)| !pattern.trim_start_matches('/').is_empty())
+            .collect();
+
+        Self { patterns }
+    }
+
+    /// Check a path relative to the root, using `/` as separator
+    pub fn is_ignored(&self, relative_path: &str, is_dir: bool) -> bool {
+        let name = relative_path.rsplit('/').next().unwrap_or(relative_path);
+        self.patterns.iter().any(|(pattern, dir_only)| {
+            if *dir_only && !is_dir {
+                return false;
+            }
+            if pattern.contains('/') {
+                glob_match(pattern.trim_start_matches('/'), relative_path)
+            } else {
+                glob_match(pattern, name)
+            }
+        })
+    }
+}
+
+/// Parse `sha256sum` output into path -> hash, unescaping names that sha256sum escaped
+fn parse_sha256sum(output: &str) -> HashMap<String, String> {
+    let mut hashes = HashMap::new();
+    for line in output.lines() {
+        let (escaped, line) = match line.strip_prefix('\\') {
+            Some(line) => (true, line),
+            None => (false, line),
+        };
+        let Some((hash, path)) = line.split_once("  ") else {
+            continue;
+        };
+        if hash.len() != 64 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+            continue;
+        }
+
+        let path = if escaped {
+            let mut unescaped = String::with_capacity(path.len());
+            let mut chars = path.chars();
+            while let Some(c) = chars.next() {
+                if c != '\\' {
+                    unescaped.push(c);
+                    continue;
+                }
+                match chars.next() {
+                    Some('n') => unescaped.push('\n'),
+                    Some('r') => unescaped.push('\r'),
+                    Some(other) => unescaped.push(other),
+                    None => unescaped.push('\\'),
+                }
+            }
+            unescaped
+        } else {
+            path.to_string()
+        };
+        hashes.insert(path, hash.to_string());
+    }
+    hashes
+}
+
 fn to_remote_path(path: String) -> String {
     if cfg!(target_os = "windows") {
         path.replace("\\", "/")
@@ -573,6 +968,18 @@ fn replace_to_local_path(path: &str, local_dir: &str, remote_dir: &str) -> Strin
     to_local_path(path)
 }
 
+/// A regular file in a remote manifest
+#[derive(Debug, Clone, Serialize)]
+pub struct FileEntry {
+    // path relative to the manifest root
+    pub path: String,
+    pub size: u64,
+    // permission bits in octal, e.g. "0644"
+    pub mode: String,
+    // None when the file could not be read
+    pub sha256: Option<String>,
+}
+
 #[derive(Debug, Clone)]
 pub struct DirFile {
     pub path: String,
@@ -597,3 +1004,61 @@ impl DirFile {
         self.symlinks.push(symlink);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+    #[test]
+    fn ignore_dir_only_patterns_skip_files() {
+        let rules = IgnoreRules::parse("# build output\n\nbuild/\n");
+        assert!(rules.is_ignored("build", true));
+        assert!(rules.is_ignored("src/build", true));
+        assert!(!rules.is_ignored("build", false));
+    }
+
+    #[test]
+    fn ignore_patterns_with_a_slash_match_from_the_root() {
+        let rules = IgnoreRules::parse("a/b\ndocs/*.tmp\n/target\n");
+        assert!(rules.is_ignored("a/b", false));
+        assert!(!rules.is_ignored("x/a/b", false));
+        assert!(rules.is_ignored("docs/x.tmp", false));
+        assert!(!rules.is_ignored("src/docs/x.tmp", false));
+        assert!(rules.is_ignored("target", true));
+        assert!(!rules.is_ignored("crates/target", true));
+    }
+
+    #[test]
+    fn ignore_name_patterns_match_at_any_depth() {
+        let rules = IgnoreRules::parse("*.log\n");
+        assert!(rules.is_ignored("app.log", false));
+        assert!(rules.is_ignored("var/log/app.log", false));
+        assert!(!rules.is_ignored("app.log.gz", false));
+    }
+
+    #[test]
+    fn sha256sum_reads_plain_lines() {
+        let output =
+            format!("{HASH}  /srv/app/a.txt\n{HASH}  /srv/app/with  spaces\nnot a hash  x\n");
+        let hashes = parse_sha256sum(&output);
+        assert_eq!(hashes.len(), 2);
+        assert_eq!(hashes["/srv/app/a.txt"], HASH);
+        assert_eq!(hashes["/srv/app/with  spaces"], HASH);
+    }
+
+    #[test]
+    fn sha256sum_unescapes_newlines() {
+        let output = format!("\\{HASH}  /srv/app/two\\nlines\n");
+        let hashes = parse_sha256sum(&output);
+        assert_eq!(hashes["/srv/app/two\nlines"], HASH);
+    }
+
+    #[test]
+    fn sha256sum_unescapes_backslashes() {
+        let output = format!("\\{HASH}  /srv/app/back\\\\slash\n");
+        let hashes = parse_sha256sum(&output);
+        assert_eq!(hashes["/srv/app/back\\slash"], HASH);
+    }
+}
@@ -1,7 +1,27 @@
-use crate::ssh::{CommandResult, OsType, Session};
+use std::{sync::OnceLock, time::Duration};
+
+use crate::{
+    config::UpdateMode,
+    retry_operation,
+    ssh::{CommandResult, ConnectError, OsType, Session},
+};
 use anyhow::Result;
 use base64::{engine::general_purpose, Engine as _};
+use rand::Rng;
+use serde::Serialize;
+
+static RETRY_POLICY: OnceLock<RetryPolicy> = OnceLock::new();
+
+// keep existing config files when a package upgrade ships a new version
+const DPKG_OPTIONS: &str =
+    r#"-o Dpkg::Options::="--force-confdef" -o Dpkg::Options::="--force-confold""#;
+
+// base64 characters per command line, well below ARG_MAX even when the
+// command is quoted again for sudo; a multiple of 4 so chunks decode independently
+const CREATE_FILE_CHUNK: usize = 64 * 1024;
 
+/// Write `content` to `path` as root. Large content is appended in chunks
+/// to a temporary file which then replaces `path`
 pub async fn create_file(
     session: &Session,
     path: &str,
@@ -9,15 +29,149 @@ pub async fn create_file(
     mode: Option<&str>,
 ) -> Result<CommandResult> {
     let encoded = general_purpose::STANDARD.encode(content.as_bytes());
-    let command = if let Some(mode) = mode {
-        format!(
-            "echo '{}' | base64 -d > {} && chmod {} {}",
-            encoded, path, mode, path
-        )
+    let chmod = mode
+        .map(|mode| format!(" && chmod {} {}", mode, path))
+        .unwrap_or_default();
+
+    if encoded.len() <= CREATE_FILE_CHUNK {
+        let command = format!("echo '{}' | base64 -d > {}{}", encoded, path, chmod);
+        return session.execute_with_sudo(&command).await;
+    }
+
+    let part = format!("{}.biusrv-part", path);
+    for (i, chunk) in encoded.as_bytes().chunks(CREATE_FILE_CHUNK).enumerate() {
+        let redirect = if i == 0 { ">" } else { ">>" };
+        let command = format!(
+            "echo '{}' | base64 -d {} {}",
+            String::from_utf8_lossy(chunk),
+            redirect,
+            part
+        );
+        let result = session.execute_with_sudo(&command).await?;
+        if result.exit_status != 0 {
+            session
+                .execute_with_sudo(&format!("rm -f {}", part))
+                .await?;
+            return Ok(result);
+        }
+    }
+
+    session
+        .execute_with_sudo(&format!("mv -f {} {}{}", part, path, chmod))
+        .await
+}
+
+/// Suffix of the copy [`create_file_with_backup`] keeps of a replaced file
+pub const BACKUP_SUFFIX: &str = ".biusrv.bak";
+
+/// Like [`create_file`], but first copies an existing `path` to `<path>.biusrv.bak`.
+/// Returns the backup path when there was a file to back up
+pub async fn create_file_with_backup(
+    session: &Session,
+    path: &str,
+    content: &str,
+    mode: Option<&str>,
+) -> Result<(CommandResult, Option<String>)> {
+    let backup = format!("{}{}", path, BACKUP_SUFFIX);
+    let result = session
+        .execute_with_sudo(&format!(
+            "if [ -e {path} ]; then cp -p {path} {backup} && echo copied; fi",
+            path = path,
+            backup = backup
+        ))
+        .await?;
+    if result.exit_status != 0 {
+        return Err(anyhow::anyhow!(
+            "Failed to back up {} (exit code: {}) - {}",
+            path,
+            result.exit_status,
+            truncate_error_message(result.output.trim(), 3)
+        ));
+    }
+
+    let backup = if result.output.trim().ends_with("copied") {
+        log::info!("Backed up {} to {}", path, backup);
+        Some(backup)
     } else {
-        format!("echo '{}' | base64 -d > {}", encoded, path)
+        None
     };
-    session.execute_with_sudo(&command).await
+
+    let result = create_file(session, path, content, mode).await?;
+    Ok((result, backup))
+}
+
+/// Markers around the block managed by [`append_block`]
+pub const BLOCK_BEGIN: &str = "# BEGIN biusrv";
+pub const BLOCK_END: &str = "# END biusrv";
+
+/// Insert `content` between [`BLOCK_BEGIN`] and [`BLOCK_END`] in a shared file,
+/// replacing an earlier block and keeping the rest of the file.
+/// The file is created if missing, returns false when it already held this block
+pub async fn append_block(session: &Session, path: &str, content: &str) -> Result<bool> {
+    let result = session
+        .query_with_sudo(&format!(
+            "if [ -e {path} ]; then cat {path}; fi",
+            path = path
+        ))
+        .await?;
+    if result.exit_status != 0 {
+        return Err(anyhow::anyhow!(
+            "Failed to read {} (exit code: {}) - {}",
+            path,
+            result.exit_status,
+            truncate_error_message(result.output.trim(), 3)
+        ));
+    }
+
+    let updated = replace_block(&result.output, content);
+    if updated == result.output {
+        return Ok(false);
+    }
+
+    // a plain redirect keeps the owner and mode of an existing file
+    let result = create_file(session, path, &updated, None).await?;
+    if result.exit_status != 0 {
+        return Err(anyhow::anyhow!(
+            "Failed to write {} (exit code: {}) - {}",
+            path,
+            result.exit_status,
+            truncate_error_message(result.output.trim(), 3)
+        ));
+    }
+    Ok(true)
+}
+
+// Put the marked block in place of an existing one, or at the end of the file
+fn replace_block(current: &str, content: &str) -> String {
+    let block = format!("{}\n{}\n{}\n", BLOCK_BEGIN, content.trim_end(), BLOCK_END);
+
+    let lines: Vec<&str> = current.lines().collect();
+    let begin = lines.iter().position(|line| line.trim() == BLOCK_BEGIN);
+    let end = begin.and_then(|begin| {
+        lines[begin..]
+            .iter()
+            .position(|line| line.trim() == BLOCK_END)
+            .map(|offset| begin + offset)
+    });
+
+    match (begin, end) {
+        (Some(begin), Some(end)) => {
+            let mut updated = String::new();
+            for line in &lines[..begin] {
+                updated.push_str(line);
+                updated.push('\n');
+            }
+            updated.push_str(&block);
+            for line in &lines[end + 1..] {
+                updated.push_str(line);
+                updated.push('\n');
+            }
+            updated
+        }
+        _ if current.is_empty() => block,
+        _ if current.ends_with('\n') => format!("{}{}", current, block),
+        _ => format!("{}\n{}", current, block),
+    }
 }
 
 pub async fn create_dir(
@@ -33,53 +187,180 @@ pub async fn create_dir(
     session.execute_with_sudo(&command).await
 }
 
+// attempts while another package manager holds the lock, about three minutes
+// with the default retry policy
+const PACKAGE_LOCK_RETRIES: u32 = 10;
+
+// Output fragments of apt, dpkg, yum/dnf and pacman when their lock is taken
+const PACKAGE_LOCK_MESSAGES: [&str; 5] = [
+    "Could not get lock",
+    "Unable to acquire the dpkg frontend lock",
+    "Unable to lock the administration directory",
+    "Existing lock /var/run/yum.pid",
+    "unable to lock database",
+];
+
+/// A package manager command failed because another one held the lock
+#[derive(Debug)]
+pub struct PackageLocked(pub CommandResult);
+
+impl std::fmt::Display for PackageLocked {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "package manager lock is held by another process")
+    }
+}
+
+impl Retryable for PackageLocked {
+    fn is_retryable(&self) -> bool {
+        true
+    }
+}
+
+// Run a package manager command, waiting out a lock held by e.g. unattended-upgrades.
+// Once the retries are used up the last result is returned as is
+async fn run_package_command(session: &Session, command: &str) -> Result<CommandResult> {
+    let result = retry_operation!(
+        PACKAGE_LOCK_RETRIES,
+        match session.execute_with_sudo(command).await {
+            Ok(result)
+                if result.exit_status != 0
+                    && PACKAGE_LOCK_MESSAGES
+                        .iter()
+                        .any(|message| result.output.contains(message)) =>
+            {
+                Err(PackageLocked(result))
+            }
+            other => Ok(other),
+        },
+        "Package manager"
+    );
+    session.forget_commands();
+    match result {
+        Ok(result) => result,
+        Err(PackageLocked(result)) => Ok(result),
+    }
+}
+
 pub async fn install(session: &Session, package: &str) -> Result<CommandResult> {
-    let command = match session.os_type() {
-        OsType::Debian => format!("DEBIAN_FRONTEND=noninteractive apt install -y -o Dpkg::Options::=\"--force-confdef\" -o Dpkg::Options::=\"--force-confold\" {}", package),
-        OsType::RedHat => format!("yum install -y {}", package),
-        OsType::Arch => format!("pacman -S --noconfirm {}", package),
-    };
-    session.execute_with_sudo(&command).await
+    install_packages(session, &[package]).await
 }
 
 pub async fn install_packages(session: &Session, packages: &[&str]) -> Result<CommandResult> {
+    let pm = session.package_manager().await?;
     let command = match session.os_type() {
-        OsType::Debian => format!("DEBIAN_FRONTEND=noninteractive apt install -y -o Dpkg::Options::=\"--force-confdef\" -o Dpkg::Options::=\"--force-confold\" {}", packages.join(" ")),
-        OsType::RedHat => format!("yum install -y {}", packages.join(" ")),
-        OsType::Arch => format!("pacman -S --noconfirm {}", packages.join(" ")),
+        OsType::Debian => format!(
+            "DEBIAN_FRONTEND=noninteractive {} install -y {} {}",
+            pm,
+            DPKG_OPTIONS,
+            packages.join(" ")
+        ),
+        OsType::RedHat => format!("{} install -y {}", pm, packages.join(" ")),
+        OsType::Arch => format!("{} -S --noconfirm {}", pm, packages.join(" ")),
     };
-    session.execute_with_sudo(&command).await
+    run_package_command(session, &command).await
 }
 
 pub async fn uninstall(session: &Session, package: &str) -> Result<CommandResult> {
-    let command = match session.os_type() {
-        OsType::Debian => format!("apt remove -y {}", package),
-        OsType::RedHat => format!("yum remove -y {}", package),
-        OsType::Arch => format!("pacman -R --noconfirm {}", package),
-    };
-    session.execute_with_sudo(&command).await
+    uninstall_packages(session, &[package]).await
 }
 
 pub async fn uninstall_packages(session: &Session, packages: &[&str]) -> Result<CommandResult> {
+    let pm = session.package_manager().await?;
     let command = match session.os_type() {
-        OsType::Debian => format!("apt remove -y {}", packages.join(" ")),
-        OsType::RedHat => format!("yum remove -y {}", packages.join(" ")),
-        OsType::Arch => format!("pacman -R --noconfirm {}", packages.join(" ")),
+        OsType::Debian | OsType::RedHat => format!("{} remove -y {}", pm, packages.join(" ")),
+        OsType::Arch => format!("{} -R --noconfirm {}", pm, packages.join(" ")),
     };
-    session.execute_with_sudo(&command).await
+    run_package_command(session, &command).await
 }
 
-pub async fn update_system(session: &Session) -> Result<CommandResult> {
-    let command = match session.os_type() {
+/// Remote log of the last [`update_system`] run
+pub const UPDATE_LOG: &str = "/tmp/update_system.log";
+/// Lines of the update log returned when the update fails
+pub const UPDATE_LOG_TAIL: usize = 20;
+
+/// Refresh package metadata and, depending on `mode`, upgrade installed packages.
+/// With `autoremove` unused dependencies and cached packages are removed afterwards.
+/// Output goes to [`UPDATE_LOG`], on failure the result holds its last lines
+pub async fn update_system(
+    session: &Session,
+    mode: UpdateMode,
+    autoremove: bool,
+) -> Result<CommandResult> {
+    let pm = session.package_manager().await?;
+    let mut command = match (session.os_type(), mode) {
+        (OsType::Debian, UpdateMode::Refresh) => format!("{} update", pm),
+        (OsType::Debian, UpdateMode::Upgrade) => {
+            format!("{pm} update && {pm} upgrade -y {}", DPKG_OPTIONS, pm = pm)
+        }
+        (OsType::Debian, UpdateMode::Full) => {
+            format!(
+                "{pm} update && {pm} dist-upgrade -y {}",
+                DPKG_OPTIONS,
+                pm = pm
+            )
+        }
+        (OsType::RedHat, UpdateMode::Refresh) => format!("{} makecache", pm),
+        (OsType::RedHat, UpdateMode::Upgrade) => format!("{} update -y", pm),
+        (OsType::RedHat, UpdateMode::Full) => format!("{} distro-sync -y", pm),
+        // pacman has no separate metadata refresh that is safe to use on its own
+        (OsType::Arch, UpdateMode::Refresh) => format!("{} -Sy --noconfirm", pm),
+        (OsType::Arch, _) => format!("{} -Syu --noconfirm", pm),
+    };
+
+    if autoremove {
+        let cleanup = match session.os_type() {
+            OsType::Debian => format!(" && {pm} autoremove -y && {pm} autoclean", pm = pm),
+            OsType::RedHat => format!(" && {pm} autoremove -y && {pm} clean packages", pm = pm),
+            OsType::Arch => format!(
+                " && {{ orphans=$({pm} -Qdtq); [ -z \"$orphans\" ] || {pm} -Rns --noconfirm $orphans; }} && {pm} -Sc --noconfirm",
+                pm = pm
+            ),
+        };
+        command.push_str(&cleanup);
+    }
+
+    // the full log stays on the server, only its tail comes back on failure
+    run_package_command(
+        session,
+        &format!(
+            "export DEBIAN_FRONTEND=noninteractive; {{ {cmd}; }} > {log} 2>&1; status=$?; \
+             [ $status -eq 0 ] || tail -n {tail} {log}; exit $status",
+            cmd = command,
+            log = UPDATE_LOG,
+            tail = UPDATE_LOG_TAIL
+        ),
+    )
+    .await
+}
+
+/// Check whether the server needs a reboot to finish updates
+pub async fn reboot_required(session: &Session) -> Result<bool> {
+    let required = match session.os_type() {
         OsType::Debian => {
-            r#"DEBIAN_FRONTEND=noninteractive apt update && apt upgrade -y -o Dpkg::Options::="--force-confdef" -o Dpkg::Options::="--force-confold""#
+            session
+                .query_command("test -f /var/run/reboot-required")
+                .await?
+                .exit_status
+                == 0
+        }
+        // exits with 1 when a reboot is needed
+        OsType::RedHat => {
+            session
+                .query_with_sudo("needs-restarting -r")
+                .await?
+                .exit_status
+                == 1
+        }
+        // the running kernel's modules are gone once a new kernel is installed
+        OsType::Arch => {
+            session
+                .query_command("test -d /usr/lib/modules/$(uname -r)")
+                .await?
+                .exit_status
+                != 0
         }
-        OsType::RedHat => "yum update -y",
-        OsType::Arch => "pacman -Syu --noconfirm",
     };
-    session
-        .execute_with_sudo(&format!("{} > /tmp/update_system.log", command))
-        .await
+    Ok(required)
 }
 
 pub async fn enable_service(session: &Session, service: &str) -> Result<CommandResult> {
@@ -212,12 +493,12 @@ pub async fn reload_service(session: &Session, service: &str) -> Result<CommandR
 
 pub async fn service_status(session: &Session, service: &str) -> Result<CommandResult> {
     let result = session
-        .execute_with_sudo(&format!("systemctl status {}", service))
+        .query_with_sudo(&format!("systemctl status {}", service))
         .await?;
 
     if result.exit_status != 0 {
         let next_result = session
-            .execute_with_sudo(&format!("service {} status", service))
+            .query_with_sudo(&format!("service {} status", service))
             .await?;
         if next_result.exit_status == 0 {
             return Ok(next_result);
@@ -226,6 +507,176 @@ pub async fn service_status(session: &Session, service: &str) -> Result<CommandR
     Ok(result)
 }
 
+/// Normalized state of a service
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ServiceState {
+    Active,
+    Inactive,
+    Failed,
+    Unknown,
+}
+
+impl std::fmt::Display for ServiceState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            ServiceState::Active => "active",
+            ServiceState::Inactive => "inactive",
+            ServiceState::Failed => "failed",
+            ServiceState::Unknown => "unknown",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// State of a service from `systemctl is-active`, or the exit code of
+/// `service <name> status` on servers without systemd
+pub async fn service_state(session: &Session, service: &str) -> Result<ServiceState> {
+    if session.has_command("systemctl").await? {
+        let result = session
+            .query_with_sudo(&format!("systemctl is-active {}", service))
+            .await?;
+        return Ok(match result.output.trim() {
+            "active" | "reloading" => ServiceState::Active,
+            "inactive" | "deactivating" => ServiceState::Inactive,
+            "failed" => ServiceState::Failed,
+            _ => ServiceState::Unknown,
+        });
+    }
+
+    // LSB status codes: 0 running, 1-2 dead with a stale pid or lock file, 3 stopped
+    let result = session
+        .query_with_sudo(&format!("service {} status", service))
+        .await?;
+    Ok(match result.exit_status {
+        0 => ServiceState::Active,
+        1 | 2 => ServiceState::Failed,
+        3 => ServiceState::Inactive,
+        _ => ServiceState::Unknown,
+    })
+}
+
+/// Whether a service starts at boot, None when systemd cannot tell
+pub async fn service_enabled(session: &Session, service: &str) -> Result<Option<bool>> {
+    if !session.has_command("systemctl").await? {
+        return Ok(None);
+    }
+
+    let result = session
+        .query_with_sudo(&format!("systemctl is-enabled {}", service))
+        .await?;
+    Ok(match result.output.trim() {
+        "enabled" | "enabled-runtime" | "alias" | "static" | "indirect" | "generated" => Some(true),
+        "disabled" | "masked" | "masked-runtime" => Some(false),
+        _ => None,
+    })
+}
+
+/// Basic inventory of a server
+#[derive(Debug, Clone, Serialize)]
+pub struct Facts {
+    /// os-release ID and VERSION_ID, e.g. "ubuntu 22.04"
+    pub distro: String,
+    pub kernel: String,
+    pub cpus: u32,
+    pub memory_bytes: u64,
+    pub disks: Vec<DiskUsage>,
+    pub uptime_secs: u64,
+}
+
+/// Usage of a mounted filesystem
+#[derive(Debug, Clone, Serialize)]
+pub struct DiskUsage {
+    pub mountpoint: String,
+    pub size_bytes: u64,
+    pub used_bytes: u64,
+}
+
+// pseudo filesystems that say nothing about storage
+const PSEUDO_FILESYSTEMS: [&str; 7] = [
+    "tmpfs", "devtmpfs", "udev", "overlay", "shm", "none", "efivarfs",
+];
+
+/// Collect CPU count, memory, disk usage, uptime and OS of a server
+pub async fn gather_facts(session: &Session) -> Result<Facts> {
+    let os = session.os_info();
+
+    let cpus = session.query_command("nproc").await?;
+    let cpus = cpus.output.trim().parse().unwrap_or(0);
+
+    let meminfo = session.query_command("cat /proc/meminfo").await?;
+    let memory_bytes = parse_memory_bytes(&meminfo.output);
+
+    // POSIX output keeps each filesystem on one line
+    let df = session.query_command("df -Pk").await?;
+    let disks = parse_disks(&df.output);
+
+    let uptime = session.query_command("cat /proc/uptime").await?;
+    let uptime_secs = parse_uptime_secs(&uptime.output);
+
+    // not detected when the OS type is configured
+    let kernel = if os.kernel.is_empty() {
+        session
+            .query_command("uname -r")
+            .await?
+            .output
+            .trim()
+            .to_string()
+    } else {
+        os.kernel.clone()
+    };
+
+    Ok(Facts {
+        distro: format!("{} {}", os.id, os.version).trim().to_string(),
+        kernel,
+        cpus,
+        memory_bytes,
+        disks,
+        uptime_secs,
+    })
+}
+
+// MemTotal of /proc/meminfo
+fn parse_memory_bytes(meminfo: &str) -> u64 {
+    meminfo
+        .lines()
+        .find_map(|line| line.strip_prefix("MemTotal:"))
+        .and_then(|value| value.split_whitespace().next())
+        .and_then(|value| value.parse::<u64>().ok())
+        .unwrap_or(0)
+        * 1024
+}
+
+// real filesystems of `df -Pk`
+fn parse_disks(df: &str) -> Vec<DiskUsage> {
+    df.lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let [filesystem, size, used, _, _, mountpoint] = fields[..] else {
+                return None;
+            };
+            if PSEUDO_FILESYSTEMS.contains(&filesystem) {
+                return None;
+            }
+            Some(DiskUsage {
+                mountpoint: mountpoint.to_string(),
+                size_bytes: size.parse::<u64>().ok()? * 1024,
+                used_bytes: used.parse::<u64>().ok()? * 1024,
+            })
+        })
+        .collect()
+}
+
+// first field of /proc/uptime, whole seconds
+fn parse_uptime_secs(uptime: &str) -> u64 {
+    uptime
+        .split_whitespace()
+        .next()
+        .and_then(|value| value.parse::<f64>().ok())
+        .unwrap_or(0.0) as u64
+}
+
 /// Truncate error message to a reasonable number of lines for display
 pub fn truncate_error_message(message: &str, max_lines: usize) -> String {
     let lines: Vec<&str> = message.lines().collect();
@@ -240,3 +691,258 @@ pub fn truncate_error_message(message: &str, max_lines: usize) -> String {
         )
     }
 }
+
+/// Match a name against a shell-style pattern: `*`, `?`, `[abc]`, `[a-z]`
+/// and numeric ranges such as `[1-12]`
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    glob_match_chars(&pattern, &name)
+}
+
+fn glob_match_chars(pattern: &[char], name: &[char]) -> bool {
+    match pattern.first() {
+        None => name.is_empty(),
+        Some('*') => (0..=name.len()).any(|i| glob_match_chars(&pattern[1..], &name[i..])),
+        Some('?') => !name.is_empty() && glob_match_chars(&pattern[1..], &name[1..]),
+        Some('[') => match pattern.iter().position(|&c| c == ']') {
+            Some(end) if end > 1 => {
+                let class: String = pattern[1..end].iter().collect();
+                let rest = &pattern[end + 1..];
+
+                if let Some((low, high)) = numeric_range(&class) {
+                    let digits = name.iter().take_while(|c| c.is_ascii_digit()).count();
+                    (1..=digits).any(|len| {
+                        let value: String = name[..len].iter().collect();
+                        value
+                            .parse::<u64>()
+                            .is_ok_and(|value| value >= low && value <= high)
+                            && glob_match_chars(rest, &name[len..])
+                    })
+                } else {
+                    name.first().is_some_and(|&c| char_class_match(&class, c))
+                        && glob_match_chars(rest, &name[1..])
+                }
+            }
+            _ => name.first() == Some(&'[') && glob_match_chars(&pattern[1..], &name[1..]),
+        },
+        Some(&c) => name.first() == Some(&c) && glob_match_chars(&pattern[1..], &name[1..]),
+    }
+}
+
+/// Parse `low-high` where both bounds are decimal numbers
+fn numeric_range(class: &str) -> Option<(u64, u64)> {
+    let (low, high) = class.split_once('-')?;
+    Some((low.parse().ok()?, high.parse().ok()?))
+}
+
+fn char_class_match(class: &str, c: char) -> bool {
+    let chars: Vec<char> = class.chars().collect();
+    let mut i = 0;
+    while i < chars.len() {
+        if i + 2 < chars.len() && chars[i + 1] == '-' {
+            if chars[i] <= c && c <= chars[i + 2] {
+                return true;
+            }
+            i += 3;
+        } else {
+            if chars[i] == c {
+                return true;
+            }
+            i += 1;
+        }
+    }
+    false
+}
+
+/// Whether a server selector contains glob syntax
+pub fn is_glob(pattern: &str) -> bool {
+    pattern.contains(['*', '?', '['])
+}
+
+/// Backoff between the attempts of `retry_operation!`
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// delay before the first retry
+    pub base_delay: Duration,
+    /// growth factor per attempt
+    pub multiplier: f64,
+    /// upper bound of a single delay
+    pub max_delay: Duration,
+    /// wait a random 50-100% of each delay so servers do not retry in lockstep
+    pub jitter: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            base_delay: Duration::from_secs(1),
+            multiplier: 2.0,
+            max_delay: Duration::from_secs(30),
+            jitter: true,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Install the global policy, later calls are ignored
+    pub fn init(policy: RetryPolicy) {
+        let _ = RETRY_POLICY.set(policy);
+    }
+
+    /// Get the global policy, or the default one if not initialized
+    pub fn get() -> RetryPolicy {
+        RETRY_POLICY.get().copied().unwrap_or_default()
+    }
+
+    /// Delay after the failed attempt number `attempt`, counting from 0
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let delay =
+            self.base_delay.as_secs_f64() * self.multiplier.max(1.0).powi(attempt.min(64) as i32);
+        let max_delay = self.max_delay.as_secs_f64();
+        let mut delay = if delay.is_finite() {
+            delay.min(max_delay)
+        } else {
+            max_delay
+        };
+
+        if self.jitter {
+            delay *= rand::thread_rng().gen_range(0.5..=1.0);
+        }
+
+        Duration::from_secs_f64(delay)
+    }
+}
+
+/// A failure another attempt cannot fix, e.g. rejected credentials,
+/// `retry_operation!` gives up on it at once
+#[derive(Debug)]
+pub struct PermanentError(anyhow::Error);
+
+impl PermanentError {
+    /// Mark an error as permanent
+    pub fn wrap(error: impl Into<anyhow::Error>) -> anyhow::Error {
+        anyhow::Error::new(PermanentError(error.into()))
+    }
+
+    pub fn inner(&self) -> &anyhow::Error {
+        &self.0
+    }
+
+    /// Whether the error, or a connection failure it wraps, is permanent
+    pub fn is_permanent(error: &anyhow::Error) -> bool {
+        error.chain().any(|e| {
+            e.is::<PermanentError>()
+                || e.downcast_ref::<ConnectError>()
+                    .is_some_and(|e| Self::is_permanent(e.inner()))
+                || e.downcast_ref::<std::io::Error>()
+                    .is_some_and(|e| !e.is_retryable())
+        })
+    }
+}
+
+impl std::fmt::Display for PermanentError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:#}", self.0)
+    }
+}
+
+impl std::error::Error for PermanentError {}
+
+/// Whether another attempt of a failed operation can succeed
+pub trait Retryable {
+    fn is_retryable(&self) -> bool;
+}
+
+impl Retryable for std::io::Error {
+    fn is_retryable(&self) -> bool {
+        !matches!(
+            self.kind(),
+            std::io::ErrorKind::NotFound
+                | std::io::ErrorKind::PermissionDenied
+                | std::io::ErrorKind::AlreadyExists
+                | std::io::ErrorKind::InvalidInput
+                | std::io::ErrorKind::Unsupported
+        )
+    }
+}
+
+impl Retryable for anyhow::Error {
+    fn is_retryable(&self) -> bool {
+        !PermanentError::is_permanent(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob_match_table() {
+        let cases = [
+            ("web*", "web1", true),
+            ("web*", "web", true),
+            ("web*", "db1", false),
+            ("*", "prod/web1", true),
+            ("prod/*", "prod/eu/web1", true),
+            ("web?", "web1", true),
+            ("web?", "web10", false),
+            ("web[12]", "web2", true),
+            ("web[a-c]", "webb", true),
+            ("web[a-c]", "webd", false),
+            ("web[1-3]", "web2", true),
+            ("web[1-3]", "web10", false),
+            ("web[1-12]", "web10", true),
+            ("web[1-12]", "web13", false),
+            ("web[1-3]-eu", "web3-eu", true),
+            ("web[", "web[", true),
+            ("web[]", "web[]", true),
+            ("web1", "web1", true),
+            ("web1", "web10", false),
+        ];
+        for (pattern, name, expected) in cases {
+            assert_eq!(
+                glob_match(pattern, name),
+                expected,
+                "{} vs {}",
+                pattern,
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn memory_is_mem_total_in_bytes() {
+        let meminfo = "MemTotal:        8123456 kB\nMemFree:         1234567 kB\n";
+        assert_eq!(parse_memory_bytes(meminfo), 8123456 * 1024);
+        assert_eq!(parse_memory_bytes("MemFree: 1 kB\n"), 0);
+    }
+
+    #[test]
+    fn disks_skip_header_and_pseudo_filesystems() {
+        let df = "Filesystem     1024-blocks     Used Available Capacity Mounted on\n\
+                  /dev/sda1         50620216 20480000  27544000      43% /\n\
+                  tmpfs               816000        0    816000       0% /run\n\
+                  /dev/sdb1          1000000   250000    750000      25% /data\n";
+        let disks = parse_disks(df);
+        assert_eq!(disks.len(), 2);
+        assert_eq!(disks[0].mountpoint, "/");
+        assert_eq!(disks[0].size_bytes, 50620216 * 1024);
+        assert_eq!(disks[0].used_bytes, 20480000 * 1024);
+        assert_eq!(disks[1].mountpoint, "/data");
+    }
+
+    #[test]
+    fn disks_ignore_malformed_lines() {
+        let df = "Filesystem 1024-blocks Used Available Capacity Mounted on\n\
+                  /dev/sda1 - 10 10 50% /\n\
+                  /dev/sda2 100 10\n";
+        assert!(parse_disks(df).is_empty());
+    }
+
+    #[test]
+    fn uptime_is_whole_seconds() {
+        assert_eq!(parse_uptime_secs("273456.78 1034567.12\n"), 273456);
+        assert_eq!(parse_uptime_secs(""), 0);
+    }
+}